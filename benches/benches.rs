@@ -1,8 +1,8 @@
 use byte_slurper::{
     capture::unpack,
     complex::ComplexByte,
+    config::Config,
     exfil::{add_stokes_avg, stokes_i},
-    CaptureConfig,
 };
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use rand::prelude::*;
@@ -13,12 +13,7 @@ fn benchmark(c: &mut Criterion) {
     let mut dummy_payload = [0u8; 8200];
     rng.fill(&mut dummy_payload[..]);
 
-    let cc = CaptureConfig {
-        channels: 2048,
-        samples: 65536,
-        avgs: 4,
-        cadence: 8.192e-6,
-    };
+    let cc = Config::default().capture_config();
 
     // Containers
     let mut pol_a = vec![ComplexByte::default(); cc.channels];
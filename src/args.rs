@@ -1,47 +1,205 @@
-//! Argument parsing for running from the command line
+//! Argument parsing for running from the command line.
+//! Everything here overrides the corresponding setting in the config file (see [`crate::config`]).
+//! The defaults live there too, `--print-config` shows them all.
 
-use std::net::SocketAddr;
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use hifitime::Epoch;
+
+use crate::{
+    config::{OutputFormat, VoltageFormat},
+    reduce::Combine,
+    wait::WaitMode,
+};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 pub struct Args {
-    /// Hexadecimal (sans leading 0x) PSRDADA key to create as source for heimdall.
+    /// TOML config file, command line flags take precedence over its settings
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+    /// Print the fully resolved configuration as TOML, defaults included, and exit
+    #[clap(long)]
+    pub print_config: bool,
+    /// Hexadecimal (sans leading 0x) PSRDADA key to write to as source for heimdall.
     /// If not set, output will be written to filterbank files.
     #[clap(short, long, value_parser = valid_dada_key)]
-    pub key: Option<i32>,
+    pub key: Option<String>,
+    /// Format of the output files, if not writing to PSRDADA
+    #[clap(long, value_enum)]
+    pub format: Option<OutputFormat>,
+    /// Create the PSRDADA buffer (destroying it on shutdown) instead of connecting to an existing one
+    #[clap(long)]
+    pub create_dada: bool,
+    /// Number of data blocks in a created PSRDADA buffer
+    #[clap(long)]
+    pub dada_blocks: Option<u64>,
+    /// Number of readers of a created PSRDADA buffer
+    #[clap(long)]
+    pub dada_readers: Option<u32>,
     /// Network device to capture packets from (MTU must be set to 9000)
     #[clap(short, long)]
-    pub device_name: String,
+    pub device_name: Option<String>,
     /// The ip and socket address of the SNAP board
     #[clap(long)]
-    pub fpga_addr: SocketAddr,
+    pub fpga_addr: Option<SocketAddr>,
     /// Port to capture UDP data from
-    #[clap(short, long, default_value_t = 60000)]
+    #[clap(short, long)]
     #[clap(value_parser = clap::value_parser!(u16).range(1..))]
-    pub port: u16,
+    pub port: Option<u16>,
     /// Port to send TCP average spectra to
-    #[clap(short, long, default_value_t = 4242)]
+    #[clap(short, long)]
     #[clap(value_parser = clap::value_parser!(u16).range(1..))]
-    pub listen_port: u16,
+    pub listen_port: Option<u16>,
+    /// Address to bind the monitoring listener to
+    #[clap(long)]
+    pub listen_addr: Option<IpAddr>,
+    /// Number of averaged spectra to integrate per monitoring frame
+    #[clap(long)]
+    pub monitor_avgs: Option<usize>,
+    /// Port to serve the HTTP/WebSocket spectrum and waterfall endpoints on (disabled if not set)
+    #[clap(long)]
+    #[clap(value_parser = clap::value_parser!(u16).range(1..))]
+    pub http_port: Option<u16>,
+    /// Address to bind the control listener to, which is unauthenticated
+    #[clap(long)]
+    pub control_addr: Option<IpAddr>,
+    /// Port to accept runtime control commands on
+    #[clap(long)]
+    #[clap(value_parser = clap::value_parser!(u16).range(1..))]
+    pub control_port: Option<u16>,
+    /// Number of recent packets to keep in memory for voltage dumps (0 disables dumps)
+    #[clap(long)]
+    pub dump_len: Option<usize>,
+    /// Seconds of data per filterbank file/PSRDADA session (never rotates on its own if not set)
+    #[clap(long)]
+    pub session_length: Option<f32>,
+    /// Number of integrations to keep in the HTTP waterfall
+    #[clap(long)]
+    pub waterfall_len: Option<usize>,
     /// Ring buffer capacity
-    #[clap(long, default_value_t = 16384)]
-    pub capacity: usize,
+    #[clap(long)]
+    pub capacity: Option<usize>,
+    /// Seconds of data the ring buffer must be able to hold
+    #[clap(long)]
+    pub buffer_time: Option<f32>,
+    /// Run the quick-look single pulse search
+    #[clap(long)]
+    pub search: bool,
+    /// S/N threshold for search candidates
+    #[clap(long)]
+    pub search_threshold: Option<f32>,
+    /// Record the raw voltages
+    #[clap(long)]
+    pub record_voltages: bool,
+    /// PSRDADA key to record voltages to instead of files
+    #[clap(long, value_parser = valid_dada_key)]
+    pub voltage_key: Option<String>,
+    /// File format for recorded voltages
+    #[clap(long, value_enum)]
+    pub voltage_format: Option<VoltageFormat>,
+    /// Only warn if the preflight checks of the host fail
+    #[clap(long)]
+    pub lenient_preflight: bool,
+    /// Save a sample of the rejected packets to this pcap file
+    #[clap(long)]
+    pub reject_file: Option<PathBuf>,
     /// Number of channels
-    #[clap(long, default_value_t = 2048)]
-    pub channels: usize,
+    #[clap(long)]
+    pub channels: Option<usize>,
     /// Number of samples
-    #[clap(long, default_value_t = 65536)]
-    pub samples: usize,
+    #[clap(long)]
+    pub samples: Option<usize>,
     /// How many samples to average
-    #[clap(long, default_value_t = 4)]
-    pub avgs: usize,
+    #[clap(long)]
+    pub avgs: Option<usize>,
+    /// Number of adjacent channels to combine into each output channel
+    #[clap(long)]
+    pub decimate: Option<usize>,
+    /// Whether to average or sum channels when decimating (time is always averaged)
+    #[clap(long, value_enum)]
+    pub combine: Option<Combine>,
     /// The cadence (in seconds) we expect the packets to arrive at
-    #[clap(long, default_value_t = 8.192e-6)]
-    pub cadence: f32,
+    #[clap(long)]
+    pub cadence: Option<f32>,
+    /// Seconds without a packet before the capture is considered stalled
+    #[clap(long)]
+    pub stall_timeout: Option<f32>,
+    /// Seconds the packets can be off the host clock before warning
+    #[clap(long)]
+    pub max_clock_offset: Option<f32>,
+    /// Seconds to stay stalled before re-arming the FPGA (never re-arms if not set)
+    #[clap(long)]
+    pub rearm_after: Option<f32>,
+    /// How the consumer waits on the ring buffer when it runs dry
+    #[clap(long, value_enum)]
+    pub wait_strategy: Option<WaitMode>,
+    /// Number of spins before yielding or parking
+    #[clap(long)]
+    pub spin_count: Option<usize>,
+    /// Number of queued packets to wait for with the batch wait strategy
+    #[clap(long)]
+    pub batch_size: Option<usize>,
+    /// Name of the source being observed
+    #[clap(long)]
+    pub source_name: Option<String>,
+    /// Right ascension (J2000) of the pointing in degrees
+    #[clap(long)]
+    pub ra: Option<f64>,
+    /// Declination (J2000) of the pointing in degrees
+    #[clap(long, allow_hyphen_values = true)]
+    pub dec: Option<f64>,
+    /// TOML file to watch for pointing updates from the telescope control system
+    #[clap(long)]
+    pub pointing_file: Option<PathBuf>,
+    /// Per-channel complex gains to calibrate with, a line of `a_re a_im b_re b_im` per channel
+    #[clap(long)]
+    pub gains_file: Option<PathBuf>,
+    /// Per-channel Jones matrices to correct the polarizations with, as JSON or text
+    #[clap(long)]
+    pub jones_file: Option<PathBuf>,
     #[clap(flatten)]
     pub verbose: clap_verbosity_flag::Verbosity,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Reprocess pcap dumps of the raw packets into output files, instead of capturing live
+    Convert(ConvertArgs),
+    /// Derive per-channel Jones matrices from pcap dumps of a calibrator
+    Polcal(PolcalArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ConvertArgs {
+    /// Epoch of payload zero, as an MJD (UTC) or a date like "2023-07-22T04:26:40 UTC"
+    #[clap(long, value_parser = parse_epoch)]
+    pub reference_epoch: Epoch,
+    /// Directory to write the output files to
+    #[clap(long, default_value = ".")]
+    pub directory: PathBuf,
+    /// Packet dumps to convert, read in the order given
+    #[clap(required = true)]
+    pub files: Vec<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct PolcalArgs {
+    /// Fractional Stokes Q,U,V of the calibrator [default: unpolarized]
+    #[clap(long, value_parser = parse_stokes, allow_hyphen_values = true)]
+    pub stokes: Option<[f32; 3]>,
+    /// JSON file to write the solutions to
+    #[clap(long, default_value = "jones.json")]
+    pub output: PathBuf,
+    /// Packet dumps of the calibrator
+    #[clap(required = true)]
+    pub files: Vec<PathBuf>,
 }
 
 /// Match verbosity filter with tracing subscriber log levels
@@ -56,6 +214,31 @@ pub fn convert_filter(filter: log::LevelFilter) -> tracing_subscriber::filter::L
     }
 }
 
-fn valid_dada_key(s: &str) -> Result<i32, String> {
+/// Parse a hexadecimal (sans leading 0x) PSRDADA key
+pub fn parse_dada_key(s: &str) -> Result<i32, String> {
     i32::from_str_radix(s, 16).map_err(|_| "Invalid hex litteral".to_string())
 }
+
+fn parse_stokes(s: &str) -> Result<[f32; 3], String> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid Stokes `{}` - {}", s, e))?;
+    values
+        .try_into()
+        .map_err(|_| format!("Expected fractional Q,U,V, not `{}`", s))
+}
+
+fn parse_epoch(s: &str) -> Result<Epoch, String> {
+    match s.parse::<f64>() {
+        Ok(mjd) => Ok(Epoch::from_mjd_utc(mjd)),
+        Err(_) => s
+            .parse()
+            .map_err(|e| format!("Invalid epoch `{}` - {}", s, e)),
+    }
+}
+
+fn valid_dada_key(s: &str) -> Result<String, String> {
+    parse_dada_key(s).map(|_| s.to_owned())
+}
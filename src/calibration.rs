@@ -0,0 +1,341 @@
+//! Per-channel, per-polarization complex gain calibration, to flatten the bandpass the FPGA's
+//! requantization gains leave us with.
+//!
+//! Gain tables are text files with a line of `a_re a_im b_re b_im` per channel (`#` starts a
+//! comment). They're either loaded at startup or measured on a calibrator with the `calibrate`
+//! control command. Stokes I only depends on the gain magnitudes, so the gains are applied to the
+//! powers of each polarization before averaging, after any polarization calibration (see
+//! [`crate::polcal`]).
+
+use std::{path::Path, sync::Arc};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    complex::{Complex, ComplexByte},
+    config::CalibrationSection,
+    control::Runtime,
+    exfil::norm_sq,
+    polcal::JonesTable,
+    CaptureConfig,
+};
+
+/// Where a gain table or Jones solution came from, for the metadata
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CalInfo {
+    /// The file the table was loaded from or written to
+    pub source: String,
+    /// SHA-256 of the table file
+    pub checksum: String,
+}
+
+/// The calibration in use, for the metadata
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CalibrationInfo {
+    pub gains: Option<CalInfo>,
+    pub jones: Option<CalInfo>,
+}
+
+/// Everything we calibrate the voltages with
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Calibration {
+    pub gains: Option<Arc<CalTable>>,
+    pub jones: Option<Arc<JonesTable>>,
+}
+
+impl Calibration {
+    pub fn is_active(&self) -> bool {
+        self.gains.is_some() || self.jones.is_some()
+    }
+
+    pub fn info(&self) -> CalibrationInfo {
+        CalibrationInfo {
+            gains: self.gains.as_ref().map(|table| table.info.clone()),
+            jones: self.jones.as_ref().map(|table| table.info.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalTable {
+    /// Complex gains of polarization A and B
+    pub gains: [Vec<Complex<f32>>; 2],
+    pub info: CalInfo,
+    // |g|^2 of each polarization, which is what actually gets applied
+    power: [Vec<f32>; 2],
+}
+
+pub(crate) fn checksum(text: &str) -> String {
+    Sha256::digest(text.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl CalTable {
+    pub fn new(gains: [Vec<Complex<f32>>; 2], info: CalInfo) -> Self {
+        let power = [0, 1].map(|p| gains[p].iter().map(|g| g.re * g.re + g.im * g.im).collect());
+        Self { gains, info, power }
+    }
+
+    /// Parse the text of a gain table for `channels` channels
+    pub fn parse(text: &str, channels: usize, source: &str) -> Result<Self, String> {
+        let mut gains = [vec![], vec![]];
+        let lines = text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.split('#').next().unwrap().trim()))
+            .filter(|(_, line)| !line.is_empty());
+        for (n, line) in lines {
+            let values = line
+                .split_whitespace()
+                .map(|v| v.parse::<f32>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("{}:{}: {}", source, n, e))?;
+            match values.as_slice() {
+                [a_re, a_im, b_re, b_im] if values.iter().all(|v| v.is_finite()) => {
+                    gains[0].push(Complex::new(*a_re, *a_im));
+                    gains[1].push(Complex::new(*b_re, *b_im));
+                }
+                _ => {
+                    return Err(format!(
+                        "{}:{}: expected four finite numbers (a_re a_im b_re b_im)",
+                        source, n
+                    ))
+                }
+            }
+        }
+        if gains[0].len() != channels {
+            return Err(format!(
+                "{} has gains for {} channels, not {}",
+                source,
+                gains[0].len(),
+                channels
+            ));
+        }
+        let info = CalInfo {
+            source: source.to_owned(),
+            checksum: checksum(text),
+        };
+        Ok(Self::new(gains, info))
+    }
+
+    pub fn load(path: &Path, channels: usize) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        Self::parse(&text, channels, &path.to_string_lossy())
+    }
+
+    /// Write a new table to `path`, which becomes its source
+    pub fn create(
+        path: &Path,
+        gains: [Vec<Complex<f32>>; 2],
+        comment: &str,
+    ) -> Result<Self, String> {
+        let mut text = format!("# {}\n# a_re a_im b_re b_im\n", comment);
+        for (a, b) in gains[0].iter().zip(&gains[1]) {
+            text += &format!("{} {} {} {}\n", a.re, a.im, b.re, b.im);
+        }
+        std::fs::write(path, &text)
+            .map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+        let info = CalInfo {
+            source: path.to_string_lossy().into_owned(),
+            checksum: checksum(&text),
+        };
+        Ok(Self::new(gains, info))
+    }
+}
+
+/// Start calibrating with the gain table and Jones matrices from the config
+pub fn load(
+    section: &CalibrationSection,
+    channels: usize,
+    runtime: &Runtime,
+) -> Result<(), String> {
+    if let Some(path) = &section.gains_file {
+        runtime.set_gains(Some(CalTable::load(path, channels)?));
+    }
+    if let Some(path) = &section.jones_file {
+        runtime.set_jones(Some(JonesTable::load(path, channels)?));
+    }
+    Ok(())
+}
+
+/// The power of each polarization, after correcting the voltages with the Jones matrices
+pub fn pol_powers(
+    pol_a: &[ComplexByte],
+    pol_b: &[ComplexByte],
+    jones: Option<&JonesTable>,
+    powers: &mut [Vec<f32>; 2],
+) {
+    let [power_a, power_b] = powers;
+    match jones {
+        Some(jones) => {
+            for (c, (a, b)) in pol_a.iter().zip(pol_b).enumerate() {
+                let [a, b] = jones.correct(c, *a, *b);
+                power_a[c] = a.norm_sqr();
+                power_b[c] = b.norm_sqr();
+            }
+        }
+        None => {
+            for (c, (a, b)) in pol_a.iter().zip(pol_b).enumerate() {
+                power_a[c] = norm_sq(*a) as f32;
+                power_b[c] = norm_sq(*b) as f32;
+            }
+        }
+    }
+}
+
+/// Calibrated version of [`crate::exfil::add_stokes_avg`], from the [`pol_powers`]
+pub fn add_calibrated_stokes_avg(
+    output: &mut [f32],
+    powers: &[Vec<f32>; 2],
+    gains: Option<&CalTable>,
+    cc: &CaptureConfig,
+) {
+    assert_eq!(output.len(), cc.channels);
+    assert_eq!(powers[0].len(), cc.channels);
+    assert_eq!(powers[1].len(), cc.channels);
+
+    let scale = 1.0 / cc.avgs as f32;
+    let [power_a, power_b] = powers;
+    match gains {
+        Some(gains) => {
+            let [gain_a, gain_b] = &gains.power;
+            for i in 0..cc.channels {
+                output[i] += (power_a[i] * gain_a[i] + power_b[i] * gain_b[i]) * scale;
+            }
+        }
+        None => {
+            for i in 0..cc.channels {
+                output[i] += (power_a[i] + power_b[i]) * scale;
+            }
+        }
+    }
+}
+
+/// Measures gains that flatten the bandpass, from the (uncalibrated) powers of a calibrator
+/// observation
+pub struct GainMeasurement {
+    sums: [Vec<f64>; 2],
+    remaining: u64,
+}
+
+impl GainMeasurement {
+    pub fn new(channels: usize, payloads: u64) -> Self {
+        Self {
+            sums: [vec![0.0; channels], vec![0.0; channels]],
+            remaining: payloads,
+        }
+    }
+
+    /// Add a payload's [`pol_powers`], returning true once we have all we asked for
+    pub fn push(&mut self, powers: &[Vec<f32>; 2]) -> bool {
+        for (sums, pol) in self.sums.iter_mut().zip(powers) {
+            for (sum, power) in sums.iter_mut().zip(pol) {
+                *sum += *power as f64;
+            }
+        }
+        self.remaining = self.remaining.saturating_sub(1);
+        self.remaining == 0
+    }
+
+    /// Real gains that bring every channel to the median power.
+    /// Channels with no power at all are left alone.
+    pub fn gains(&self) -> [Vec<Complex<f32>>; 2] {
+        let mut powers: Vec<_> = self.sums.iter().flatten().filter(|s| **s > 0.0).collect();
+        powers.sort_by(|a, b| a.total_cmp(b));
+        let median = powers.get(powers.len() / 2).map_or(0.0, |m| **m);
+        [0, 1].map(|p| {
+            self.sums[p]
+                .iter()
+                .map(|sum| {
+                    let gain = if *sum > 0.0 {
+                        (median / sum).sqrt() as f32
+                    } else {
+                        1.0
+                    };
+                    Complex::new(gain, 0.0)
+                })
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, exfil::add_stokes_avg};
+
+    #[test]
+    fn test_parse() {
+        let text = "# measured on Cas A\n1 0 0.5 0.5\n\n2 -1 1 0 # hot channel\n";
+        let table = CalTable::parse(text, 2, "gains.txt").unwrap();
+        assert_eq!(table.gains[0][1], Complex::new(2.0, -1.0));
+        assert_eq!(table.power, [vec![1.0, 5.0], vec![0.5, 1.0]]);
+        assert_eq!(table.info.checksum.len(), 64);
+        let path = std::env::temp_dir().join(format!("gains-test-{}.txt", std::process::id()));
+        let created = CalTable::create(&path, table.gains.clone(), "again").unwrap();
+        let again = CalTable::load(&path, 2).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(again, created);
+        assert_eq!(again.gains, table.gains);
+        assert_ne!(again.info.checksum, table.info.checksum);
+        assert!(CalTable::parse(text, 3, "gains.txt").is_err());
+        assert!(CalTable::parse("1 0 1\n", 1, "gains.txt").is_err());
+        assert!(CalTable::parse("1 0 1 nan\n", 1, "gains.txt").is_err());
+        assert!(CalTable::parse("1 0 1 x\n", 1, "gains.txt").is_err());
+    }
+
+    #[test]
+    fn test_calibration() {
+        let cc = CaptureConfig {
+            channels: 4,
+            samples: 1024,
+            avgs: 1,
+            ..Config::default().capture_config()
+        };
+        // An uneven bandpass, with one dead channel
+        let pol_a = [(1, 0), (2, 2), (4, 0), (0, 0)].map(|(re, im)| Complex::new(re, im));
+        let pol_b = [(0, 2), (1, 1), (4, 4), (0, 0)].map(|(re, im)| Complex::new(re, im));
+        let mut powers = [vec![0.0; cc.channels], vec![0.0; cc.channels]];
+        pol_powers(&pol_a, &pol_b, None, &mut powers);
+        let mut measurement = GainMeasurement::new(cc.channels, 2);
+        assert!(!measurement.push(&powers));
+        assert!(measurement.push(&powers));
+        let table = CalTable::new(
+            measurement.gains(),
+            CalInfo {
+                source: "measured".to_owned(),
+                checksum: String::new(),
+            },
+        );
+        let mut flat = vec![0.0; cc.channels];
+        add_calibrated_stokes_avg(&mut flat, &powers, Some(&table), &cc);
+        // Every channel gets brought to the median power (8) in each polarization
+        for (power, expected) in flat.iter().zip([16.0, 16.0, 16.0, 0.0]) {
+            assert!((power - expected).abs() < 1e-4);
+        }
+        // Unit gains change nothing
+        let unity = CalTable::new(
+            [
+                vec![Complex::new(1.0, 0.0); 4],
+                vec![Complex::new(0.0, -1.0); 4],
+            ],
+            table.info.clone(),
+        );
+        let mut calibrated = vec![0.0; cc.channels];
+        let mut raw = vec![0.0; cc.channels];
+        add_calibrated_stokes_avg(&mut calibrated, &powers, Some(&unity), &cc);
+        add_stokes_avg(&mut raw, &pol_a, &pol_b, &cc);
+        assert_eq!(calibrated, raw);
+        // So does an identity Jones matrix
+        let (one, zero) = (Complex::new(1.0, 0.0), Complex::default());
+        let identity = vec![[[one, zero], [zero, one]]; cc.channels];
+        let jones = JonesTable::new(identity, table.info.clone()).unwrap();
+        let mut corrected = [vec![0.0; cc.channels], vec![0.0; cc.channels]];
+        pol_powers(&pol_a, &pol_b, Some(&jones), &mut corrected);
+        assert_eq!(corrected, powers);
+    }
+}
@@ -1,53 +1,223 @@
 //! This module contains all the capture logic
 
-use tracing::warn;
+use std::{path::Path, sync::Arc, time::Duration};
 
-use crate::complex::Complex;
+use tracing::{info, warn};
+
+use crate::{
+    complex::Complex,
+    config::BoardSection,
+    health::Health,
+    packet::{self, Reject, Udp},
+    timing::TimingMonitor,
+    wait::Waker,
+};
 
 // FPGA UDP "Word" size (8 bytes as per CASPER docs)
-const WORD_SIZE: usize = 8;
+pub const WORD_SIZE: usize = 8;
 // 8192 bytes for 1 chunk of 2048 channels + 8 bytes for the header
 // FIXME: This should be set by capture config
 pub const TIMESTAMP_SIZE: usize = 8;
 pub const SPECTRA_SIZE: usize = 8192;
 pub const PAYLOAD_SIZE: usize = SPECTRA_SIZE + TIMESTAMP_SIZE;
-// UDP Header size (spec-defined)
-const UDP_HEADER_SIZE: usize = 42;
+// Frequency channels in each payload, two per polarization per word
+pub const PACKET_CHANNELS: usize = SPECTRA_SIZE / WORD_SIZE * 2;
 
 pub type PayloadBytes = [u8; PAYLOAD_SIZE];
 
+// Payload numbers further than this from the last one from the same board are suspect (about 8.6s)
+const SEQUENCE_WINDOW: u64 = 1 << 20;
+
+/// A payload and the index of the board (in sub-band order) it came from
+#[derive(Debug, Clone, Copy)]
+pub struct Packet {
+    pub board: usize,
+    pub payload: PayloadBytes,
+}
+
+impl Packet {
+    /// A packet from the only board
+    pub fn single(payload: PayloadBytes) -> Self {
+        Self { board: 0, payload }
+    }
+}
+
+/// Works out which board a packet came from by its UDP port and source
+pub struct Router {
+    boards: Vec<BoardSection>,
+    // Last payload number from each board, and one far from it we haven't believed yet
+    last: Vec<Option<u64>>,
+    suspect: Vec<Option<u64>>,
+}
+
+impl Router {
+    pub fn new(boards: &[BoardSection]) -> Self {
+        Self {
+            boards: boards.to_vec(),
+            last: vec![None; boards.len()],
+            suspect: vec![None; boards.len()],
+        }
+    }
+
+    /// The BPF filter for every board's port, with or without a VLAN tag
+    pub fn filter(&self) -> String {
+        let mut ports: Vec<_> = self.boards.iter().map(|b| b.port).collect();
+        ports.sort_unstable();
+        ports.dedup();
+        let ports = ports
+            .iter()
+            .map(|port| format!("dst port {}", port))
+            .collect::<Vec<_>>()
+            .join(" or ");
+        format!("{0} or (vlan and ({0}))", ports)
+    }
+
+    /// The board a packet came from, if it's one of ours
+    pub fn route(&self, udp: &Udp) -> Option<usize> {
+        // Unset sources match anything
+        self.boards.iter().position(|b| {
+            b.port == udp.dest_port
+                && (b.source_ip.is_none() || b.source_ip == Some(udp.source))
+                && (b.source_port.is_none() || b.source_port == Some(udp.source_port))
+        })
+    }
+
+    /// Whether a payload number is anywhere near the last one from the same board.
+    /// A jump (like after re-arming) is only believed once the next packet agrees with it.
+    fn in_sequence(&mut self, board: usize, payload_n: u64) -> bool {
+        let near = |n: Option<u64>| n.is_some_and(|n| n.abs_diff(payload_n) <= SEQUENCE_WINDOW);
+        if self.last[board].is_none() || near(self.last[board]) || near(self.suspect[board]) {
+            self.last[board] = Some(payload_n);
+            self.suspect[board] = None;
+            true
+        } else {
+            self.suspect[board] = Some(payload_n);
+            false
+        }
+    }
+
+    /// The board a packet came from, along with its payload
+    pub fn accept<'a>(&mut self, packet: &pcap::Packet<'a>) -> Result<(usize, &'a [u8]), Reject> {
+        let udp = packet::parse_captured(packet)?;
+        let board = self.route(&udp).ok_or(Reject::Unknown)?;
+        if udp.payload.len() != PAYLOAD_SIZE {
+            return Err(Reject::Size);
+        }
+        if !self.in_sequence(board, payload_number(udp.payload)) {
+            return Err(Reject::Sequence);
+        }
+        Ok((board, udp.payload))
+    }
+}
+
+/// Saves the first few rejected packets to a pcap file, for a closer look
+pub struct RejectSampler {
+    savefile: pcap::Savefile,
+    remaining: usize,
+}
+
+impl RejectSampler {
+    pub fn new<T: pcap::Activated + pcap::State>(
+        cap: &pcap::Capture<T>,
+        path: &Path,
+        count: usize,
+    ) -> Result<Self, String> {
+        let savefile = cap
+            .savefile(path)
+            .map_err(|e| format!("Couldn't create {}: {}", path.display(), e))?;
+        info!(
+            "Saving up to {} rejected packets to {}",
+            count,
+            path.display()
+        );
+        Ok(Self {
+            savefile,
+            remaining: count,
+        })
+    }
+
+    /// Save this packet if we haven't saved enough yet, returning whether we did
+    fn sample(&mut self, packet: &pcap::Packet) -> bool {
+        if self.remaining == 0 {
+            return false;
+        }
+        self.savefile.write(packet);
+        // These are few and far between, so make sure they're there if we get killed
+        if let Err(e) = self.savefile.flush() {
+            warn!("Couldn't save a rejected packet: {}", e);
+        }
+        self.remaining -= 1;
+        true
+    }
+}
+
+/// Count a packet we couldn't use, and keep it if we're still sampling them
+fn reject(
+    health: &Health,
+    sampler: &mut Option<RejectSampler>,
+    packet: &pcap::Packet,
+    reason: Reject,
+) {
+    health.rejected(reason);
+    if sampler.as_mut().is_some_and(|s| s.sample(packet)) {
+        health.sampled();
+    }
+}
+
 pub fn capture_udp(
     mut cap: pcap::Capture<pcap::Active>,
-    mut producer: rtrb::Producer<PayloadBytes>,
+    mut router: Router,
+    mut sampler: Option<RejectSampler>,
+    mut timing: TimingMonitor,
+    mut producer: rtrb::Producer<Packet>,
+    waker: Arc<Waker>,
+    health: Arc<Health>,
 ) -> ! {
     loop {
         let mut payload = [0u8; PAYLOAD_SIZE];
-        let packet = if let Ok(pak) = cap.next() {
-            pak
-        } else {
-            // Keep truckin, we don't care!
-            warn!("libpcap error");
-            continue;
+        let packet = match cap.next() {
+            Ok(pak) => pak,
+            // The watchdog keeps track of how long it's been quiet
+            Err(pcap::Error::TimeoutExpired) => {
+                health.timeout();
+                continue;
+            }
+            Err(e) => {
+                // Keep truckin, we don't care!
+                warn!("libpcap error: {}", e);
+                continue;
+            }
         };
-        let data = &packet.data[UDP_HEADER_SIZE..];
-        // Skip bad packets (we should probably count how often this happens)
-        if data.len() != PAYLOAD_SIZE {
-            continue;
-        }
+        // Skip (and count) bad packets, and ones from a board we don't know about
+        let (board, data) = match router.accept(&packet) {
+            Ok(accepted) => accepted,
+            Err(reason) => {
+                reject(&health, &mut sampler, &packet, reason);
+                continue;
+            }
+        };
+        let payload_n = payload_number(data);
+        health.packet(board, payload_n);
+        // The kernel's timestamp of when it came in
+        let ts = packet.header.ts;
+        let received = Duration::new(ts.tv_sec as u64, ts.tv_usec as u32 * 1000);
+        timing.packet(board, payload_n, received);
         // Memcpy payload to payload
         payload.copy_from_slice(data);
         // Send to ringbuffer
         producer
-            .push(payload)
+            .push(Packet { board, payload })
             .expect("ring buffer full, try increasing capacity");
+        // Wake up the consumer if it's parked
+        waker.notify(&producer);
     }
 }
 
 /// Unpacks a raw UDP payload into the two polarizations
 pub fn unpack(
     payload: &[u8],
-    pol_a: &mut Vec<Complex<i8>>,
-    pol_b: &mut Vec<Complex<i8>>,
+    pol_a: &mut [Complex<i8>],
+    pol_b: &mut [Complex<i8>],
     payload_n: &mut u64,
 ) {
     assert_eq!(
@@ -73,11 +243,16 @@ pub fn unpack(
         pol_b[2 * i + 1] = Complex::new(word[6] as i8, word[7] as i8);
     }
     // Then unpack the timestamp/order
-    *payload_n = u64::from_be_bytes(
+    *payload_n = payload_number(payload);
+}
+
+/// The timestamp/order of a payload from its header
+pub fn payload_number(payload: &[u8]) -> u64 {
+    u64::from_be_bytes(
         payload[0..TIMESTAMP_SIZE]
             .try_into()
             .expect("This is exactly 8 bytes"),
-    );
+    )
 }
 
 #[cfg(test)]
@@ -97,4 +272,76 @@ mod tests {
         assert_eq!(pol_b[1], Complex { re: 7i8, im: 8i8 });
         assert_eq!(payload_n, 1);
     }
+
+    #[test]
+    fn test_router() {
+        let board = |port, source_ip: Option<&str>, source_port| BoardSection {
+            fpga_addr: "192.168.0.3:69".parse().unwrap(),
+            port,
+            source_ip: source_ip.map(|ip| ip.parse().unwrap()),
+            source_port,
+            fch1: None,
+        };
+        let router = Router::new(&[
+            board(60000, Some("192.168.1.10"), None),
+            board(60000, Some("192.168.1.11"), None),
+            board(60001, None, Some(1234)),
+        ]);
+        assert_eq!(
+            router.filter(),
+            "dst port 60000 or dst port 60001 or (vlan and (dst port 60000 or dst port 60001))"
+        );
+        let udp = |source: [u8; 4], source_port, dest_port| Udp {
+            source: source.into(),
+            source_port,
+            dest_port,
+            payload: &[],
+        };
+        assert_eq!(router.route(&udp([192, 168, 1, 11], 1, 60000)), Some(1));
+        assert_eq!(router.route(&udp([192, 168, 1, 10], 1, 60000)), Some(0));
+        assert_eq!(router.route(&udp([10, 0, 0, 1], 1234, 60001)), Some(2));
+        assert_eq!(router.route(&udp([10, 0, 0, 1], 1, 60001)), None);
+        assert_eq!(router.route(&udp([10, 0, 0, 1], 1234, 60000)), None);
+        // A wild payload number is only believed if the next one agrees with it
+        let mut router = router;
+        assert!(router.in_sequence(0, 100));
+        assert!(router.in_sequence(0, 90));
+        assert!(!router.in_sequence(0, u64::MAX / 2));
+        assert!(router.in_sequence(0, 101));
+        assert!(!router.in_sequence(0, 5 << 40));
+        assert!(router.in_sequence(0, (5 << 40) + 1));
+        assert!(router.in_sequence(0, (5 << 40) + 2));
+        // Each board is checked on its own
+        assert!(router.in_sequence(1, 7));
+    }
+
+    #[test]
+    fn test_reject_sampler() {
+        let cap = pcap::Capture::dead(pcap::Linktype::ETHERNET).unwrap();
+        let path = std::env::temp_dir().join(format!("rejects-test-{}.pcap", std::process::id()));
+        let mut sampler = Some(RejectSampler::new(&cap, &path, 2).unwrap());
+        let health = Health::default();
+        let data = [0u8; 64];
+        let header = pcap::PacketHeader {
+            ts: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            caplen: data.len() as u32,
+            len: data.len() as u32,
+        };
+        let packet = pcap::Packet::new(&header, &data);
+        // Every rejection is counted, but only the first few are saved
+        for reason in [Reject::Size, Reject::Unknown, Reject::Size] {
+            reject(&health, &mut sampler, &packet, reason);
+        }
+        let rejections = health.rejections();
+        assert_eq!(rejections.size, 2);
+        assert_eq!(rejections.unknown, 1);
+        assert_eq!(rejections.sampled, 2);
+        // Nothing is sampled without a sampler
+        reject(&health, &mut None, &packet, Reject::Snapped);
+        assert_eq!(health.rejections().sampled, 2);
+        let _ = std::fs::remove_file(&path);
+    }
 }
@@ -1,3 +1,7 @@
+use std::ops::{Add, Mul, Neg, Sub};
+
+use num_traits::Float;
+
 #[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct Complex<T> {
     pub re: T,
@@ -12,3 +16,59 @@ impl<T> Complex<T> {
 
 /// The type of raw channel data out of the FPGA
 pub type ComplexByte = Complex<i8>;
+
+// Just enough arithmetic for the polarization calibration
+impl<T: Float> Complex<T> {
+    pub fn conj(self) -> Self {
+        Self::new(self.re, -self.im)
+    }
+
+    pub fn norm_sqr(self) -> T {
+        self.re * self.re + self.im * self.im
+    }
+
+    pub fn scale(self, k: T) -> Self {
+        Self::new(self.re * k, self.im * k)
+    }
+
+    pub fn inv(self) -> Self {
+        self.conj().scale(self.norm_sqr().recip())
+    }
+}
+
+impl<T: Float> Add for Complex<T> {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Self::new(self.re + rhs.re, self.im + rhs.im)
+    }
+}
+
+impl<T: Float> Sub for Complex<T> {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        Self::new(self.re - rhs.re, self.im - rhs.im)
+    }
+}
+
+impl<T: Float> Mul for Complex<T> {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self {
+        Self::new(
+            self.re * rhs.re - self.im * rhs.im,
+            self.re * rhs.im + self.im * rhs.re,
+        )
+    }
+}
+
+impl<T: Float> Neg for Complex<T> {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Self::new(-self.re, -self.im)
+    }
+}
+
+impl From<ComplexByte> for Complex<f32> {
+    fn from(c: ComplexByte) -> Self {
+        Self::new(c.re as f32, c.im as f32)
+    }
+}
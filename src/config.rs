@@ -0,0 +1,956 @@
+//! Observation configuration, loaded from a TOML file and overridden by command line flags.
+//!
+//! Every setting has a default here, so a config file only needs the things that differ.
+//! The fully resolved configuration can be dumped with `--print-config` and archived.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    args::{parse_dada_key, Args, Command},
+    capture::PACKET_CHANNELS,
+    inject::Injection,
+    mask::{parse_range, ChannelMask},
+    pointing::Pointing,
+    reduce::Combine,
+    vdif::station_id,
+    wait::{WaitMode, WaitStrategy},
+    CaptureConfig,
+};
+
+// Also catches NaNs
+fn positive(v: f64) -> bool {
+    v > 0.0
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureSection {
+    /// Network device to capture packets from
+    pub device_name: Option<String>,
+    /// The ip and socket address of the SNAP board
+    pub fpga_addr: Option<SocketAddr>,
+    /// Port to capture UDP data from
+    pub port: u16,
+    /// Only take packets from this address, if set (the boards each have their own)
+    pub source_ip: Option<IpAddr>,
+    /// Only take packets from this port, if set (the boards each have their own)
+    pub source_port: Option<u16>,
+    /// Ring buffer capacity (in packets)
+    pub capacity: usize,
+    /// Seconds of data the ring buffer should be able to hold
+    pub buffer_time: f32,
+    /// Refuse to start if the preflight checks fail, otherwise just warn
+    pub strict_preflight: bool,
+    /// Save a sample of the rejected packets to this pcap file, if set
+    pub reject_file: Option<PathBuf>,
+    /// Number of rejected packets to save
+    pub reject_samples: usize,
+    pub wait_strategy: WaitMode,
+    pub spin_count: usize,
+    pub batch_size: usize,
+    /// Boards covering adjacent sub-bands, in frequency order, instead of the single FPGA
+    pub boards: Vec<BoardSection>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BoardSection {
+    /// The ip and socket address of the SNAP board
+    pub fpga_addr: SocketAddr,
+    /// Port its UDP data is sent to
+    pub port: u16,
+    /// Where its UDP data comes from, needed if it shares a port with another board
+    pub source_ip: Option<IpAddr>,
+    /// The port its UDP data is sent from, which can also tell boards apart
+    pub source_port: Option<u16>,
+    /// Center frequency of its first channel in MHz, required with more than one board to check
+    /// that they're listed in order and cover adjacent sub-bands
+    pub fch1: Option<f64>,
+}
+
+impl BoardSection {
+    /// Whether we can tell this board's packets from the other's
+    fn distinct(&self, other: &Self) -> bool {
+        fn differ<T: PartialEq>(a: Option<T>, b: Option<T>) -> bool {
+            a.is_some() && b.is_some() && a != b
+        }
+        self.port != other.port
+            || differ(self.source_ip, other.source_ip)
+            || differ(self.source_port, other.source_port)
+    }
+}
+
+impl Default for CaptureSection {
+    fn default() -> Self {
+        Self {
+            device_name: None,
+            fpga_addr: None,
+            port: 60000,
+            source_ip: None,
+            source_port: None,
+            capacity: 16384,
+            buffer_time: 0.1,
+            strict_preflight: true,
+            reject_file: None,
+            reject_samples: 100,
+            wait_strategy: WaitMode::Spin,
+            spin_count: 1000,
+            batch_size: 64,
+            boards: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PacketSection {
+    /// Seconds per packet
+    pub cadence: f32,
+    /// Frequency channels in each packet
+    pub channels: usize,
+}
+
+impl Default for PacketSection {
+    fn default() -> Self {
+        Self {
+            cadence: 8.192e-6,
+            channels: PACKET_CHANNELS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FrequencySection {
+    /// Center frequency of the first channel in MHz
+    pub fch1: f64,
+    /// Total bandwidth in MHz
+    pub bandwidth: f64,
+}
+
+impl Default for FrequencySection {
+    fn default() -> Self {
+        // Set by hardware
+        Self {
+            fch1: 1280.06103516,
+            bandwidth: 250.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AveragingSection {
+    /// Number of frequency channels
+    pub channels: usize,
+    /// Number of averaged samples per output window
+    pub samples: usize,
+    /// Number of packets per average
+    pub avgs: usize,
+    /// Adjacent channels combined into each output channel
+    pub decimate: usize,
+    /// Whether to average or sum channels when decimating (time is always averaged)
+    pub combine: Combine,
+}
+
+impl Default for AveragingSection {
+    fn default() -> Self {
+        Self {
+            channels: 2048,
+            samples: 65536,
+            avgs: 4,
+            decimate: 1,
+            combine: Combine::Mean,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// Format of the output files, when not writing to PSRDADA
+pub enum OutputFormat {
+    /// Sigproc filterbank
+    Filterbank,
+    /// PSRFITS search mode
+    Psrfits,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputSection {
+    /// Hexadecimal PSRDADA key, files are written if this isn't set
+    pub dada_key: Option<String>,
+    /// Format of the files written if there's no PSRDADA key
+    pub format: OutputFormat,
+    /// Spectra per PSRFITS subint (row)
+    pub nsblk: usize,
+    /// Subints per PSRFITS file, never rotates on subints if not set
+    pub subints_per_file: Option<u64>,
+    /// Create (and on shutdown, destroy) the PSRDADA buffer instead of connecting to an existing one
+    pub dada_create: bool,
+    /// Number of data blocks in a created PSRDADA buffer
+    pub dada_blocks: u64,
+    /// Number of readers of a created PSRDADA buffer
+    pub dada_readers: u32,
+    /// Number of recent packets to keep for voltage dumps (0 disables dumps)
+    pub dump_len: usize,
+    /// Seconds of data per filterbank file/PSRDADA session, never rotates on its own if not set
+    pub session_length: Option<f32>,
+}
+
+impl Default for OutputSection {
+    fn default() -> Self {
+        Self {
+            dada_key: None,
+            format: OutputFormat::Filterbank,
+            nsblk: 4096,
+            subints_per_file: None,
+            dada_create: false,
+            dada_blocks: 8,
+            dada_readers: 1,
+            dump_len: 0,
+            session_length: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaskSection {
+    /// Zero the aliased channels at the edges of each board's sub-band
+    pub edges: bool,
+    /// Additional channels (`n`) or inclusive channel ranges (`n-m`) to zero
+    pub channels: Vec<String>,
+}
+
+impl Default for MaskSection {
+    fn default() -> Self {
+        Self {
+            edges: true,
+            channels: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MonitoringSection {
+    /// Address to bind the monitoring (TCP and HTTP) listeners to
+    pub listen_addr: IpAddr,
+    /// Port to send framed average spectra to
+    pub listen_port: u16,
+    /// Number of averaged spectra per monitoring frame
+    pub integrations: usize,
+    /// Port for the HTTP/WebSocket endpoints, disabled if not set
+    pub http_port: Option<u16>,
+    /// Number of integrations to keep in the HTTP waterfall
+    pub waterfall_len: usize,
+    /// Address to bind the control listener to. Anyone who can reach it can change the
+    /// masks and recording, so keep it local unless the network is trusted.
+    pub control_addr: IpAddr,
+    /// Port to accept runtime control commands on
+    pub control_port: u16,
+    /// Seconds without a packet before the capture is considered stalled
+    pub stall_timeout: f32,
+    /// Seconds to stay stalled before re-arming the FPGA, never re-arms if not set
+    pub rearm_after: Option<f32>,
+    /// Seconds the packets can be off the host clock before warning. Arming only knows the
+    /// start to within a second, so lower this only once that's sorted.
+    pub max_clock_offset: f32,
+}
+
+impl Default for MonitoringSection {
+    fn default() -> Self {
+        Self {
+            listen_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            listen_port: 4242,
+            // At incoming samples at 8us, if we're averaging over there by 4, this is about 62.5ms
+            integrations: 2048,
+            http_port: None,
+            waterfall_len: 256,
+            control_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            control_port: 4243,
+            stall_timeout: 5.0,
+            rearm_after: None,
+            max_clock_offset: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelescopeSection {
+    /// Telescope name for the DADA header
+    pub name: Option<String>,
+    /// Sigproc telescope ID
+    pub telescope_id: Option<u32>,
+    /// Sigproc machine (backend) ID
+    pub machine_id: Option<u32>,
+    /// TOML file the telescope control system updates with the current [`Pointing`]
+    pub pointing_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchSection {
+    /// Run the quick-look single pulse search on the averaged Stokes I
+    pub enabled: bool,
+    /// Lowest trial DM in pc cm^-3
+    pub dm_min: f32,
+    /// Highest trial DM in pc cm^-3
+    pub dm_max: f32,
+    /// Spacing of the trial DMs
+    pub dm_step: f32,
+    /// Widest boxcar in samples, every power of two up to this is tried
+    pub max_width: usize,
+    /// S/N above which a candidate is reported
+    pub threshold: f32,
+    /// Number of samples searched at once
+    pub gulp: usize,
+    /// Dedispersion sums per second the search can keep up with, spectra are averaged in time
+    /// before they're searched to stay under it
+    pub max_rate: f64,
+    /// File candidates are appended to as JSON lines
+    pub candidates_file: PathBuf,
+}
+
+impl Default for SearchSection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dm_min: 0.0,
+            dm_max: 500.0,
+            dm_step: 5.0,
+            max_width: 32,
+            threshold: 8.0,
+            gulp: 4096,
+            max_rate: 5e8,
+            candidates_file: PathBuf::from("candidates.jsonl"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InjectionSection {
+    /// File every injection is appended to as JSON lines
+    pub log_file: PathBuf,
+    /// Pulses to inject, more can be added from the control channel
+    pub pulses: Vec<Injection>,
+}
+
+impl Default for InjectionSection {
+    fn default() -> Self {
+        Self {
+            log_file: PathBuf::from("injections.jsonl"),
+            pulses: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// File format for the recorded voltages
+pub enum VoltageFormat {
+    /// DADA files with an ASCII header, as read by dspsr
+    Dada,
+    /// VDIF frames, a thread per polarization
+    Vdif,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VoltageSection {
+    /// Record the raw dual-polarization voltages
+    pub enabled: bool,
+    /// PSRDADA key to write the voltages to, DADA files are written if not set
+    pub dada_key: Option<String>,
+    /// Format of the voltage files
+    pub format: VoltageFormat,
+    /// Two character VDIF station code
+    pub vdif_station: String,
+    /// Directory to write voltage files to
+    pub directory: PathBuf,
+    /// Seconds of data per voltage file
+    pub file_length: f32,
+    /// Payloads buffered between the exfil thread and the writer
+    pub capacity: usize,
+}
+
+impl Default for VoltageSection {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dada_key: None,
+            format: VoltageFormat::Dada,
+            vdif_station: "GX".to_owned(),
+            directory: PathBuf::from("."),
+            file_length: 10.0,
+            // About a second at the full cadence
+            capacity: 131072,
+        }
+    }
+}
+
+impl VoltageSection {
+    pub fn dada_key(&self) -> Option<Result<i32, String>> {
+        self.dada_key.as_deref().map(parse_dada_key)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CalibrationSection {
+    /// Per-channel complex gains to apply before detection (see [`crate::calibration`])
+    pub gains_file: Option<PathBuf>,
+    /// Per-channel Jones matrices to correct the polarizations with (see [`crate::polcal`])
+    pub jones_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub capture: CaptureSection,
+    pub packet: PacketSection,
+    pub frequency: FrequencySection,
+    pub averaging: AveragingSection,
+    pub outputs: OutputSection,
+    pub masks: MaskSection,
+    pub monitoring: MonitoringSection,
+    pub telescope: TelescopeSection,
+    pub search: SearchSection,
+    pub injection: InjectionSection,
+    pub voltages: VoltageSection,
+    pub calibration: CalibrationSection,
+    /// The initial pointing
+    pub pointing: Pointing,
+}
+
+impl Config {
+    /// Load the config file (if there is one), apply the command line overrides, and validate
+    pub fn load(args: &Args) -> Result<Self, String> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply_args(args);
+        // Printing is for putting a config file together, so it needn't be complete yet
+        if args.print_config {
+            return Ok(config);
+        }
+        match args.command {
+            Some(Command::Convert(_) | Command::Polcal(_)) => config.validate_processing()?,
+            None => config.validate()?,
+        }
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        toml::from_str(&contents).map_err(|e| format!("Couldn't parse {}: {}", path.display(), e))
+    }
+
+    /// Command line flags take precedence over the config file
+    pub fn apply_args(&mut self, args: &Args) {
+        fn set<T: Clone>(field: &mut T, arg: &Option<T>) {
+            if let Some(v) = arg {
+                *field = v.clone();
+            }
+        }
+        let capture = &mut self.capture;
+        if args.device_name.is_some() {
+            capture.device_name = args.device_name.clone();
+        }
+        if args.fpga_addr.is_some() {
+            capture.fpga_addr = args.fpga_addr;
+        }
+        set(&mut capture.port, &args.port);
+        set(&mut capture.capacity, &args.capacity);
+        set(&mut capture.buffer_time, &args.buffer_time);
+        if args.lenient_preflight {
+            capture.strict_preflight = false;
+        }
+        if args.reject_file.is_some() {
+            capture.reject_file = args.reject_file.clone();
+        }
+        set(&mut capture.wait_strategy, &args.wait_strategy);
+        set(&mut capture.spin_count, &args.spin_count);
+        set(&mut capture.batch_size, &args.batch_size);
+        set(&mut self.packet.cadence, &args.cadence);
+        set(&mut self.averaging.channels, &args.channels);
+        set(&mut self.averaging.samples, &args.samples);
+        set(&mut self.averaging.avgs, &args.avgs);
+        set(&mut self.averaging.decimate, &args.decimate);
+        set(&mut self.averaging.combine, &args.combine);
+        if args.key.is_some() {
+            self.outputs.dada_key = args.key.clone();
+        }
+        if args.create_dada {
+            self.outputs.dada_create = true;
+        }
+        set(&mut self.outputs.format, &args.format);
+        set(&mut self.outputs.dada_blocks, &args.dada_blocks);
+        set(&mut self.outputs.dada_readers, &args.dada_readers);
+        set(&mut self.outputs.dump_len, &args.dump_len);
+        if args.session_length.is_some() {
+            self.outputs.session_length = args.session_length;
+        }
+        let monitoring = &mut self.monitoring;
+        set(&mut monitoring.listen_addr, &args.listen_addr);
+        set(&mut monitoring.listen_port, &args.listen_port);
+        set(&mut monitoring.integrations, &args.monitor_avgs);
+        if args.http_port.is_some() {
+            monitoring.http_port = args.http_port;
+        }
+        set(&mut monitoring.waterfall_len, &args.waterfall_len);
+        set(&mut monitoring.control_addr, &args.control_addr);
+        set(&mut monitoring.control_port, &args.control_port);
+        set(&mut monitoring.stall_timeout, &args.stall_timeout);
+        set(&mut monitoring.max_clock_offset, &args.max_clock_offset);
+        if args.rearm_after.is_some() {
+            monitoring.rearm_after = args.rearm_after;
+        }
+        if args.pointing_file.is_some() {
+            self.telescope.pointing_file = args.pointing_file.clone();
+        }
+        if args.gains_file.is_some() {
+            self.calibration.gains_file = args.gains_file.clone();
+        }
+        if args.jones_file.is_some() {
+            self.calibration.jones_file = args.jones_file.clone();
+        }
+        if args.source_name.is_some() {
+            self.pointing.source_name = args.source_name.clone();
+        }
+        if args.ra.is_some() {
+            self.pointing.ra = args.ra;
+        }
+        if args.dec.is_some() {
+            self.pointing.dec = args.dec;
+        }
+        if args.search {
+            self.search.enabled = true;
+        }
+        set(&mut self.search.threshold, &args.search_threshold);
+        if args.record_voltages {
+            self.voltages.enabled = true;
+        }
+        if args.voltage_key.is_some() {
+            self.voltages.dada_key = args.voltage_key.clone();
+        }
+        set(&mut self.voltages.format, &args.voltage_format);
+    }
+
+    /// Check everything up front so we don't find out halfway through an observation
+    pub fn validate(&self) -> Result<(), String> {
+        if self.capture.device_name.is_none() {
+            return Err("A capture device name is required".to_owned());
+        }
+        match (self.capture.fpga_addr, self.capture.boards.len()) {
+            (None, 0) => return Err("The FPGA address is required".to_owned()),
+            (Some(_), 1..) => {
+                return Err("Set either the FPGA address or the boards, not both".to_owned())
+            }
+            _ => (),
+        }
+        let boards = &self.capture.boards;
+        for (i, a) in boards.iter().enumerate() {
+            for b in &boards[i + 1..] {
+                if !a.distinct(b) {
+                    return Err(format!(
+                        "Boards sharing port {} need different source IPs or ports",
+                        a.port
+                    ));
+                }
+            }
+        }
+        if self.capture.reject_file.is_some() && self.capture.reject_samples == 0 {
+            return Err("The number of rejected packets to save must be nonzero".to_owned());
+        }
+        if boards.len() > 1 && (self.outputs.dump_len > 0 || self.voltages.enabled) {
+            return Err("Voltages can only be recorded from a single board".to_owned());
+        }
+        self.validate_processing()?;
+        // The sub-bands are stitched together in the order the boards are listed
+        if boards.len() > 1 {
+            let native_foff = self.frequency.bandwidth / self.averaging.channels as f64;
+            for (i, board) in boards.iter().enumerate() {
+                let expected =
+                    self.frequency.fch1 + (i * self.packet.channels) as f64 * native_foff;
+                match board.fch1 {
+                    None => {
+                        return Err(format!(
+                            "Board {} needs its fch1 to check the sub-bands line up",
+                            i
+                        ))
+                    }
+                    Some(fch1) if (fch1 - expected).abs() > native_foff / 2.0 => {
+                        return Err(format!(
+                            "Board {} starts at {} MHz, but the boards must be listed in order with adjacent sub-bands, putting it at {} MHz",
+                            i, fch1, expected
+                        ))
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The boards we capture from, in sub-band order
+    pub fn boards(&self) -> Vec<BoardSection> {
+        match self.capture.fpga_addr {
+            Some(fpga_addr) => vec![BoardSection {
+                fpga_addr,
+                port: self.capture.port,
+                source_ip: self.capture.source_ip,
+                source_port: self.capture.source_port,
+                fch1: Some(self.frequency.fch1),
+            }],
+            None => self.capture.boards.clone(),
+        }
+    }
+
+    /// Everything but the live capture settings, which offline conversion doesn't need
+    pub fn validate_processing(&self) -> Result<(), String> {
+        if self.capture.capacity == 0 {
+            return Err("The ring buffer capacity must be nonzero".to_owned());
+        }
+        if !positive(self.capture.buffer_time as f64) {
+            return Err("The buffer time must be positive".to_owned());
+        }
+        if !positive(self.packet.cadence as f64) {
+            return Err("The packet cadence must be positive".to_owned());
+        }
+        let packet_channels = PacketSection::default().channels;
+        if self.packet.channels != packet_channels {
+            return Err(format!(
+                "The payload format has {} channels per packet, not {}",
+                packet_channels, self.packet.channels
+            ));
+        }
+        let boards = self.capture.boards.len().max(1);
+        if self.averaging.channels != boards * self.packet.channels {
+            return Err(format!(
+                "Channels ({}) must equal the channels per packet ({}) times the boards ({})",
+                self.averaging.channels, self.packet.channels, boards
+            ));
+        }
+        if self.averaging.samples == 0 || self.averaging.avgs == 0 {
+            return Err("Samples and averages must be nonzero".to_owned());
+        }
+        let decimate = self.averaging.decimate;
+        if decimate == 0 || !self.averaging.channels.is_multiple_of(decimate) {
+            return Err(format!(
+                "The channel decimation factor ({}) must evenly divide the channels ({})",
+                decimate, self.averaging.channels
+            ));
+        }
+        if !positive(self.frequency.bandwidth) {
+            return Err("The bandwidth must be positive".to_owned());
+        }
+        self.dada_key().transpose()?;
+        if self.outputs.dada_create
+            && (self.outputs.dada_blocks < 2 || self.outputs.dada_readers == 0)
+        {
+            return Err("A PSRDADA buffer needs at least 2 blocks and 1 reader".to_owned());
+        }
+        if self.outputs.format == OutputFormat::Psrfits {
+            if self.outputs.dada_key.is_some() {
+                return Err("PSRFITS is only written to files, unset the PSRDADA key".to_owned());
+            }
+            if self.outputs.nsblk == 0 || self.outputs.subints_per_file == Some(0) {
+                return Err(
+                    "PSRFITS needs nonzero spectra per subint and subints per file".to_owned(),
+                );
+            }
+        }
+        self.channel_mask()?;
+        if self.monitoring.integrations == 0 {
+            return Err("Monitoring integrations must be nonzero".to_owned());
+        }
+        if self.monitoring.waterfall_len == 0 {
+            return Err("The waterfall length must be nonzero".to_owned());
+        }
+        if !positive(self.monitoring.stall_timeout as f64) {
+            return Err("The stall timeout must be positive".to_owned());
+        }
+        if !positive(self.monitoring.max_clock_offset as f64) {
+            return Err("The maximum clock offset must be positive".to_owned());
+        }
+        if self
+            .monitoring
+            .rearm_after
+            .is_some_and(|r| !positive(r as f64))
+        {
+            return Err("The re-arm time must be positive".to_owned());
+        }
+        if self
+            .outputs
+            .session_length
+            .is_some_and(|l| !positive(l as f64))
+        {
+            return Err("The session length must be positive".to_owned());
+        }
+        if self.search.enabled {
+            let search = &self.search;
+            if search.dm_min < 0.0
+                || !(search.dm_min..=f32::MAX).contains(&search.dm_max)
+                || !positive(search.dm_step as f64)
+            {
+                return Err(
+                    "The search DM range must be nonnegative with a positive step".to_owned(),
+                );
+            }
+            if !search.max_width.is_power_of_two() || search.max_width > search.gulp {
+                return Err(format!(
+                    "The maximum boxcar width ({}) must be a power of two no longer than the gulp ({})",
+                    search.max_width, search.gulp
+                ));
+            }
+            if !positive(search.threshold as f64) {
+                return Err("The search threshold must be positive".to_owned());
+            }
+            if !positive(search.max_rate) {
+                return Err("The search rate must be positive".to_owned());
+            }
+        }
+        if self.voltages.enabled {
+            let key = self.voltages.dada_key().transpose()?;
+            if key.is_some() && key == self.dada_key().transpose()? {
+                return Err("The voltages need their own PSRDADA key".to_owned());
+            }
+            if self.voltages.format == VoltageFormat::Vdif {
+                if key.is_some() {
+                    return Err("VDIF voltages can only be written to files".to_owned());
+                }
+                station_id(&self.voltages.vdif_station)?;
+                if !self.averaging.channels.is_power_of_two() {
+                    return Err("VDIF needs a power of two channels".to_owned());
+                }
+            }
+            if !positive(self.voltages.file_length as f64) || self.voltages.capacity == 0 {
+                return Err(
+                    "The voltage file length and buffer capacity must be positive".to_owned(),
+                );
+            }
+        }
+        self.injection
+            .pulses
+            .iter()
+            .try_for_each(Injection::validate)?;
+        self.pointing.validate()?;
+        Ok(())
+    }
+
+    /// The resolved configuration as TOML
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("Config is always representable as TOML")
+    }
+
+    pub fn capture_config(&self) -> CaptureConfig {
+        CaptureConfig {
+            channels: self.averaging.channels,
+            samples: self.averaging.samples,
+            avgs: self.averaging.avgs,
+            decimate: self.averaging.decimate,
+            combine: self.averaging.combine,
+            cadence: self.packet.cadence,
+            fch1: self.frequency.fch1,
+            bandwidth: self.frequency.bandwidth,
+        }
+    }
+
+    pub fn wait_strategy(&self) -> WaitStrategy {
+        WaitStrategy {
+            mode: self.capture.wait_strategy,
+            spins: self.capture.spin_count,
+            batch: self.capture.batch_size,
+        }
+    }
+
+    pub fn dada_key(&self) -> Option<Result<i32, String>> {
+        self.outputs.dada_key.as_deref().map(parse_dada_key)
+    }
+
+    pub fn channel_mask(&self) -> Result<ChannelMask, String> {
+        let channels = self.averaging.channels;
+        let mut mask = if self.masks.edges {
+            ChannelMask::edges(channels, self.packet.channels)
+        } else {
+            ChannelMask::new(channels)
+        };
+        for range in &self.masks.channels {
+            mask.set_range(parse_range(range)?, true)?;
+        }
+        Ok(mask)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_overrides() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [capture]
+            device_name = "eth0"
+            fpga_addr = "192.168.0.3:69"
+
+            [averaging]
+            avgs = 8
+
+            [masks]
+            channels = ["1000-1009", "1500"]
+
+            [[injection.pulses]]
+            mjd = 60000.5
+            dm = 250.0
+            fluence = 10.0
+            width = 1.0
+            "#,
+        )
+        .unwrap();
+        let args = Args::parse_from(["byte_slurper", "--avgs", "16", "--key", "dada"]);
+        config.apply_args(&args);
+        config.validate().unwrap();
+        assert_eq!(config.averaging.avgs, 16);
+        assert_eq!(config.averaging.samples, 65536);
+        assert_eq!(config.dada_key(), Some(Ok(0xdada)));
+        assert_eq!(config.channel_mask().unwrap().count(), 502 + 11);
+        assert_eq!(config.injection.pulses[0].scattering, 0.0);
+        // Printing doesn't need a complete config
+        let args = Args::parse_from(["byte_slurper", "--print-config", "--avgs", "16"]);
+        assert!(Config::load(&args).is_ok());
+        let args = Args::parse_from(["byte_slurper", "--avgs", "16"]);
+        assert!(Config::load(&args).is_err());
+        // And the printed config should load back to the same thing
+        assert_eq!(toml::from_str::<Config>(&config.to_toml()).unwrap(), config);
+    }
+
+    #[test]
+    fn test_validation() {
+        let mut config = Config::default();
+        assert!(config.validate().is_err());
+        config.capture.device_name = Some("eth0".to_owned());
+        config.capture.fpga_addr = Some("192.168.0.3:69".parse().unwrap());
+        config.validate().unwrap();
+        config.masks.channels = vec!["2040-2048".to_owned()];
+        assert!(config.validate().is_err());
+        config.masks.channels.clear();
+        config.outputs.dada_create = true;
+        config.outputs.dada_blocks = 1;
+        assert!(config.validate().is_err());
+        config.outputs.dada_blocks = 4;
+        config.validate().unwrap();
+        config.averaging.decimate = 3;
+        assert!(config.validate().is_err());
+        config.averaging.decimate = 4;
+        config.validate().unwrap();
+        let cc = config.capture_config();
+        assert_eq!(cc.out_channels(), 512);
+        assert!((cc.fch1() - (1280.06103516 + 1.5 * 250.0 / 2048.0)).abs() < 1e-9);
+        assert!((cc.fcenter() - 1405.0).abs() < 1e-6);
+        config.search.enabled = true;
+        config.validate().unwrap();
+        config.search.max_width = 24;
+        assert!(config.validate().is_err());
+        config.search.max_width = 16;
+        config.search.dm_max = -1.0;
+        assert!(config.validate().is_err());
+        config.search.dm_max = 100.0;
+        config.validate().unwrap();
+        config.voltages.enabled = true;
+        config.voltages.dada_key = Some("dada".to_owned());
+        config.outputs.dada_key = Some("dada".to_owned());
+        assert!(config.validate().is_err());
+        config.voltages.dada_key = Some("eada".to_owned());
+        config.validate().unwrap();
+        config.voltages.format = VoltageFormat::Vdif;
+        assert!(config.validate().is_err());
+        config.voltages.dada_key = None;
+        config.validate().unwrap();
+        config.voltages.vdif_station = "GRX".to_owned();
+        assert!(config.validate().is_err());
+        config.voltages.vdif_station = "GX".to_owned();
+        config.outputs.format = OutputFormat::Psrfits;
+        assert!(config.validate().is_err());
+        config.outputs.dada_key = None;
+        config.validate().unwrap();
+        config.capture.reject_file = Some(PathBuf::from("rejects.pcap"));
+        config.capture.reject_samples = 0;
+        assert!(config.validate().is_err());
+        config.capture.reject_samples = 10;
+        config.validate().unwrap();
+        config.monitoring.waterfall_len = 0;
+        assert!(config.validate().is_err());
+        config.monitoring.waterfall_len = 1;
+        config.validate().unwrap();
+        config.averaging.channels = 1000;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_boards() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [capture]
+            device_name = "eth0"
+
+            [[capture.boards]]
+            fpga_addr = "192.168.0.3:69"
+            port = 60000
+            source_ip = "192.168.1.10"
+            fch1 = 1280.06103516
+
+            [[capture.boards]]
+            fpga_addr = "192.168.0.4:69"
+            port = 60000
+            source_ip = "192.168.1.11"
+            fch1 = 1405.06103516
+            "#,
+        )
+        .unwrap();
+        // Channels have to cover both sub-bands
+        assert!(config.validate().is_err());
+        config.averaging.channels = 4096;
+        config.validate().unwrap();
+        assert_eq!(config.boards().len(), 2);
+        config.capture.boards[1].source_ip = None;
+        assert!(config.validate().is_err());
+        config.capture.boards[1].port = 60001;
+        config.validate().unwrap();
+        // The sub-bands have to be in order and line up
+        config.capture.boards.swap(0, 1);
+        assert!(config.validate().is_err());
+        config.capture.boards.swap(0, 1);
+        config.capture.boards[1].fch1 = Some(1500.0);
+        assert!(config.validate().is_err());
+        config.capture.boards[1].fch1 = None;
+        assert!(config.validate().is_err());
+        config.capture.boards[1].fch1 = Some(1405.06103516);
+        config.validate().unwrap();
+        // The aliased edges at the seam between the sub-bands are masked too
+        let mut spectrum = vec![1f32; config.averaging.channels];
+        config.channel_mask().unwrap().apply(&mut spectrum);
+        let seam = PACKET_CHANNELS - 251..PACKET_CHANNELS + 251;
+        assert!(spectrum[seam.clone()].iter().all(|&v| v == 0.0));
+        assert_eq!(spectrum[seam.start - 1], 1.0);
+        assert_eq!(spectrum[seam.end], 1.0);
+        config.outputs.dump_len = 1024;
+        assert!(config.validate().is_err());
+        config.outputs.dump_len = 0;
+        config.capture.fpga_addr = Some("192.168.0.3:69".parse().unwrap());
+        assert!(config.validate().is_err());
+    }
+}
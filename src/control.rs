@@ -0,0 +1,486 @@
+//! Runtime control channel, so we can tweak settings without restarting heimdall.
+//!
+//! Clients connect over TCP and send newline-delimited commands. Every command gets a
+//! single line reply starting with `OK` or `ERR`. Send `help` for the list of commands.
+//!
+//! `rotate` starts a new filterbank file or PSRDADA session, and `record stop` ends the current
+//! one (marking the end of data for PSRDADA) until `record start`.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use hifitime::Epoch;
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{
+    calibration::{CalTable, Calibration},
+    health::Health,
+    inject::Injection,
+    mask::{parse_range, ChannelMask},
+    pointing::Pointing,
+    polcal::JonesTable,
+    wait::WaitStats,
+};
+
+const HELP: &str = "commands: status | mask <chans>... | unmask <chans>... | mask-reset | monitor-avgs <n> | pointing [source=<name>] [ra|dec|az|za=<deg>]... | inject mjd|in=<..> dm=<..> fluence=<..> width=<..> [scattering|index=<..>] | calibrate <secs>|off | rotate | record start|stop | dump";
+
+/// Runtime-safe parameters shared between the control channel and the processing threads
+pub struct Runtime {
+    mask: Mutex<ChannelMask>,
+    /// The mask from the config, restored by `mask-reset`
+    default_mask: ChannelMask,
+    mask_version: AtomicU64,
+    monitor_avgs: AtomicUsize,
+    pointing: Mutex<Pointing>,
+    injections: Mutex<Vec<Injection>>,
+    injections_pending: AtomicBool,
+    calibration: Mutex<Calibration>,
+    calibration_version: AtomicU64,
+    calibrate: Mutex<Option<f32>>,
+    calibrate_pending: AtomicBool,
+    recording: AtomicBool,
+    rotate: AtomicBool,
+    dump: AtomicBool,
+    shutdown: AtomicBool,
+    payload_n: AtomicU64,
+    payload_start: Mutex<Epoch>,
+    payload_start_version: AtomicU64,
+    started: Instant,
+    dumps_enabled: bool,
+}
+
+impl Runtime {
+    pub fn new(
+        payload_start: Epoch,
+        mask: ChannelMask,
+        monitor_avgs: usize,
+        pointing: Pointing,
+        dumps_enabled: bool,
+    ) -> Self {
+        Self {
+            mask: Mutex::new(mask.clone()),
+            default_mask: mask,
+            mask_version: AtomicU64::new(0),
+            monitor_avgs: AtomicUsize::new(monitor_avgs),
+            pointing: Mutex::new(pointing),
+            injections: Mutex::new(vec![]),
+            injections_pending: AtomicBool::new(false),
+            calibration: Mutex::new(Calibration::default()),
+            calibration_version: AtomicU64::new(0),
+            calibrate: Mutex::new(None),
+            calibrate_pending: AtomicBool::new(false),
+            recording: AtomicBool::new(true),
+            rotate: AtomicBool::new(false),
+            dump: AtomicBool::new(false),
+            shutdown: AtomicBool::new(false),
+            payload_n: AtomicU64::new(0),
+            payload_start: Mutex::new(payload_start),
+            payload_start_version: AtomicU64::new(0),
+            started: Instant::now(),
+            dumps_enabled,
+        }
+    }
+
+    pub fn mask(&self) -> ChannelMask {
+        self.mask.lock().unwrap().clone()
+    }
+
+    /// Returns the current mask if it has changed since we last saw `version`
+    pub fn mask_update(&self, version: &mut u64) -> Option<ChannelMask> {
+        let current = self.mask_version.load(Ordering::Acquire);
+        if current == *version {
+            return None;
+        }
+        *version = current;
+        Some(self.mask())
+    }
+
+    fn update_mask(
+        &self,
+        f: impl FnOnce(&mut ChannelMask) -> Result<(), String>,
+    ) -> Result<usize, String> {
+        let mut mask = self.mask.lock().unwrap();
+        let mut new_mask = mask.clone();
+        f(&mut new_mask)?;
+        *mask = new_mask;
+        self.mask_version.fetch_add(1, Ordering::Release);
+        Ok(mask.count())
+    }
+
+    pub fn monitor_avgs(&self) -> usize {
+        self.monitor_avgs.load(Ordering::Relaxed)
+    }
+
+    pub fn pointing(&self) -> Pointing {
+        self.pointing.lock().unwrap().clone()
+    }
+
+    /// Update the pointing, rotating the output so each file/session has a single pointing
+    pub fn set_pointing(&self, pointing: Pointing) {
+        let mut current = self.pointing.lock().unwrap();
+        if *current == pointing {
+            return;
+        }
+        info!("Pointing changed to {:?}", pointing);
+        *current = pointing;
+        self.rotate.store(true, Ordering::Relaxed);
+    }
+
+    /// Queue a pulse to be injected into the data
+    pub fn queue_injection(&self, injection: Injection) {
+        self.injections.lock().unwrap().push(injection);
+        self.injections_pending.store(true, Ordering::Release);
+    }
+
+    /// Returns the injections queued since we last checked
+    pub fn take_injections(&self) -> Vec<Injection> {
+        if !self.injections_pending.swap(false, Ordering::Acquire) {
+            return vec![];
+        }
+        std::mem::take(&mut *self.injections.lock().unwrap())
+    }
+
+    pub fn calibration(&self) -> Calibration {
+        self.calibration.lock().unwrap().clone()
+    }
+
+    /// Returns the current calibration if it has changed since we last saw `version`
+    pub fn calibration_update(&self, version: &mut u64) -> Option<Calibration> {
+        let current = self.calibration_version.load(Ordering::Acquire);
+        if current == *version {
+            return None;
+        }
+        *version = current;
+        Some(self.calibration())
+    }
+
+    fn update_calibration(&self, f: impl FnOnce(&mut Calibration)) {
+        f(&mut self.calibration.lock().unwrap());
+        self.calibration_version.fetch_add(1, Ordering::Release);
+    }
+
+    /// Replace the gain table (or stop applying gains with None)
+    pub fn set_gains(&self, table: Option<CalTable>) {
+        if let Some(table) = &table {
+            info!("Calibrating with gains from {}", table.info.source);
+        }
+        self.update_calibration(|cal| cal.gains = table.map(Arc::new));
+    }
+
+    /// Replace the Jones matrices (or stop correcting the polarizations with None)
+    pub fn set_jones(&self, table: Option<JonesTable>) {
+        if let Some(table) = &table {
+            info!("Calibrating with Jones matrices from {}", table.info.source);
+        }
+        self.update_calibration(|cal| cal.jones = table.map(Arc::new));
+    }
+
+    /// Returns the seconds of data to measure gains from, if that was requested since we last checked
+    pub fn take_calibrate(&self) -> Option<f32> {
+        if !self.calibrate_pending.swap(false, Ordering::Acquire) {
+            return None;
+        }
+        self.calibrate.lock().unwrap().take()
+    }
+
+    /// Measure new gains from the next `secs` seconds of data
+    pub fn request_calibration(&self, secs: f32) {
+        *self.calibrate.lock().unwrap() = Some(secs);
+        self.calibrate_pending.store(true, Ordering::Release);
+    }
+
+    pub fn recording(&self) -> bool {
+        self.recording.load(Ordering::Relaxed)
+    }
+
+    /// Returns true (once) if a rotation was requested
+    pub fn take_rotate(&self) -> bool {
+        self.rotate.load(Ordering::Relaxed) && self.rotate.swap(false, Ordering::Relaxed)
+    }
+
+    /// Returns true (once) if a voltage dump was requested
+    pub fn take_dump(&self) -> bool {
+        self.dump.load(Ordering::Relaxed) && self.dump.swap(false, Ordering::Relaxed)
+    }
+
+    /// Ask the consumers to finish up what they're writing and return
+    pub fn request_shutdown(&self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+    }
+
+    pub fn shutting_down(&self) -> bool {
+        self.shutdown.load(Ordering::Relaxed)
+    }
+
+    /// The epoch of payload number 0
+    pub fn payload_start(&self) -> Epoch {
+        *self.payload_start.lock().unwrap()
+    }
+
+    /// Returns the epoch of payload number 0 if it has changed since we last saw `version`
+    pub fn payload_start_update(&self, version: &mut u64) -> Option<Epoch> {
+        let current = self.payload_start_version.load(Ordering::Acquire);
+        if current == *version {
+            return None;
+        }
+        *version = current;
+        Some(self.payload_start())
+    }
+
+    /// Update the epoch of payload number 0 after the FPGA has been re-armed.
+    /// The output gets rotated so the new file/session has the right start time.
+    pub fn set_payload_start(&self, payload_start: Epoch) {
+        *self.payload_start.lock().unwrap() = payload_start;
+        self.payload_start_version.fetch_add(1, Ordering::Release);
+        self.rotate.store(true, Ordering::Relaxed);
+    }
+
+    /// The most recent payload number we processed
+    pub fn payload_n(&self) -> u64 {
+        self.payload_n.load(Ordering::Relaxed)
+    }
+
+    /// Record the most recent payload number we processed
+    pub fn set_payload_n(&self, payload_n: u64) {
+        self.payload_n.store(payload_n, Ordering::Relaxed);
+    }
+}
+
+/// Run a single command, returning the reply (without the OK/ERR prefix)
+pub fn handle_command(
+    line: &str,
+    runtime: &Runtime,
+    stats: &WaitStats,
+    health: &Health,
+) -> Result<String, String> {
+    let mut words = line.split_whitespace();
+    let cmd = words.next().unwrap_or_default();
+    let args: Vec<_> = words.collect();
+    match (cmd, args.as_slice()) {
+        ("help", []) => Ok(HELP.to_owned()),
+        ("status", []) => Ok(json!({
+            "uptime_s": runtime.started.elapsed().as_secs_f64(),
+            "health": health.status(),
+            "since_packet_s": health.since_packet().as_secs_f64(),
+            "since_commit_s": health.since_commit().as_secs_f64(),
+            "packets": health.packet_stats(),
+            "rejected": health.rejections(),
+            "timing": health.timing(),
+            "boards": health.board_stats(),
+            "payload_n": runtime.payload_n(),
+            "recording": runtime.recording(),
+            "monitor_avgs": runtime.monitor_avgs(),
+            "masked_channels": runtime.mask.lock().unwrap().count(),
+            "calibration": runtime.calibration().info(),
+            "idle_s": stats.idle().as_secs_f64(),
+            "waits": stats.waits(),
+            "parks": stats.parks(),
+        })
+        .to_string()),
+        ("mask" | "unmask", ranges) if !ranges.is_empty() => {
+            let ranges = ranges
+                .iter()
+                .map(|r| parse_range(r))
+                .collect::<Result<Vec<_>, _>>()?;
+            let masked = runtime.update_mask(|mask| {
+                ranges
+                    .into_iter()
+                    .try_for_each(|r| mask.set_range(r, cmd == "mask"))
+            })?;
+            info!("Control: {} -> {} channels masked", line.trim(), masked);
+            Ok(format!("{} channels masked", masked))
+        }
+        ("mask-reset", []) => {
+            let masked = runtime.update_mask(|mask| {
+                *mask = runtime.default_mask.clone();
+                Ok(())
+            })?;
+            info!("Control: mask reset to defaults");
+            Ok(format!("{} channels masked", masked))
+        }
+        ("monitor-avgs", [n]) => {
+            let n = n
+                .parse::<usize>()
+                .ok()
+                .filter(|&n| n > 0)
+                .ok_or_else(|| format!("Invalid integration length `{}`", n))?;
+            runtime.monitor_avgs.store(n, Ordering::Relaxed);
+            info!("Control: monitoring integration length set to {}", n);
+            Ok(format!("monitor-avgs {}", n))
+        }
+        ("pointing", []) => Ok(serde_json::to_string(&runtime.pointing()).unwrap()),
+        ("pointing", pairs) => {
+            let mut pointing = runtime.pointing();
+            pointing.update(pairs)?;
+            runtime.set_pointing(pointing);
+            Ok(serde_json::to_string(&runtime.pointing()).unwrap())
+        }
+        ("inject", pairs) if !pairs.is_empty() => {
+            let injection = Injection::parse(pairs, Epoch::now().map_err(|e| e.to_string())?)?;
+            info!("Control: injection requested at MJD {}", injection.mjd);
+            let reply = serde_json::to_string(&injection).unwrap();
+            runtime.queue_injection(injection);
+            Ok(reply)
+        }
+        ("calibrate", ["off"]) => {
+            runtime.set_gains(None);
+            Ok("gains off".to_owned())
+        }
+        ("calibrate", [secs]) => {
+            let secs: f32 = secs
+                .parse()
+                .map_err(|_| format!("Invalid number of seconds `{}`", secs))?;
+            if !(secs > 0.0 && secs.is_finite()) {
+                return Err("The calibration time must be positive".to_owned());
+            }
+            runtime.request_calibration(secs);
+            Ok(format!("measuring gains for {}s", secs))
+        }
+        ("rotate", []) => {
+            runtime.rotate.store(true, Ordering::Relaxed);
+            info!("Control: rotation requested");
+            Ok("rotating".to_owned())
+        }
+        ("record", [state @ ("start" | "stop")]) => {
+            runtime
+                .recording
+                .store(*state == "start", Ordering::Relaxed);
+            info!("Control: recording {}", state);
+            Ok(format!("recording {}", state))
+        }
+        ("dump", []) if runtime.dumps_enabled => {
+            runtime.dump.store(true, Ordering::Relaxed);
+            info!("Control: voltage dump requested");
+            Ok("dumping".to_owned())
+        }
+        ("dump", []) => Err("Voltage dumps are disabled (see --dump-len)".to_owned()),
+        _ => Err(format!("Unknown command `{}`, {}", line.trim(), HELP)),
+    }
+}
+
+fn handle_client(stream: TcpStream, runtime: &Runtime, stats: &WaitStats, health: &Health) {
+    let mut writer = match stream.try_clone() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match handle_command(&line, runtime, stats, health) {
+            Ok(msg) => format!("OK {}\n", msg),
+            Err(msg) => {
+                warn!("Control: rejected `{}`: {}", line.trim(), msg);
+                format!("ERR {}\n", msg)
+            }
+        };
+        if writer.write_all(reply.as_bytes()).is_err() {
+            break;
+        }
+    }
+}
+
+pub fn control_server(
+    addr: SocketAddr,
+    runtime: Arc<Runtime>,
+    stats: Arc<WaitStats>,
+    health: Arc<Health>,
+) {
+    let listener = TcpListener::bind(addr).unwrap();
+    info!("Control channel listening on {}", addr);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                info!("New control client {:?}", stream.peer_addr());
+                let runtime = runtime.clone();
+                let stats = stats.clone();
+                let health = health.clone();
+                std::thread::spawn(move || handle_client(stream, &runtime, &stats, &health));
+            }
+            Err(e) => warn!("Failed to accept control client: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands() {
+        let runtime = Runtime::new(
+            Epoch::from_mjd_utc(59_000.0),
+            ChannelMask::edges(2048, 2048),
+            2048,
+            Pointing::default(),
+            false,
+        );
+        let stats = WaitStats::default();
+        let health = Health::default();
+        let mut version = 0;
+        assert!(runtime.mask_update(&mut version).is_none());
+        assert_eq!(
+            handle_command("mask 300-309 400", &runtime, &stats, &health).unwrap(),
+            "513 channels masked"
+        );
+        assert_eq!(runtime.mask_update(&mut version).unwrap().count(), 513);
+        assert!(runtime.mask_update(&mut version).is_none());
+        assert!(handle_command("mask 2000-2048", &runtime, &stats, &health).is_err());
+        handle_command("monitor-avgs 16", &runtime, &stats, &health).unwrap();
+        assert_eq!(runtime.monitor_avgs(), 16);
+        assert!(handle_command("monitor-avgs 0", &runtime, &stats, &health).is_err());
+        handle_command("record stop", &runtime, &stats, &health).unwrap();
+        assert!(!runtime.recording());
+        handle_command("rotate", &runtime, &stats, &health).unwrap();
+        assert!(runtime.take_rotate());
+        assert!(!runtime.take_rotate());
+        handle_command(
+            "pointing source=test ra=10 dec=20",
+            &runtime,
+            &stats,
+            &health,
+        )
+        .unwrap();
+        assert_eq!(runtime.pointing().ra, Some(10.0));
+        assert!(runtime.take_rotate());
+        handle_command("pointing ra=10", &runtime, &stats, &health).unwrap();
+        assert!(!runtime.take_rotate());
+        assert!(handle_command("pointing ra=400", &runtime, &stats, &health).is_err());
+        assert!(runtime.take_injections().is_empty());
+        handle_command(
+            "inject in=5 dm=100 fluence=10 width=1",
+            &runtime,
+            &stats,
+            &health,
+        )
+        .unwrap();
+        assert!(handle_command("inject dm=100", &runtime, &stats, &health).is_err());
+        handle_command("calibrate 2.5", &runtime, &stats, &health).unwrap();
+        assert_eq!(runtime.take_calibrate(), Some(2.5));
+        assert_eq!(runtime.take_calibrate(), None);
+        assert!(handle_command("calibrate -1", &runtime, &stats, &health).is_err());
+        let mut version = 0;
+        assert!(runtime.calibration_update(&mut version).is_none());
+        handle_command("calibrate off", &runtime, &stats, &health).unwrap();
+        assert_eq!(
+            runtime.calibration_update(&mut version),
+            Some(Calibration::default())
+        );
+        assert_eq!(runtime.take_injections().len(), 1);
+        assert!(runtime.take_injections().is_empty());
+        assert!(handle_command("dump", &runtime, &stats, &health).is_err());
+        assert!(handle_command("bogus", &runtime, &stats, &health).is_err());
+    }
+}
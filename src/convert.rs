@@ -0,0 +1,326 @@
+//! Offline conversion of pcap dumps of the raw packets, through the same processing as the live
+//! capture. Timing comes from a reference epoch for payload zero instead of arming the FPGA, so
+//! converting the same dumps always gives the same files.
+
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crossbeam_channel::{bounded, Sender};
+use hifitime::Epoch;
+use sigproc_filterbank::write::WriteFilterbank;
+use tracing::{info, warn};
+
+use crate::{
+    args::ConvertArgs,
+    calibration,
+    capture::{payload_number, Packet, PayloadBytes, PAYLOAD_SIZE, TIMESTAMP_SIZE},
+    config::{Config, OutputFormat},
+    control::Runtime,
+    exfil::{
+        create_filterbank, create_psrfits, filterbank_context, payload_epoch, session_due,
+        Processor,
+    },
+    health::Health,
+    inject::Injector,
+    metadata::Metadata,
+    monitoring::Spectrum,
+    packet::{self, Reject},
+    psrfits::PsrfitsWriter,
+    voltage::{Continuity, Sequence, MAX_GAP},
+    CaptureConfig,
+};
+
+enum Output {
+    Filterbank(File),
+    Psrfits(Box<PsrfitsWriter>),
+}
+
+/// Turns a stream of payloads into output files, filling gaps in the sequence with zeros
+pub struct Converter {
+    cc: CaptureConfig,
+    config: Config,
+    directory: PathBuf,
+    runtime: Arc<Runtime>,
+    health: Arc<Health>,
+    metadata: Arc<Metadata>,
+    // Nobody is listening, but the processor wants somewhere to send the monitoring spectra
+    tcp_sender: Sender<Spectrum>,
+    processor: Processor,
+    sequence: Sequence,
+    fb: WriteFilterbank,
+    // The current file and the payload number it started at
+    output: Option<(Output, u64)>,
+    payloads: u64,
+    filled: u64,
+    late: u64,
+    spectra: u64,
+}
+
+impl Converter {
+    pub fn new(config: &Config, reference_epoch: Epoch, directory: &Path) -> Result<Self, String> {
+        if config.outputs.dada_key.is_some() {
+            return Err("Conversion only writes files, unset the PSRDADA key".to_owned());
+        }
+        let cc = config.capture_config();
+        if cc.boards() > 1 {
+            return Err("Conversion only handles dumps from a single board".to_owned());
+        }
+        let runtime = Arc::new(Runtime::new(
+            reference_epoch,
+            config.channel_mask()?,
+            config.monitoring.integrations,
+            config.pointing.clone(),
+            false,
+        ));
+        calibration::load(&config.calibration, cc.channels, &runtime)?;
+        let health = Arc::new(Health::default());
+        let metadata = Arc::new(Metadata::new(
+            config.clone(),
+            vec![],
+            runtime.clone(),
+            health.clone(),
+        ));
+        let (tcp_sender, _) = bounded(1);
+        Ok(Self {
+            processor: new_processor(&cc, &runtime, &health, &tcp_sender),
+            sequence: Sequence::new((MAX_GAP / cc.cadence as f64) as u64),
+            fb: filterbank_context(&cc, &config.telescope),
+            cc,
+            config: config.clone(),
+            directory: directory.to_owned(),
+            runtime,
+            health,
+            metadata,
+            tcp_sender,
+            output: None,
+            payloads: 0,
+            filled: 0,
+            late: 0,
+            spectra: 0,
+        })
+    }
+
+    /// Add the UDP payload of the next packet, skipping anything that isn't one of ours
+    pub fn push(&mut self, data: &[u8]) -> Result<(), String> {
+        let payload: &PayloadBytes = match data.try_into() {
+            Ok(payload) => payload,
+            Err(_) => {
+                self.reject(Reject::Size);
+                return Ok(());
+            }
+        };
+        let payload_n = payload_number(payload);
+        self.health.packet(0, payload_n);
+        match self.sequence.check(payload_n) {
+            Continuity::Next => (),
+            Continuity::Gap(missing) => {
+                let mut fill = [0u8; PAYLOAD_SIZE];
+                for n in payload_n - missing..payload_n {
+                    fill[..TIMESTAMP_SIZE].copy_from_slice(&n.to_be_bytes());
+                    self.process(&fill)?;
+                }
+                self.filled += missing;
+            }
+            Continuity::Late => {
+                self.late += 1;
+                return Ok(());
+            }
+            Continuity::Restart => {
+                if self.payloads > 0 {
+                    warn!("Payload numbers jumped to {}, starting over", payload_n);
+                }
+                self.close()?;
+                self.processor =
+                    new_processor(&self.cc, &self.runtime, &self.health, &self.tcp_sender);
+            }
+        }
+        self.payloads += 1;
+        self.process(payload)
+    }
+
+    /// Count a packet we couldn't use
+    pub fn reject(&self, reason: Reject) {
+        self.health.rejected(reason);
+    }
+
+    fn process(&mut self, payload: &PayloadBytes) -> Result<(), String> {
+        if !self.processor.process(&Packet::single(*payload)) {
+            return Ok(());
+        }
+        let outputs = self.config.outputs.clone();
+        let due = self.output.as_ref().is_some_and(|(output, start)| {
+            session_due(
+                *start,
+                self.processor.payload_n(),
+                &self.cc,
+                outputs.session_length,
+            ) || matches!((output, outputs.subints_per_file),
+                (Output::Psrfits(writer), Some(n)) if writer.rows() >= n)
+        });
+        if due {
+            self.close()?;
+        }
+        let (output, _) = self.output.get_or_insert_with(|| {
+            let start = self.processor.window_start();
+            let tstart = payload_epoch(self.runtime.payload_start(), start, &self.cc);
+            let pointing = self.runtime.pointing();
+            let output = match outputs.format {
+                OutputFormat::Filterbank => Output::Filterbank(create_filterbank(
+                    &mut self.fb,
+                    &self.directory,
+                    tstart,
+                    &pointing,
+                    &self.metadata,
+                )),
+                OutputFormat::Psrfits => Output::Psrfits(Box::new(create_psrfits(
+                    &self.cc,
+                    outputs.nsblk,
+                    &self.directory,
+                    tstart,
+                    &pointing,
+                    &self.metadata,
+                ))),
+            };
+            (output, start)
+        });
+        let spectrum = self.processor.spectrum();
+        match output {
+            Output::Filterbank(file) => file.write_all(&self.fb.pack(spectrum)),
+            Output::Psrfits(writer) => writer.push(spectrum),
+        }
+        .map_err(|e| format!("Error writing output - {}", e))?;
+        self.spectra += 1;
+        Ok(())
+    }
+
+    /// Finish the current file (and its metadata)
+    fn close(&mut self) -> Result<(), String> {
+        match self.output.take() {
+            Some((Output::Psrfits(writer), _)) => writer
+                .finish()
+                .map_err(|e| format!("Error finishing PSRFITS file - {}", e))?,
+            Some((Output::Filterbank(_), _)) => (),
+            None => return Ok(()),
+        }
+        self.metadata.stop();
+        Ok(())
+    }
+
+    /// Close the last file, reporting what we did
+    pub fn finish(mut self) -> Result<(), String> {
+        self.close()?;
+        info!(
+            "Converted {} payloads into {} spectra, filled {} missing payloads and dropped {} late ones",
+            self.payloads, self.spectra, self.filled, self.late
+        );
+        Ok(())
+    }
+}
+
+fn new_processor(
+    cc: &CaptureConfig,
+    runtime: &Arc<Runtime>,
+    health: &Arc<Health>,
+    tcp_sender: &Sender<Spectrum>,
+) -> Processor {
+    Processor::new(
+        cc,
+        runtime.clone(),
+        health.clone(),
+        tcp_sender.clone(),
+        None,
+        Injector::new(cc, None),
+        None,
+        None,
+    )
+}
+
+/// Call `f` with the UDP payload of every packet to our port in the dumps, in order,
+/// or why it couldn't be used
+pub(crate) fn read_payloads(
+    files: &[PathBuf],
+    port: u16,
+    mut f: impl FnMut(Result<&[u8], Reject>) -> Result<(), String>,
+) -> Result<(), String> {
+    for path in files {
+        info!("Reading {}", path.display());
+        let mut cap = pcap::Capture::from_file(path)
+            .map_err(|e| format!("Couldn't open {}: {}", path.display(), e))?;
+        // Same filter as the live capture
+        cap.filter(
+            &format!("dst port {0} or (vlan and dst port {0})", port),
+            true,
+        )
+        .map_err(|e| format!("Error creating port filter: {}", e))?;
+        loop {
+            match cap.next() {
+                Ok(packet) => f(packet::parse_captured(&packet).map(|udp| udp.payload))?,
+                Err(pcap::Error::NoMorePackets) => break,
+                Err(e) => return Err(format!("Error reading {}: {}", path.display(), e)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Convert the packet dumps given on the command line
+pub fn convert(args: &ConvertArgs, config: &Config) -> Result<(), String> {
+    let mut converter = Converter::new(config, args.reference_epoch, &args.directory)?;
+    read_payloads(&args.files, config.capture.port, |data| match data {
+        Ok(data) => converter.push(data),
+        Err(reason) => {
+            converter.reject(reason);
+            Ok(())
+        }
+    })?;
+    converter.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_converter() {
+        let mut config = Config::default();
+        config.averaging.avgs = 4;
+        let directory = std::env::temp_dir().join(format!("convert-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let epoch = Epoch::from_unix_seconds(1_690_000_000.0);
+        let mut converter = Converter::new(&config, epoch, &directory).unwrap();
+        let mut payload = [1u8; PAYLOAD_SIZE];
+        let mut push = |converter: &mut Converter, n: u64| {
+            payload[..TIMESTAMP_SIZE].copy_from_slice(&n.to_be_bytes());
+            converter.push(&payload).unwrap();
+        };
+        // A gap, a late packet and something that isn't a payload
+        for n in (100..110).chain(115..120).chain([112, 120]) {
+            push(&mut converter, n);
+        }
+        converter.push(&[0u8; 100]).unwrap();
+        assert_eq!(converter.payloads, 16);
+        assert_eq!(converter.filled, 5);
+        assert_eq!(converter.late, 1);
+        assert_eq!(converter.spectra, 5);
+        assert_eq!(converter.health.rejections().size, 1);
+        // Way ahead starts over, dropping the partial average
+        push(&mut converter, 1_000_000);
+        assert_eq!(converter.spectra, 5);
+        for n in 1_000_001..1_000_004 {
+            push(&mut converter, n);
+        }
+        assert_eq!(converter.spectra, 6);
+        let files: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        // Two filterbanks and their sidecars
+        assert_eq!(files.len(), 4);
+        converter.finish().unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
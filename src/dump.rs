@@ -0,0 +1,102 @@
+//! A time-domain buffer of the most recent raw payloads that we can dump to disk on request
+
+use std::{fs::File, io::Write};
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+use hifitime::Epoch;
+use tracing::{info, warn};
+
+use crate::{
+    capture::{PayloadBytes, PAYLOAD_SIZE},
+    exfil::heimdall_timestamp,
+};
+
+/// Ring of the last `capacity` payloads, written out in packet order by a background thread
+pub struct VoltageDump {
+    ring: Vec<PayloadBytes>,
+    capacity: usize,
+    // Next slot to write to
+    head: usize,
+    filled: bool,
+    to_writer: Sender<(Vec<PayloadBytes>, usize)>,
+    from_writer: Receiver<Vec<PayloadBytes>>,
+}
+
+fn dump_writer(rx: Receiver<(Vec<PayloadBytes>, usize)>, tx: Sender<Vec<PayloadBytes>>) {
+    for (ring, oldest) in rx {
+        let filename = format!(
+            "grex-dump-{}.dat",
+            heimdall_timestamp(&Epoch::now().unwrap())
+        );
+        info!("Dumping {} payloads to {}", ring.len(), filename);
+        let result = File::create(&filename).and_then(|mut file| {
+            // Oldest payloads first
+            ring[oldest..]
+                .iter()
+                .chain(&ring[..oldest])
+                .try_for_each(|payload| file.write_all(payload))
+        });
+        if let Err(e) = result {
+            warn!("Failed to write voltage dump: {}", e);
+        }
+        // Hand the buffer back to be filled again
+        if tx.send(ring).is_err() {
+            break;
+        }
+    }
+}
+
+impl VoltageDump {
+    pub fn new(capacity: usize) -> Self {
+        let (to_writer, writer_rx) = bounded(1);
+        let (writer_tx, from_writer) = bounded(1);
+        std::thread::spawn(move || dump_writer(writer_rx, writer_tx));
+        Self {
+            ring: vec![[0u8; PAYLOAD_SIZE]; capacity],
+            capacity,
+            head: 0,
+            filled: false,
+            to_writer,
+            from_writer,
+        }
+    }
+
+    /// Copy a payload into the ring, overwriting the oldest one
+    pub fn push(&mut self, payload: &PayloadBytes) {
+        if self.ring.is_empty() {
+            // We're waiting on the writer to give the buffer back
+            match self.from_writer.try_recv() {
+                Ok(mut ring) => {
+                    // Partial dumps come back truncated
+                    ring.resize(self.capacity, [0u8; PAYLOAD_SIZE]);
+                    self.ring = ring;
+                }
+                Err(_) => return,
+            }
+        }
+        self.ring[self.head].copy_from_slice(payload);
+        self.head = (self.head + 1) % self.ring.len();
+        if self.head == 0 {
+            self.filled = true;
+        }
+    }
+
+    /// Send the contents of the ring to be written to disk.
+    /// Returns false if a dump is still in progress or there is nothing to dump.
+    pub fn trigger(&mut self) -> bool {
+        if self.ring.is_empty() || (!self.filled && self.head == 0) {
+            return false;
+        }
+        let mut ring = std::mem::take(&mut self.ring);
+        // Only dump what we've actually filled
+        let oldest = if self.filled {
+            self.head
+        } else {
+            ring.truncate(self.head);
+            0
+        };
+        self.head = 0;
+        self.filled = false;
+        self.to_writer.send((ring, oldest)).is_ok()
+    }
+}
@@ -1,28 +1,49 @@
 //! This module is responsible for exfilling packet data to heimdall
 
-use std::{collections::HashMap, io::Write};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use byte_slice_cast::AsByteSlice;
 use chrono::{Datelike, TimeZone, Timelike, Utc};
-use crossbeam_channel::Sender;
+use crossbeam_channel::{Sender, TrySendError};
 use hifitime::{Epoch, TimeUnits};
 use lending_iterator::LendingIterator;
-use psrdada::client::DadaClient;
+use psrdada::{
+    builder::DadaClientBuilder,
+    client::{DadaClient, HeaderClient},
+    io::WriteBlock,
+};
 use sigproc_filterbank::write::WriteFilterbank;
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, warn};
 
 use crate::{
-    capture::{unpack, PayloadBytes},
-    complex::ComplexByte,
+    calibration::{add_calibrated_stokes_avg, pol_powers, CalTable, Calibration, GainMeasurement},
+    capture::{unpack, Packet},
+    complex::{Complex, ComplexByte},
+    config::{OutputSection, TelescopeSection},
+    control::Runtime,
+    dump::VoltageDump,
+    health::Health,
+    inject::Injector,
+    mask::ChannelMask,
+    merge::Merger,
+    metadata::{sidecar_path, Metadata},
+    monitoring::Spectrum,
+    pointing::{dada_dec, dada_ra, sigproc_dec, sigproc_ra, Pointing},
+    psrfits::PsrfitsWriter,
+    reduce::decimate_channels,
+    voltage::VoltageRecorder,
+    wait::Waiter,
     CaptureConfig,
 };
 
-// Set by hardware (in MHz)
-const LOWBAND_MID_FREQ: f64 = 1280.06103516;
-const BANDWIDTH: f64 = 250.0;
-
 /// Convert a chronno DateTime into a heimdall-compatible timestamp string
-fn heimdall_timestamp(time: &Epoch) -> String {
+pub(crate) fn heimdall_timestamp(time: &Epoch) -> String {
     let unix = time.to_unix_seconds();
     let time = Utc.timestamp_opt(unix as i64, 0).unwrap();
     format!(
@@ -40,7 +61,7 @@ fn square_byte(byte: i8) -> u16 {
     byte.unsigned_abs() as u16 * byte.unsigned_abs() as u16
 }
 
-fn norm_sq(cb: ComplexByte) -> u16 {
+pub(crate) fn norm_sq(cb: ComplexByte) -> u16 {
     square_byte(cb.re) + square_byte(cb.im)
 }
 
@@ -48,203 +69,1089 @@ pub fn stokes_i(pol_x: ComplexByte, pol_y: ComplexByte) -> u16 {
     norm_sq(pol_x) + norm_sq(pol_y)
 }
 
-fn fullness(c: &rtrb::Consumer<PayloadBytes>) -> f32 {
+fn fullness(c: &rtrb::Consumer<Packet>) -> f32 {
     c.slots() as f32 / c.buffer().capacity() as f32
 }
 
 pub fn add_stokes_avg(
-    output: &mut Vec<f32>,
-    pol_a: &Vec<ComplexByte>,
-    pol_b: &Vec<ComplexByte>,
+    output: &mut [f32],
+    pol_a: &[ComplexByte],
+    pol_b: &[ComplexByte],
     cc: &CaptureConfig,
 ) {
     assert_eq!(output.len(), cc.channels);
     assert_eq!(pol_a.len(), cc.channels);
     assert_eq!(pol_b.len(), cc.channels);
 
+    // Time is always a mean, `Combine` only applies to the channels
+    let scale = 1.0 / cc.avgs as f32;
     for i in 0..cc.channels {
-        output[i] += stokes_i(pol_a[i], pol_b[i]) as f32 / cc.avgs as f32;
+        output[i] += stokes_i(pol_a[i], pol_b[i]) as f32 * scale;
+    }
+}
+
+/// Warn (once) when the ringbuffer gets close to full
+fn check_fullness(c: &rtrb::Consumer<Packet>, rising_edge: &mut bool) {
+    if fullness(c) >= 0.9 && !*rising_edge {
+        warn!("The raw UDP byte ringbuffer is 90% full");
+        *rising_edge = true;
+    } else if fullness(c) < 0.9 && *rising_edge {
+        *rising_edge = false;
     }
 }
 
+/// The epoch of a given payload, as each payload represents cc.cadence timesteps after payload_start
+pub fn payload_epoch(payload_start: Epoch, payload_n: u64, cc: &CaptureConfig) -> Epoch {
+    payload_start + (payload_n as f64 * cc.cadence as f64).seconds()
+}
+
+/// The processing shared by all the consumers, turning raw payloads into averaged, masked spectra
+pub struct Processor {
+    cc: CaptureConfig,
+    // Containers for parsed spectra
+    pol_a: Vec<ComplexByte>,
+    pol_b: Vec<ComplexByte>,
+    payload_n: u64,
+    // The payload before this one, and how many we've missed since the averaging window started
+    last_payload_n: Option<u64>,
+    missing: u64,
+    // Stitches the sub-bands together when there's more than one board
+    merger: Option<Merger>,
+    // Averaging window
+    avg: Vec<f32>,
+    avg_cnt: usize,
+    // The averaged spectrum after frequency decimation
+    out: Vec<f32>,
+    mask: ChannelMask,
+    mask_version: u64,
+    runtime: Arc<Runtime>,
+    tcp_sender: Sender<Spectrum>,
+    search_sender: Option<Sender<Spectrum>>,
+    search_behind: bool,
+    injector: Injector,
+    calibration: Calibration,
+    calibration_version: u64,
+    measurement: Option<GainMeasurement>,
+    // Power of each polarization, when calibrating
+    powers: [Vec<f32>; 2],
+    dump: Option<VoltageDump>,
+    voltages: Option<VoltageRecorder>,
+}
+
+impl Processor {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        cc: &CaptureConfig,
+        runtime: Arc<Runtime>,
+        health: Arc<Health>,
+        tcp_sender: Sender<Spectrum>,
+        search_sender: Option<Sender<Spectrum>>,
+        injector: Injector,
+        dump: Option<VoltageDump>,
+        voltages: Option<VoltageRecorder>,
+    ) -> Self {
+        Self {
+            cc: *cc,
+            pol_a: vec![ComplexByte::default(); cc.channels],
+            pol_b: vec![ComplexByte::default(); cc.channels],
+            payload_n: 0,
+            last_payload_n: None,
+            missing: 0,
+            merger: (cc.boards() > 1).then(|| Merger::new(cc.boards(), health)),
+            avg: vec![0f32; cc.channels],
+            avg_cnt: 0,
+            out: vec![0f32; cc.out_channels()],
+            mask: runtime.mask(),
+            mask_version: 0,
+            runtime,
+            tcp_sender,
+            search_sender,
+            search_behind: false,
+            injector,
+            calibration: Calibration::default(),
+            calibration_version: 0,
+            measurement: None,
+            powers: [vec![0f32; cc.channels], vec![0f32; cc.channels]],
+            dump,
+            voltages,
+        }
+    }
+
+    /// Process the next payload, returning true if we've filled an averaging window.
+    /// The averaged spectrum is then available from [`Processor::spectrum`] until the next call.
+    pub fn process(&mut self, packet: &Packet) -> bool {
+        // Start a new average if we finished the last one
+        if self.avg_cnt == self.cc.avgs {
+            self.avg_cnt = 0;
+            self.avg.fill(0.0);
+            self.missing = 0;
+        }
+        if let Some(dump) = &mut self.dump {
+            if self.runtime.take_dump() && !dump.trigger() {
+                warn!("Voltage dump requested while another was in progress");
+            }
+            dump.push(&packet.payload);
+        }
+        if let Some(voltages) = &mut self.voltages {
+            voltages.push(&packet.payload);
+        }
+        // Unpack payload to spectra, or wait for the rest of the sub-bands
+        match &mut self.merger {
+            Some(merger) => match merger.push(packet, &mut self.pol_a, &mut self.pol_b) {
+                Some(payload_n) => self.payload_n = payload_n,
+                None => return false,
+            },
+            None => unpack(
+                &packet.payload,
+                &mut self.pol_a,
+                &mut self.pol_b,
+                &mut self.payload_n,
+            ),
+        }
+        self.runtime.set_payload_n(self.payload_n);
+        if let Some(last) = self.last_payload_n {
+            self.missing += self.payload_n.saturating_sub(last + 1);
+        }
+        self.last_payload_n = Some(self.payload_n);
+        // Pick up a new gain table, or start measuring one
+        if let Some(calibration) = self
+            .runtime
+            .calibration_update(&mut self.calibration_version)
+        {
+            self.calibration = calibration;
+        }
+        if let Some(secs) = self.runtime.take_calibrate() {
+            info!("Measuring gains from the next {}s of data", secs);
+            let payloads = (secs as f64 / self.cc.cadence as f64).ceil() as u64;
+            self.measurement = Some(GainMeasurement::new(self.cc.channels, payloads));
+        }
+        // Generate stokes for this sample and push to averaging window
+        // This is a transpose operation because the average calculation needs the time axis
+        // to be contiguous as that's what we're summing over
+        if !self.calibration.is_active() && self.measurement.is_none() {
+            add_stokes_avg(&mut self.avg, &self.pol_a, &self.pol_b, &self.cc);
+        } else {
+            let jones = self.calibration.jones.as_deref();
+            pol_powers(&self.pol_a, &self.pol_b, jones, &mut self.powers);
+            if let Some(measurement) = &mut self.measurement {
+                if measurement.push(&self.powers) {
+                    let gains = measurement.gains();
+                    self.measurement = None;
+                    self.save_gains(gains);
+                }
+            }
+            let gains = self.calibration.gains.as_deref();
+            add_calibrated_stokes_avg(&mut self.avg, &self.powers, gains, &self.cc);
+        }
+        self.avg_cnt += 1;
+        if self.avg_cnt < self.cc.avgs {
+            return false;
+        }
+        // Add any synthetic pulses, before masking so they look like the real thing
+        for injection in self.runtime.take_injections() {
+            self.injector.schedule(injection);
+        }
+        if !self.injector.idle() {
+            let start = self.window_start();
+            let epoch = payload_epoch(self.runtime.payload_start(), start, &self.cc);
+            self.injector.apply(&mut self.avg, epoch, start);
+        }
+        // Pick up any changes to the mask from the control channel
+        if let Some(mask) = self.runtime.mask_update(&mut self.mask_version) {
+            self.mask = mask;
+        }
+        self.mask.apply(&mut self.avg);
+        decimate_channels(&self.avg, &mut self.out, self.cc.combine);
+        // Send this average over to the TCP listener, we don't care if this errors
+        let _ = self.tcp_sender.try_send(Spectrum {
+            payload_n: self.payload_n,
+            missing: self.missing,
+            data: self.out.clone(),
+        });
+        // The search gets every spectrum it can keep up with, but never holds us up
+        if let Some(sender) = &self.search_sender {
+            let spectrum = Spectrum {
+                payload_n: self.payload_n,
+                missing: self.missing,
+                data: self.out.clone(),
+            };
+            match sender.try_send(spectrum) {
+                Err(TrySendError::Full(_)) if !self.search_behind => {
+                    warn!("The search can't keep up, dropping spectra");
+                    self.search_behind = true;
+                }
+                Ok(_) => self.search_behind = false,
+                Err(_) => (),
+            }
+        }
+        true
+    }
+
+    /// Write out freshly measured gains and start using them
+    fn save_gains(&self, gains: [Vec<Complex<f32>>; 2]) {
+        let end = payload_epoch(self.runtime.payload_start(), self.payload_n, &self.cc);
+        let path = PathBuf::from(format!("gains-{}.txt", heimdall_timestamp(&end)));
+        let comment = format!("Measured from the data up to {}", end);
+        match CalTable::create(&path, gains, &comment) {
+            Ok(table) => self.runtime.set_gains(Some(table)),
+            Err(e) => error!("Not using the measured gains - {}", e),
+        }
+    }
+
+    /// The most recent averaged (and decimated) spectrum
+    pub fn spectrum(&self) -> &[f32] {
+        &self.out
+    }
+
+    /// Payload number of the most recent payload
+    pub fn payload_n(&self) -> u64 {
+        self.payload_n
+    }
+
+    pub fn runtime(&self) -> &Runtime {
+        &self.runtime
+    }
+
+    /// Payload number of the first payload in the current averaging window
+    pub fn window_start(&self) -> u64 {
+        (self.payload_n + 1).saturating_sub(self.avg_cnt as u64)
+    }
+}
+
+/// The filterbank context with the header stuff that doesn't change between files
+pub(crate) fn filterbank_context(
+    cc: &CaptureConfig,
+    telescope: &TelescopeSection,
+) -> WriteFilterbank {
+    let mut fb = WriteFilterbank::new(cc.out_channels(), 1);
+    fb.fch1 = Some(cc.fch1()); // Start of band + half the step size
+    fb.foff = Some(cc.foff());
+    fb.tsamp = Some(cc.tsamp() as f64);
+    fb.telescope_id = telescope.telescope_id;
+    fb.machine_id = telescope.machine_id;
+    fb
+}
+
+pub(crate) fn create_filterbank(
+    fb: &mut WriteFilterbank,
+    directory: &Path,
+    tstart: Epoch,
+    pointing: &Pointing,
+    metadata: &Metadata,
+) -> File {
+    let filename = directory.join(format!("grex-{}.fil", heimdall_timestamp(&tstart)));
+    info!("Writing to new filterbank {}", filename.display());
+    let mut file = File::create(&filename).unwrap();
+    fb.tstart = Some(tstart.to_mjd_utc_days());
+    fb.source_name = pointing.source_name.clone();
+    fb.src_raj = pointing.ra.map(sigproc_ra);
+    fb.src_dej = pointing.dec.map(sigproc_dec);
+    fb.az_start = pointing.az;
+    fb.za_start = pointing.za;
+    file.write_all(&fb.header_bytes()).unwrap();
+    metadata.start(&filename.to_string_lossy(), sidecar_path(&filename), tstart);
+    file
+}
+
 /// Basically the same as the dada consumer, except write to a filterbank instead with no chunking
 pub fn filterbank_consumer(
-    mut consumer: rtrb::Consumer<PayloadBytes>,
-    tcp_sender: Sender<Vec<f32>>,
+    mut consumer: rtrb::Consumer<Packet>,
     cc: &CaptureConfig,
-    payload_start: Epoch,
+    mut waiter: Waiter,
+    mut processor: Processor,
+    health: Arc<Health>,
+    metadata: Arc<Metadata>,
 ) {
-    let mut pol_a = vec![ComplexByte::default(); cc.channels];
-    let mut pol_b = vec![ComplexByte::default(); cc.channels];
-    let mut avg = vec![0f32; cc.channels];
-    let mut avg_cnt = 0usize;
     let mut fullness_rising_edge = false;
-    let mut payload_n = 0u64;
-    // Create the file
-    let mut file = std::fs::File::create(format!(
-        "grex-{}.fil",
-        heimdall_timestamp(&Epoch::now().unwrap())
-    ))
+    let mut fb = filterbank_context(cc, &metadata.config().telescope);
+    let session_length = metadata.config().outputs.session_length;
+    // The file gets created (with its timestamp) on the first spectrum we record,
+    // alongside the payload number it started at
+    let mut file: Option<(File, u64)> = None;
+    loop {
+        check_fullness(&consumer, &mut fullness_rising_edge);
+        // Stop waiting if we're shut down while the data has stalled
+        let packet = waiter.pop_unless(&mut consumer, || processor.runtime().shutting_down());
+        let full = packet.is_some_and(|packet| processor.process(&packet));
+        let runtime = processor.runtime();
+        if runtime.shutting_down() {
+            return;
+        }
+        if !full {
+            continue;
+        }
+        metadata.check_calibration();
+        let due = file.as_ref().is_some_and(|(_, start)| {
+            session_due(*start, processor.payload_n(), cc, session_length)
+        });
+        if runtime.take_rotate() || due {
+            file = None;
+        }
+        if !runtime.recording() {
+            if file.take().is_some() {
+                info!("Recording stopped");
+                metadata.stop();
+            }
+            continue;
+        }
+        let (file, _) = file.get_or_insert_with(|| {
+            let start = processor.window_start();
+            let file = create_filterbank(
+                &mut fb,
+                Path::new("."),
+                payload_epoch(runtime.payload_start(), start, cc),
+                &runtime.pointing(),
+                &metadata,
+            );
+            (file, start)
+        });
+        // Stream to FB
+        file.write_all(&fb.pack(processor.spectrum())).unwrap();
+        health.commit();
+    }
+}
+
+pub(crate) fn create_psrfits(
+    cc: &CaptureConfig,
+    nsblk: usize,
+    directory: &Path,
+    tstart: Epoch,
+    pointing: &Pointing,
+    metadata: &Metadata,
+) -> PsrfitsWriter {
+    let filename = directory.join(format!("grex-{}.fits", heimdall_timestamp(&tstart)));
+    info!("Writing to new PSRFITS {}", filename.display());
+    let writer = PsrfitsWriter::create(
+        &filename,
+        cc,
+        nsblk,
+        tstart,
+        pointing,
+        &metadata.config().telescope,
+    )
     .unwrap();
-    // Create the filterbank context
-    let mut fb = WriteFilterbank::new(cc.channels, 1);
-    // Setup the header stuff
-    fb.fch1 = Some(LOWBAND_MID_FREQ); // Start of band + half the step size
-    fb.foff = Some(BANDWIDTH / cc.channels as f64);
-    fb.tsamp = Some(cc.tsamp() as f64);
-    // We will capture the timestamp on the first packet
-    let mut first_payload = true;
+    metadata.start(&filename.to_string_lossy(), sidecar_path(&filename), tstart);
+    writer
+}
+
+/// Same as the filterbank consumer, but writing PSRFITS search-mode files.
+/// Files also rotate after `subints_per_file` rows, and a partial subint at the end is padded with
+/// the mean spectrum of its samples and written as the last row.
+pub fn psrfits_consumer(
+    mut consumer: rtrb::Consumer<Packet>,
+    cc: &CaptureConfig,
+    mut waiter: Waiter,
+    mut processor: Processor,
+    health: Arc<Health>,
+    metadata: Arc<Metadata>,
+) {
+    let mut fullness_rising_edge = false;
+    let outputs = &metadata.config().outputs;
+    let (nsblk, subints_per_file) = (outputs.nsblk, outputs.subints_per_file);
+    let session_length = outputs.session_length;
+    let finish = |writer: PsrfitsWriter| {
+        if let Err(e) = writer.finish() {
+            error!("Error finishing PSRFITS file - {}", e);
+        }
+    };
+    let mut file: Option<(PsrfitsWriter, u64)> = None;
     loop {
-        // Check fullness and report
-        if fullness(&consumer) >= 0.9 && !fullness_rising_edge {
-            warn!("The raw UDP byte ringbuffer is 90% full");
-            fullness_rising_edge = true;
-        } else if fullness(&consumer) < 0.9 && fullness_rising_edge {
-            fullness_rising_edge = false;
-        }
-        let payload = if let Ok(pl) = consumer.pop() {
-            pl
-        } else {
+        check_fullness(&consumer, &mut fullness_rising_edge);
+        // Stop waiting if we're shut down while the data has stalled
+        let packet = waiter.pop_unless(&mut consumer, || processor.runtime().shutting_down());
+        let full = packet.is_some_and(|packet| processor.process(&packet));
+        let runtime = processor.runtime();
+        if runtime.shutting_down() {
+            if let Some((writer, _)) = file.take() {
+                finish(writer);
+            }
+            return;
+        }
+        if !full {
             continue;
+        }
+        metadata.check_calibration();
+        let due = file.as_ref().is_some_and(|(writer, start)| {
+            session_due(*start, processor.payload_n(), cc, session_length)
+                || subints_per_file.is_some_and(|n| writer.rows() >= n)
+        });
+        if runtime.take_rotate() || due {
+            if let Some((writer, _)) = file.take() {
+                finish(writer);
+            }
+        }
+        if !runtime.recording() {
+            if let Some((writer, _)) = file.take() {
+                finish(writer);
+                info!("Recording stopped");
+                metadata.stop();
+            }
+            continue;
+        }
+        let (writer, _) = file.get_or_insert_with(|| {
+            let start = processor.window_start();
+            let writer = create_psrfits(
+                cc,
+                nsblk,
+                Path::new("."),
+                payload_epoch(runtime.payload_start(), start, cc),
+                &runtime.pointing(),
+                &metadata,
+            );
+            (writer, start)
+        });
+        if let Err(e) = writer.push(processor.spectrum()) {
+            error!("Error writing PSRFITS row - {}", e);
+        }
+        health.commit();
+    }
+}
+
+/// The whole UTC second at or before `epoch`, as DADA's UTC_START only has second precision
+pub(crate) fn utc_start_second(epoch: Epoch) -> Epoch {
+    Epoch::from_unix_seconds(epoch.to_unix_seconds().floor())
+}
+
+/// The DADA OBS_OFFSET (in bytes) of a session starting at `start`, relative to `utc_start`
+fn obs_offset(utc_start: Epoch, start: Epoch, cc: &CaptureConfig) -> u64 {
+    let samples = ((start - utc_start).to_seconds() / cc.tsamp() as f64).round() as u64;
+    samples * spectrum_bytes(cc) as u64
+}
+
+/// Bytes in one averaged spectrum
+fn spectrum_bytes(cc: &CaptureConfig) -> usize {
+    cc.out_channels() * std::mem::size_of::<f32>()
+}
+
+/// Bytes in the PSRDADA blocks we create, which hold a window of averaged spectra
+pub fn dada_block_size(cc: &CaptureConfig) -> usize {
+    cc.window_size() * std::mem::size_of::<f32>()
+}
+
+/// Connect to (or create) the PSRDADA buffer.
+/// A buffer we create is destroyed when the client is dropped.
+fn dada_client(
+    key: i32,
+    cc: &CaptureConfig,
+    outputs: &OutputSection,
+) -> Result<DadaClient, String> {
+    let block_size = dada_block_size(cc);
+    let client = if outputs.dada_create {
+        info!(
+            "Creating PSRDADA buffer {:x} with {} blocks of {} bytes",
+            key, outputs.dada_blocks, block_size
+        );
+        DadaClientBuilder::new(key)
+            .buf_size(block_size as u64)
+            .num_bufs(outputs.dada_blocks)
+            .num_readers(outputs.dada_readers)
+            .build()
+            .map_err(|e| format!("Couldn't create PSRDADA buffer {:x}: {:?}", key, e))?
+    } else {
+        DadaClient::new(key).map_err(|e| {
+            format!(
+                "Couldn't connect to PSRDADA buffer {:x} ({:?}), create it or pass --create-dada",
+                key, e
+            )
+        })?
+    };
+    let buf_size = client.data_buf_size();
+    if buf_size == 0 {
+        return Err(format!("PSRDADA buffer {:x} has empty blocks", key));
+    }
+    if buf_size % spectrum_bytes(cc) != 0 {
+        warn!(
+            "PSRDADA buffer {:x} has {} byte blocks, which isn't a whole number of {} byte spectra. Spectra will be split across blocks",
+            key,
+            buf_size,
+            spectrum_bytes(cc)
+        );
+    } else if buf_size != block_size {
+        info!(
+            "PSRDADA buffer {:x} has {} spectra per block",
+            key,
+            buf_size / spectrum_bytes(cc)
+        );
+    }
+    Ok(client)
+}
+
+/// Keeps track of how full the current PSRDADA block is, so we can write to blocks of whatever
+/// size the buffer actually has, splitting spectra across block boundaries.
+pub(crate) struct BlockFill {
+    block_size: usize,
+    filled: usize,
+}
+
+impl BlockFill {
+    pub(crate) fn new(block_size: usize) -> Self {
+        Self {
+            block_size,
+            filled: 0,
+        }
+    }
+
+    /// Write as much of `bytes[*offset..]` to the block as fits, advancing `offset`.
+    /// Returns true if the block is now full and needs to be committed.
+    pub(crate) fn write(
+        &mut self,
+        block: &mut impl Write,
+        bytes: &[u8],
+        offset: &mut usize,
+    ) -> std::io::Result<bool> {
+        let n = (self.block_size - self.filled).min(bytes.len() - *offset);
+        block.write_all(&bytes[*offset..*offset + n])?;
+        *offset += n;
+        self.filled += n;
+        if self.filled == self.block_size {
+            self.filled = 0;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Start over with an empty block, after committing a partial one
+    pub(crate) fn reset(&mut self) {
+        self.filled = 0;
+    }
+}
+
+/// Whether a file/session that started at `start_payload` has run for `session_length` seconds
+pub(crate) fn session_due(
+    start_payload: u64,
+    payload_n: u64,
+    cc: &CaptureConfig,
+    session_length: Option<f32>,
+) -> bool {
+    session_length.is_some_and(|length| {
+        (payload_n.saturating_sub(start_payload) + 1) as f64 * cc.cadence as f64 >= length as f64
+    })
+}
+
+/// FILE_SIZE for the PSRDADA header, the bytes in a whole session if they're limited or else
+/// one data block
+pub(crate) fn file_size(cc: &CaptureConfig, session_length: Option<f32>, block_size: u64) -> u64 {
+    session_length.map_or(block_size, |length| {
+        (length as f64 / cc.tsamp() as f64).ceil() as u64 * spectrum_bytes(cc) as u64
+    })
+}
+
+/// A PSRDADA data block, so the block handling can be tested without a buffer
+pub(crate) trait DadaBlock: Write {
+    fn mark_eod(&mut self);
+    fn commit(self);
+}
+
+impl DadaBlock for WriteBlock<'_> {
+    fn mark_eod(&mut self) {
+        WriteBlock::mark_eod(self)
+    }
+
+    fn commit(self) {
+        WriteBlock::commit(self)
+    }
+}
+
+/// What to do with the block we're filling
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Step {
+    /// Write the next spectrum
+    Write,
+    /// Mark the end of the session, the next one starts in a fresh block
+    EndSession,
+    /// We're done, ending the session first if there is one
+    Stop { end_session: bool },
+}
+
+/// Where the spectra written to PSRDADA come from
+pub(crate) trait SpectrumSource {
+    /// Wait for what to do next
+    fn next(&mut self) -> Step;
+    /// The spectrum to write after [`Step::Write`]
+    fn spectrum(&self) -> &[u8];
+    /// Called every time a block is handed over to PSRDADA
+    fn committed(&mut self);
+}
+
+/// Fill `block` from `source`, committing it when it's full or the session ends. A spectrum that
+/// doesn't fit is split, with `split` holding how much of it was written so the rest goes at the
+/// start of the next block. Returns false once the source stops.
+pub(crate) fn fill_block(
+    mut block: impl DadaBlock,
+    fill: &mut BlockFill,
+    split: &mut Option<usize>,
+    source: &mut impl SpectrumSource,
+) -> std::io::Result<bool> {
+    loop {
+        let mut offset = match split.take() {
+            Some(offset) => offset,
+            None => match source.next() {
+                Step::Write => 0,
+                step @ (Step::EndSession | Step::Stop { end_session: true }) => {
+                    // Commit whatever we have of this block, even if that's nothing
+                    block.mark_eod();
+                    block.commit();
+                    fill.reset();
+                    source.committed();
+                    return Ok(step == Step::EndSession);
+                }
+                Step::Stop { end_session: false } => return Ok(false),
+            },
         };
-        unpack(&payload, &mut pol_a, &mut pol_b, &mut payload_n);
-        // Timestamp first one
-        if first_payload {
-            first_payload = false;
-            // Each payload represents cc.candence timesteps after payload_start
-            let payload_offset = (payload_n as f64 * cc.cadence as f64).seconds();
-            let payload_epoch = payload_start + payload_offset;
-            fb.tstart = Some(payload_epoch.to_mjd_utc_days());
-            // Write out the header
-            file.write_all(&fb.header_bytes()).unwrap();
-        }
-        // Add to averages
-        add_stokes_avg(&mut avg, &pol_a, &pol_b, cc);
-        avg_cnt += 1;
-        if avg_cnt == cc.avgs {
-            avg_cnt = 0;
-            let _ = tcp_sender.try_send(avg.clone());
-            // Zero the first and last 250 samples because aliasing
-            (avg[0..=250]).fill(0.0);
-            (avg[1797..=2047]).fill(0.0);
-            // Stream to FB
-            file.write_all(&fb.pack(&avg)).unwrap();
-            // Reset averages
-            avg.fill(0.0);
+        let bytes = source.spectrum();
+        if fill.write(&mut block, bytes, &mut offset)? {
+            debug!("Commiting block to PSRDADA");
+            *split = (offset < bytes.len()).then_some(offset);
+            block.commit();
+            source.committed();
+            return Ok(true);
+        }
+    }
+}
+
+/// The processed spectra for the DADA consumer, starting and ending sessions as we go
+struct DadaSessions<'a> {
+    key: i32,
+    consumer: rtrb::Consumer<Packet>,
+    cc: &'a CaptureConfig,
+    waiter: Waiter,
+    processor: Processor,
+    health: Arc<Health>,
+    metadata: Arc<Metadata>,
+    hc: HeaderClient<'a>,
+    // The header keys that don't change between sessions
+    header: HashMap<String, String>,
+    session_length: Option<f32>,
+    fullness_rising_edge: bool,
+    // The payload number the current session started at, if there is one
+    session: Option<u64>,
+    // Set if we ended a session before writing the current spectrum
+    pending: bool,
+}
+
+impl DadaSessions<'_> {
+    fn start_session(&mut self) {
+        let runtime = self.processor.runtime();
+        let start_payload = self.processor.window_start();
+        let payload_start = runtime.payload_start();
+        let start = payload_epoch(payload_start, start_payload, self.cc);
+        let utc_start = utc_start_second(payload_start);
+        let header = &mut self.header;
+        header.insert("UTC_START".to_owned(), heimdall_timestamp(&utc_start));
+        header.insert(
+            "OBS_OFFSET".to_owned(),
+            obs_offset(utc_start, start, self.cc).to_string(),
+        );
+        let pointing = runtime.pointing();
+        for (key, value) in [
+            ("SOURCE", pointing.source_name),
+            ("RA", pointing.ra.map(dada_ra)),
+            ("DEC", pointing.dec.map(dada_dec)),
+        ] {
+            match value {
+                Some(value) => header.insert(key.to_owned(), value),
+                None => header.remove(key),
+            };
         }
+        // Safety: All these header keys and values are valid
+        unsafe { self.hc.push_header(header).unwrap() };
+        info!(
+            "Started DADA session at {} (OBS_OFFSET {})",
+            start, header["OBS_OFFSET"]
+        );
+        self.metadata.start(
+            &format!("dada:{:x}", self.key),
+            PathBuf::from(format!("grex-{}.json", heimdall_timestamp(&start))),
+            start,
+        );
+        self.session = Some(start_payload);
+    }
+
+    fn end_session(&mut self) -> bool {
+        if self.session.take().is_none() {
+            return false;
+        }
+        info!("Ending DADA session");
+        self.metadata.stop();
+        true
+    }
+}
+
+impl SpectrumSource for DadaSessions<'_> {
+    fn next(&mut self) -> Step {
+        loop {
+            if !self.pending {
+                check_fullness(&self.consumer, &mut self.fullness_rising_edge);
+                // Wait until we get data. By default this busy waits and pegs the CPU at 100%, as
+                // we don't want to give the time to the kernel with yield (15ms penalty). We stop
+                // waiting if we're shut down while the data has stalled.
+                let packet = self.waiter.pop_unless(&mut self.consumer, || {
+                    self.processor.runtime().shutting_down()
+                });
+                // If we've filled the averaging window, move on to the next step
+                let full = packet.is_some_and(|packet| self.processor.process(&packet));
+                if !full && !self.processor.runtime().shutting_down() {
+                    continue;
+                }
+                self.metadata.check_calibration();
+            }
+            self.pending = false;
+            let runtime = self.processor.runtime();
+            let shutdown = runtime.shutting_down();
+            let recording = runtime.recording();
+            let rotate = runtime.take_rotate()
+                || self.session.is_some_and(|start| {
+                    session_due(
+                        start,
+                        self.processor.payload_n(),
+                        self.cc,
+                        self.session_length,
+                    )
+                });
+            if shutdown {
+                return Step::Stop {
+                    end_session: self.end_session(),
+                };
+            }
+            if (rotate || !recording) && self.end_session() {
+                // Come back for the spectrum we're holding on to
+                self.pending = true;
+                return Step::EndSession;
+            }
+            if !recording {
+                continue;
+            }
+            if self.session.is_none() {
+                self.start_session();
+            }
+            return Step::Write;
+        }
+    }
+
+    fn spectrum(&self) -> &[u8] {
+        self.processor.spectrum().as_byte_slice()
+    }
+
+    fn committed(&mut self) {
+        self.health.commit();
     }
 }
 
 /// Grab bytes from the capture thread to get them all the way to heimdall.
 /// This doesn't need to be realtime, because we have cushion from the rtrb.
 /// This function needs to run at less than the cadence (8.192us) (on average).
+///
+/// Data is written in sessions, each starting with its own header and ending with end-of-data.
+/// A session ends when rotated (from the control channel, a re-arm, a pointing change, or the
+/// session length running out) or when recording is stopped, and the next one starts on the
+/// following spectrum. UTC_START is the second the FPGA was armed in, so OBS_OFFSET keeps
+/// counting across sessions until the timing is reset by a re-arm.
+///
+/// Every session goes through the same writer. Marking end-of-data ends PSRDADA's current
+/// transfer, and the next header and block start another, which readers see as a new
+/// observation.
+///
+/// Returns on shutdown after ending the current session, dropping the client (and so destroying
+/// the buffer if we created it).
 pub fn dada_consumer(
     key: i32,
-    mut consumer: rtrb::Consumer<PayloadBytes>,
-    tcp_sender: Sender<Vec<f32>>,
+    consumer: rtrb::Consumer<Packet>,
     cc: &CaptureConfig,
-    payload_start: Epoch,
+    waiter: Waiter,
+    processor: Processor,
+    health: Arc<Health>,
+    metadata: Arc<Metadata>,
 ) {
-    let mut fullness_rising_edge = false;
-    // Containers for parsed spectra
-    let mut pol_a = vec![ComplexByte::default(); cc.channels];
-    let mut pol_b = vec![ComplexByte::default(); cc.channels];
-    let mut payload_n = 0u64;
-    // Averaging window
-    let mut avg = vec![0f32; cc.channels];
-    let mut avg_cnt = 0usize;
-    // DADA window
-    let mut stokes_cnt = 0usize;
-    // We will capture the timestamp on the first packet
-    let mut first_payload = true;
-    // Send the header (heimdall only wants one)
+    // Connect to the PSRDADA buffer on this thread
+    let mut client = dada_client(key, cc, &metadata.config().outputs).unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1)
+    });
+    let mut fill = BlockFill::new(client.data_buf_size());
     let mut header = HashMap::from([
-        ("NCHAN".to_owned(), cc.channels.to_string()),
-        ("BW".to_owned(), "250".to_owned()),
-        ("FREQ".to_owned(), "1405".to_owned()),
+        ("NCHAN".to_owned(), cc.out_channels().to_string()),
+        ("BW".to_owned(), cc.bandwidth.to_string()),
+        ("FREQ".to_owned(), cc.fcenter().to_string()),
         ("NPOL".to_owned(), "1".to_owned()),
-        ("NBIT".to_owned(), "16".to_owned()),
-        ("OBS_OFFSET".to_owned(), 0.to_string()),
+        (
+            "NBIT".to_owned(),
+            (8 * std::mem::size_of::<f32>()).to_string(),
+        ),
         ("TSAMP".to_owned(), (cc.tsamp() * 1e6).to_string()),
+        ("RESOLUTION".to_owned(), spectrum_bytes(cc).to_string()),
+        (
+            "FILE_SIZE".to_owned(),
+            file_size(
+                cc,
+                metadata.config().outputs.session_length,
+                client.data_buf_size() as u64,
+            )
+            .to_string(),
+        ),
     ]);
-    // Connect to the PSRDADA buffer on this thread
-    let mut client = DadaClient::new(key).expect("Could not connect to PSRDADA buffer");
+    if let Some(name) = &metadata.config().telescope.name {
+        header.insert("TELESCOPE".to_owned(), name.clone());
+    }
     // Grab PSRDADA writing context
-    let (mut hc, mut dc) = client.split();
+    let (hc, mut dc) = client.split();
     let mut data_writer = dc.writer();
-    info!("DADA header pushed, starting main loop");
-    // Start the main consumer loop
-    loop {
-        // Grab the next psrdada block we can write to (BLOCKING)
-        let mut block = data_writer.next().unwrap();
+    let mut sessions = DadaSessions {
+        key,
+        consumer,
+        cc,
+        waiter,
+        processor,
+        session_length: metadata.config().outputs.session_length,
+        health,
+        metadata,
+        hc,
+        header,
+        fullness_rising_edge: false,
+        session: None,
+        pending: false,
+    };
+    info!("Connected to PSRDADA, starting main loop");
+    // How much of the current spectrum we've written, if it was split across blocks
+    let mut split: Option<usize> = None;
+    // Grab the next psrdada block we can write to (BLOCKING) every time we fill one
+    while fill_block(
+        data_writer.next().unwrap(),
+        &mut fill,
+        &mut split,
+        &mut sessions,
+    )
+    .unwrap()
+    {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{complex::Complex, config::Config, reduce::Combine};
+
+    #[test]
+    fn test_obs_offset() {
+        let cc = Config::default().capture_config();
+        let payload_start = Epoch::from_unix_seconds(1_660_000_000.25);
+        let utc_start = utc_start_second(payload_start);
+        assert_eq!(utc_start, Epoch::from_unix_seconds(1_660_000_000.0));
+        // A quarter of a second is 7629.39 spectra
+        assert_eq!(obs_offset(utc_start, payload_start, &cc), 7629 * 2048 * 4);
+        assert!(!session_due(0, 1000, &cc, None));
+        assert!(!session_due(0, 1000, &cc, Some(1.0)));
+        assert!(session_due(0, 122_070, &cc, Some(1.0)));
+        // A second is 30517.58 spectra
+        assert_eq!(file_size(&cc, Some(1.0), 4096), 30518 * 2048 * 4);
+        assert_eq!(file_size(&cc, None, 4096), 4096);
+    }
+
+    #[test]
+    fn test_block_fill() {
+        // 10 byte "spectra" into 16 byte blocks
+        let spectra: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 10]).collect();
+        let mut fill = BlockFill::new(16);
+        let mut blocks = vec![vec![]];
+        for spectrum in &spectra {
+            let mut offset = 0;
+            while offset < spectrum.len() {
+                if fill
+                    .write(blocks.last_mut().unwrap(), spectrum, &mut offset)
+                    .unwrap()
+                {
+                    blocks.push(vec![]);
+                }
+            }
+        }
+        assert_eq!(
+            blocks.iter().map(Vec::len).collect::<Vec<_>>(),
+            [16, 16, 16, 2]
+        );
+        assert_eq!(blocks.concat(), spectra.concat());
+    }
+
+    /// Plays back a list of steps, writing 100 byte spectra
+    struct Script {
+        steps: std::vec::IntoIter<Step>,
+        written: Vec<Vec<u8>>,
+        commits: usize,
+    }
+
+    impl Script {
+        fn new(steps: Vec<Step>) -> Self {
+            Self {
+                steps: steps.into_iter(),
+                written: vec![],
+                commits: 0,
+            }
+        }
+    }
+
+    impl SpectrumSource for Script {
+        fn next(&mut self) -> Step {
+            let step = self.steps.next().unwrap();
+            if step == Step::Write {
+                self.written.push(vec![self.written.len() as u8; 100]);
+            }
+            step
+        }
+
+        fn spectrum(&self) -> &[u8] {
+            self.written.last().unwrap()
+        }
+
+        fn committed(&mut self) {
+            self.commits += 1;
+        }
+    }
+
+    /// A block of a pretend PSRDADA buffer, that ends up in `committed` with its end of data flag
+    struct MemoryBlock<'a> {
+        committed: &'a mut Vec<(Vec<u8>, bool)>,
+        data: Vec<u8>,
+        eod: bool,
+    }
+
+    impl Write for MemoryBlock<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.data.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl DadaBlock for MemoryBlock<'_> {
+        fn mark_eod(&mut self) {
+            self.eod = true;
+        }
+
+        fn commit(self) {
+            self.committed.push((self.data, self.eod));
+        }
+    }
+
+    fn run(script: &mut Script, block_size: usize) -> Vec<(Vec<u8>, bool)> {
+        let mut committed = vec![];
+        let mut fill = BlockFill::new(block_size);
+        let mut split = None;
         loop {
-            // Check fullness and report
-            if fullness(&consumer) >= 0.9 && !fullness_rising_edge {
-                warn!("The raw UDP byte ringbuffer is 90% full");
-                fullness_rising_edge = true;
-            } else if fullness(&consumer) < 0.9 && fullness_rising_edge {
-                fullness_rising_edge = false;
-            }
-            // Busy wait until we get data. This will peg the CPU at 100%, but that's ok
-            // we don't want to give the time to the kernel with yeild, as that has a 15ms penalty
-            let payload = if let Ok(pl) = consumer.pop() {
-                pl
-            } else {
-                continue;
+            let block = MemoryBlock {
+                committed: &mut committed,
+                data: vec![],
+                eod: false,
             };
-            // Unpack payload to spectra
-            unpack(&payload, &mut pol_a, &mut pol_b, &mut payload_n);
-            // Timestamp first one
-            if first_payload {
-                first_payload = false;
-                // Each payload represents cc.candence timesteps after payload_start
-                let payload_offset = (payload_n as f64 * cc.cadence as f64).seconds();
-                let payload_epoch = payload_start + payload_offset;
-                let timestamp_str = heimdall_timestamp(&payload_epoch);
-                header.insert("UTC_START".to_owned(), timestamp_str);
-                // Write the single header
-                // Safety: All these header keys and values are valid
-                unsafe { hc.push_header(&header).unwrap() };
-            }
-            // TODO: Push to a time-domain buffer that we might want to dump
-            // Generate stokes for this sample and push to averaging window
-            // This is a transpose operation because the average calculation needs the time axis
-            // to be contiguous as that's what we're summing over
-            add_stokes_avg(&mut avg, &pol_a, &pol_b, cc);
-            avg_cnt += 1;
-            // If we've filled the averaging window, move on to the next step
-            if avg_cnt == cc.avgs {
-                // Reset the counter
-                avg_cnt = 0;
-                // Zero the first and last 250 samples because aliasing
-                (avg[0..=250]).fill(0.0);
-                (avg[1797..=2047]).fill(0.0);
-                // Write this block
-                block.write_all(avg.as_byte_slice()).unwrap();
-                // Send this average over to the TCP listener, we don't care if this errors
-                let _ = tcp_sender.try_send(avg.clone());
-                // Reset the averages
-                avg.fill(0.0);
-                stokes_cnt += 1;
-                // If we've filled the window, commit it to PSRDADA
-                if stokes_cnt == cc.samples {
-                    debug!("Commiting window to PSRDADA");
-                    // Reset the stokes counter
-                    stokes_cnt = 0;
-                    // Commit data and update
-                    block.commit();
-                    //Break to finish the write
-                    break;
-                }
+            if !fill_block(block, &mut fill, &mut split, script).unwrap() {
+                return committed;
             }
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::complex::Complex;
+    #[test]
+    fn test_fill_block() {
+        use Step::*;
+        // Two sessions of three spectra, in blocks that aren't a multiple of the spectrum size
+        let mut script = Script::new(vec![
+            Write,
+            Write,
+            Write,
+            EndSession,
+            Write,
+            Write,
+            Write,
+            Stop { end_session: true },
+        ]);
+        let committed = run(&mut script, 256);
+        // Each session ends with a partial block marked end of data
+        assert_eq!(
+            committed
+                .iter()
+                .map(|(data, eod)| (data.len(), *eod))
+                .collect::<Vec<_>>(),
+            [(256, false), (44, true), (256, false), (44, true)]
+        );
+        let data: Vec<u8> = committed.into_iter().flat_map(|(data, _)| data).collect();
+        assert_eq!(data, script.written.concat());
+        assert_eq!(script.commits, 4);
+        // Without a session to end, stopping leaves the block alone
+        let mut script = Script::new(vec![Write, Write, EndSession, Stop { end_session: false }]);
+        let committed = run(&mut script, 100);
+        assert_eq!(committed.len(), 3);
+        assert!(committed[2].0.is_empty() && committed[2].1);
+    }
+
+    #[test]
+    #[ignore = "needs a local PSRDADA install with shared memory"]
+    fn test_dada_blocks() {
+        use std::io::Read;
+
+        let mut client = DadaClientBuilder::new(0xbead)
+            .buf_size(256)
+            .num_bufs(16)
+            .build()
+            .unwrap();
+        let (_, mut dc) = client.split();
+        // The same as test_fill_block, but through PSRDADA
+        let mut script = Script::new(vec![
+            Step::Write,
+            Step::Write,
+            Step::Write,
+            Step::EndSession,
+            Step::Write,
+            Step::Write,
+            Step::Stop { end_session: true },
+        ]);
+        {
+            let mut writer = dc.writer();
+            let mut fill = BlockFill::new(256);
+            let mut split = None;
+            while fill_block(writer.next().unwrap(), &mut fill, &mut split, &mut script).unwrap() {}
+        }
+        // Each session reads back on its own, up to its end of data
+        for session in [&script.written[..3], &script.written[3..]] {
+            let mut reader = dc.reader();
+            let mut read = vec![];
+            while let Some(mut block) = reader.next() {
+                block.read_to_end(&mut read).unwrap();
+            }
+            assert_eq!(read, session.concat());
+        }
+    }
+
+    #[test]
+    fn test_missing() {
+        use crate::{
+            capture::{PAYLOAD_SIZE, TIMESTAMP_SIZE},
+            pointing::Pointing,
+        };
+        let cc = Config::default().capture_config();
+        let runtime = Arc::new(Runtime::new(
+            Epoch::from_unix_seconds(1_690_000_000.0),
+            ChannelMask::new(cc.channels),
+            1,
+            Pointing::default(),
+            false,
+        ));
+        let (tcp_sender, tcp_receiver) = crossbeam_channel::unbounded();
+        let mut processor = Processor::new(
+            &cc,
+            runtime,
+            Arc::new(Health::default()),
+            tcp_sender,
+            None,
+            Injector::new(&cc, None),
+            None,
+            None,
+        );
+        let mut payload = [0u8; PAYLOAD_SIZE];
+        // Two lost in the second average
+        for n in (0..6u64).chain(8..12) {
+            payload[..TIMESTAMP_SIZE].copy_from_slice(&n.to_be_bytes());
+            processor.process(&Packet::single(payload));
+        }
+        let missing: Vec<_> = tcp_receiver.try_iter().map(|s| s.missing).collect();
+        assert_eq!(missing, [0, 2]);
+    }
 
     #[test]
     fn test_stokes() {
@@ -252,4 +1159,24 @@ mod tests {
         let pol_y = Complex { re: -1i8, im: -1i8 };
         assert_eq!(4u16, stokes_i(pol_x, pol_y))
     }
+
+    #[test]
+    fn test_stokes_avg() {
+        let mut cc = CaptureConfig {
+            channels: 4,
+            samples: 1024,
+            decimate: 2,
+            ..Config::default().capture_config()
+        };
+        let pol = vec![Complex { re: 1i8, im: 1i8 }; cc.channels];
+        // Time is a mean whichever way the channels are combined
+        for combine in [Combine::Mean, Combine::Sum] {
+            cc.combine = combine;
+            let mut avg = vec![0f32; cc.channels];
+            for _ in 0..cc.avgs {
+                add_stokes_avg(&mut avg, &pol, &pol, &cc);
+            }
+            assert_eq!(avg, [4.0; 4]);
+        }
+    }
 }
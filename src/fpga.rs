@@ -0,0 +1,30 @@
+//! Control of the SNAP board
+
+use casperfpga::transport::{tapcp::Tapcp, Transport};
+use hifitime::Epoch;
+
+/// Reset the boards and signal them all to start on the next rising PPS edge, returning the
+/// epoch of the first payload. Every board counts payloads from the same edge, which is what
+/// lets us line up their sub-bands.
+pub fn arm(transports: &mut [Tapcp]) -> Result<Epoch, String> {
+    // FIXME replace 32 bit word with bool
+    for (board, transport) in transports.iter_mut().enumerate() {
+        pulse(transport, "master_rst").map_err(|e| format!("Board {}: {}", board, e))?;
+    }
+    // FIXME, actually time this
+    let payload_start = Epoch::now().map_err(|e| format!("Couldn't read the clock: {}", e))?;
+    for (board, transport) in transports.iter_mut().enumerate() {
+        pulse(transport, "pps_trig").map_err(|e| format!("Board {}: {}", board, e))?;
+    }
+    Ok(payload_start)
+}
+
+/// Set a register high then low again
+fn pulse(transport: &mut Tapcp, device: &str) -> Result<(), String> {
+    for value in [1u32, 0u32] {
+        transport
+            .write(device, 0, &value)
+            .map_err(|e| format!("Couldn't write {}: {}", device, e))?;
+    }
+    Ok(())
+}
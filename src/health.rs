@@ -0,0 +1,426 @@
+//! Health checks for the packet flow, and a watchdog that tries to recover from stalls
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use casperfpga::transport::tapcp::Tapcp;
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use crate::{control::Runtime, fpga, packet::Reject, timing::TimingStats};
+
+// How often the watchdog checks on things
+const WATCHDOG_PERIOD: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    /// Packets or commits are late, but still trickling in
+    Degraded,
+    /// No packets for the full stall timeout
+    Stalled,
+}
+
+#[derive(Debug, Copy, Clone)]
+/// How long things can go quiet before we change status
+pub struct HealthLimits {
+    /// Time without a packet before we're degraded
+    pub packet: Duration,
+    /// Time without a packet before we're stalled
+    pub stall: Duration,
+    /// Time without writing data out before we're degraded
+    pub commit: Duration,
+}
+
+/// Decide on a status given how long it's been since we saw packets and wrote data out
+pub fn assess(
+    since_packet: Duration,
+    since_commit: Duration,
+    limits: &HealthLimits,
+) -> HealthStatus {
+    if since_packet >= limits.stall {
+        HealthStatus::Stalled
+    } else if since_packet >= limits.packet || since_commit >= limits.commit {
+        HealthStatus::Degraded
+    } else {
+        HealthStatus::Ok
+    }
+}
+
+/// Running totals of what came in off the wire
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PacketStats {
+    /// Valid packets
+    pub received: u64,
+    /// Packets we couldn't use, for any of the reasons in [`Rejections`]
+    pub malformed: u64,
+    /// Number of times the payload number skipped
+    pub gaps: u64,
+    /// Number of payloads we never saw because of those gaps
+    pub missing: u64,
+    /// libpcap timeouts
+    pub timeouts: u64,
+}
+
+impl std::ops::Sub for PacketStats {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self {
+            received: self.received - rhs.received,
+            malformed: self.malformed - rhs.malformed,
+            gaps: self.gaps - rhs.gaps,
+            missing: self.missing - rhs.missing,
+            timeouts: self.timeouts - rhs.timeouts,
+        }
+    }
+}
+
+/// Running totals of the packets we threw away, by why
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Rejections {
+    /// Shorter than their headers say
+    pub truncated: u64,
+    /// Neither IPv4 nor IPv6
+    pub not_ip: u64,
+    /// IP fragments
+    pub fragment: u64,
+    /// Not UDP
+    pub not_udp: u64,
+    /// UDP payloads that weren't the size of one of ours
+    pub size: u64,
+    /// Not from (or to) any of our boards
+    pub unknown: u64,
+    /// Cut short by the snap length
+    pub snapped: u64,
+    /// Payload numbers way off from the rest
+    pub sequence: u64,
+    /// Of all of those, how many were saved to the sample file
+    pub sampled: u64,
+}
+
+/// Running totals for each board, when merging several
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BoardStats {
+    /// Valid packets
+    pub received: u64,
+    /// Number of times the payload number skipped
+    pub gaps: u64,
+    /// Number of payloads we never saw because of those gaps
+    pub missing: u64,
+    /// Packets that showed up after their spectrum was stitched together
+    pub late: u64,
+    /// Stitched spectra with this board's sub-band zeroed
+    pub filled: u64,
+    /// Whether we're still waiting on this board's packets
+    pub up: bool,
+}
+
+struct BoardHealth {
+    received: AtomicU64,
+    gaps: AtomicU64,
+    missing: AtomicU64,
+    late: AtomicU64,
+    filled: AtomicU64,
+    // Only written by the capture thread, u64::MAX before the first packet
+    last_payload_n: AtomicU64,
+    up: AtomicBool,
+}
+
+impl Default for BoardHealth {
+    fn default() -> Self {
+        Self {
+            received: AtomicU64::new(0),
+            gaps: AtomicU64::new(0),
+            missing: AtomicU64::new(0),
+            late: AtomicU64::new(0),
+            filled: AtomicU64::new(0),
+            last_payload_n: AtomicU64::new(u64::MAX),
+            up: AtomicBool::new(true),
+        }
+    }
+}
+
+/// Timestamps of the last bits of progress through the pipeline, shared by all the threads
+pub struct Health {
+    started: Instant,
+    // Nanoseconds since `started`
+    last_packet: AtomicU64,
+    last_commit: AtomicU64,
+    timeouts: AtomicU64,
+    // Indexed by the `Reject` discriminant
+    rejected: [AtomicU64; Reject::COUNT],
+    sampled: AtomicU64,
+    boards: Vec<BoardHealth>,
+    timing: Mutex<TimingStats>,
+    status: AtomicU8,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+impl Health {
+    /// Health for the given number of boards
+    pub fn new(boards: usize) -> Self {
+        Self {
+            started: Instant::now(),
+            last_packet: AtomicU64::new(0),
+            last_commit: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+            rejected: Default::default(),
+            sampled: AtomicU64::new(0),
+            boards: (0..boards).map(|_| BoardHealth::default()).collect(),
+            timing: Mutex::new(TimingStats::default()),
+            status: AtomicU8::new(HealthStatus::Ok as u8),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_nanos() as u64
+    }
+
+    /// Called by the capture thread for every valid packet
+    pub fn packet(&self, board: usize, payload_n: u64) {
+        self.last_packet.store(self.now(), Ordering::Relaxed);
+        let board = &self.boards[board];
+        board.received.fetch_add(1, Ordering::Relaxed);
+        let last = board.last_payload_n.swap(payload_n, Ordering::Relaxed);
+        // Payload numbers restart from zero when the FPGA gets re-armed, which we also count
+        if last != u64::MAX && payload_n != last.wrapping_add(1) {
+            board.gaps.fetch_add(1, Ordering::Relaxed);
+            board
+                .missing
+                .fetch_add(payload_n.saturating_sub(last + 1), Ordering::Relaxed);
+        }
+    }
+
+    /// Called when merging for a packet that came in too late to be used
+    pub fn late(&self, board: usize) {
+        self.boards[board].late.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called when merging for a spectrum missing this board's sub-band
+    pub fn filled(&self, board: usize) {
+        self.boards[board].filled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called when merging as boards drop out and come back
+    pub fn set_up(&self, board: usize, up: bool) {
+        self.boards[board].up.store(up, Ordering::Relaxed);
+    }
+
+    /// Called by the capture thread for every packet that wasn't a payload
+    pub fn rejected(&self, reason: Reject) {
+        self.rejected[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejections(&self) -> Rejections {
+        let count = |reason: Reject| self.rejected[reason as usize].load(Ordering::Relaxed);
+        Rejections {
+            truncated: count(Reject::Truncated),
+            not_ip: count(Reject::NotIp),
+            fragment: count(Reject::Fragment),
+            not_udp: count(Reject::NotUdp),
+            size: count(Reject::Size),
+            unknown: count(Reject::Unknown),
+            snapped: count(Reject::Snapped),
+            sequence: count(Reject::Sequence),
+            sampled: self.sampled.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Called by the capture thread as it works out the packet timing
+    pub fn set_timing(&self, timing: TimingStats) {
+        *self.timing.lock().unwrap() = timing;
+    }
+
+    pub fn timing(&self) -> TimingStats {
+        *self.timing.lock().unwrap()
+    }
+
+    /// Called by the capture thread for every rejected packet it saves
+    pub fn sampled(&self) {
+        self.sampled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called by the consumers whenever data is written out
+    pub fn commit(&self) {
+        self.last_commit.store(self.now(), Ordering::Relaxed);
+    }
+
+    /// Called by the capture thread on every libpcap timeout
+    pub fn timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Time since the last valid packet (or since startup if there hasn't been one)
+    pub fn since_packet(&self) -> Duration {
+        Duration::from_nanos(
+            self.now()
+                .saturating_sub(self.last_packet.load(Ordering::Relaxed)),
+        )
+    }
+
+    /// Time since data was last written out (or since startup if it hasn't been)
+    pub fn since_commit(&self) -> Duration {
+        Duration::from_nanos(
+            self.now()
+                .saturating_sub(self.last_commit.load(Ordering::Relaxed)),
+        )
+    }
+
+    /// Total number of libpcap timeouts
+    pub fn timeouts(&self) -> u64 {
+        self.timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Totals over all the boards
+    pub fn packet_stats(&self) -> PacketStats {
+        let boards = self.board_stats();
+        PacketStats {
+            received: boards.iter().map(|b| b.received).sum(),
+            malformed: self
+                .rejected
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .sum(),
+            gaps: boards.iter().map(|b| b.gaps).sum(),
+            missing: boards.iter().map(|b| b.missing).sum(),
+            timeouts: self.timeouts(),
+        }
+    }
+
+    pub fn board_stats(&self) -> Vec<BoardStats> {
+        self.boards
+            .iter()
+            .map(|b| BoardStats {
+                received: b.received.load(Ordering::Relaxed),
+                gaps: b.gaps.load(Ordering::Relaxed),
+                missing: b.missing.load(Ordering::Relaxed),
+                late: b.late.load(Ordering::Relaxed),
+                filled: b.filled.load(Ordering::Relaxed),
+                up: b.up.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    pub fn status(&self) -> HealthStatus {
+        match self.status.load(Ordering::Relaxed) {
+            0 => HealthStatus::Ok,
+            1 => HealthStatus::Degraded,
+            _ => HealthStatus::Stalled,
+        }
+    }
+}
+
+/// Periodically assess the health of the pipeline, logging state transitions.
+/// If given the FPGA transports, re-arm the boards after being stalled for `rearm_after`.
+pub fn watchdog(
+    health: Arc<Health>,
+    limits: HealthLimits,
+    runtime: Arc<Runtime>,
+    mut rearm: Option<(Vec<Tapcp>, Duration)>,
+) {
+    let mut last_timeouts = 0;
+    let mut last_rearm: Option<Instant> = None;
+    loop {
+        std::thread::sleep(WATCHDOG_PERIOD);
+        let since_packet = health.since_packet();
+        let since_commit = health.since_commit();
+        let status = assess(since_packet, since_commit, &limits);
+        let old_status = health.status();
+        if status != old_status {
+            let timeouts = health.timeouts();
+            let msg = format!(
+                "Health {:?} -> {:?} ({:.1}s since last packet, {:.1}s since last write, {} libpcap timeouts)",
+                old_status,
+                status,
+                since_packet.as_secs_f32(),
+                since_commit.as_secs_f32(),
+                timeouts - last_timeouts
+            );
+            match status {
+                HealthStatus::Ok => info!("{}", msg),
+                HealthStatus::Degraded => warn!("{}", msg),
+                HealthStatus::Stalled => error!("{}", msg),
+            }
+            last_timeouts = timeouts;
+            health.status.store(status as u8, Ordering::Relaxed);
+        }
+        if status != HealthStatus::Stalled {
+            last_rearm = None;
+            continue;
+        }
+        if let Some((transports, rearm_after)) = &mut rearm {
+            // Wait for the rearm duration since the stall started (or since our last attempt)
+            let waited = last_rearm.map_or(since_packet, |t| t.elapsed());
+            if waited < *rearm_after {
+                continue;
+            }
+            warn!(
+                "Stalled for {:.1}s, re-arming the FPGA",
+                since_packet.as_secs_f32()
+            );
+            // Either way, try again after another `rearm_after` if we're still stalled
+            last_rearm = Some(Instant::now());
+            match fpga::arm(transports) {
+                // Payload numbers restart, so everything downstream needs the new reference
+                Ok(payload_start) => runtime.set_payload_start(payload_start),
+                Err(e) => error!("Couldn't re-arm the FPGA: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assess() {
+        let limits = HealthLimits {
+            packet: Duration::from_secs(1),
+            stall: Duration::from_secs(5),
+            commit: Duration::from_secs(10),
+        };
+        let s = Duration::from_secs;
+        assert_eq!(assess(s(0), s(0), &limits), HealthStatus::Ok);
+        assert_eq!(assess(s(2), s(0), &limits), HealthStatus::Degraded);
+        assert_eq!(assess(s(0), s(11), &limits), HealthStatus::Degraded);
+        assert_eq!(assess(s(6), s(11), &limits), HealthStatus::Stalled);
+    }
+
+    #[test]
+    fn test_packet_stats() {
+        let health = Health::new(2);
+        for n in [10, 11, 12, 15, 16, 0] {
+            health.packet(0, n);
+        }
+        // Each board's sequence is tracked on its own
+        for n in [10, 12, 13] {
+            health.packet(1, n);
+        }
+        health.rejected(Reject::Size);
+        health.rejected(Reject::Fragment);
+        health.filled(1);
+        let stats = health.packet_stats();
+        assert_eq!(stats.received, 9);
+        assert_eq!(stats.malformed, 2);
+        assert_eq!(health.rejections().fragment, 1);
+        assert_eq!(stats.gaps, 3);
+        assert_eq!(stats.missing, 3);
+        let boards = health.board_stats();
+        assert_eq!(boards[1].missing, 1);
+        assert_eq!(boards[1].filled, 1);
+        assert!(boards[1].up);
+    }
+}
@@ -0,0 +1,404 @@
+//! Synthetic pulse injection into the Stokes I stream, to measure end-to-end sensitivity.
+//!
+//! Pulses are added to the averaged spectra before masking and decimation, so everything
+//! downstream (the outputs, monitoring and the search) sees them like a real signal. Each pulse
+//! is a Gaussian convolved with a one-sided exponential scattering tail, dispersed across the
+//! band. Every injection is logged (and appended to a JSON lines file) when it starts, so
+//! recovery rates can be worked out from the detections afterwards.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use hifitime::{Epoch, TimeUnits};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{CaptureConfig, KDM};
+
+// FWHM of a Gaussian in units of its standard deviation
+const FWHM_SIGMAS: f64 = 2.354_820_045;
+// How far out (in sigmas and scattering times) a pulse is still worth adding
+const GAUSSIAN_EXTENT: f64 = 6.0;
+const SCATTERING_EXTENT: f64 = 10.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// A single pulse to inject. Frequency dependent quantities are referenced to the band center.
+pub struct Injection {
+    /// MJD (UTC) of the peak of the (unscattered) pulse at the top of the band
+    pub mjd: f64,
+    /// Dispersion measure in pc cm^-3
+    pub dm: f64,
+    /// Fluence in (averaged Stokes I units) ms
+    pub fluence: f64,
+    /// Intrinsic FWHM in ms
+    pub width: f64,
+    /// Scattering time in ms, scaling as frequency^-4
+    #[serde(default)]
+    pub scattering: f64,
+    #[serde(default)]
+    pub spectral_index: f64,
+}
+
+impl Injection {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=f64::MAX).contains(&self.dm) {
+            return Err(format!("Injection DM of {} is negative", self.dm));
+        }
+        if self.width <= 0.0 || !self.width.is_finite() {
+            return Err("Injection width must be positive".to_owned());
+        }
+        if !(0.0..=f64::MAX).contains(&self.scattering) {
+            return Err("Injection scattering time can't be negative".to_owned());
+        }
+        if !self.fluence.is_finite() || !self.spectral_index.is_finite() {
+            return Err("Injection fluence and spectral index must be finite".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Parse `key=value` pairs (mjd or in, dm, fluence, width, scattering, index), where `in`
+    /// is the number of seconds after `now` to inject at
+    pub fn parse(pairs: &[&str], now: Epoch) -> Result<Self, String> {
+        let mut mjd = None;
+        let mut dm = None;
+        let mut fluence = None;
+        let mut width = None;
+        let mut scattering = 0.0;
+        let mut spectral_index = 0.0;
+        for pair in pairs {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got `{}`", pair))?;
+            let value = value
+                .parse::<f64>()
+                .map_err(|_| format!("Invalid number `{}`", value))?;
+            match key {
+                "mjd" => mjd = Some(value),
+                "in" => mjd = Some((now + value.seconds()).to_mjd_utc_days()),
+                "dm" => dm = Some(value),
+                "fluence" => fluence = Some(value),
+                "width" => width = Some(value),
+                "scattering" => scattering = value,
+                "index" => spectral_index = value,
+                _ => return Err(format!("Unknown injection field `{}`", key)),
+            }
+        }
+        let missing = |name: &str| format!("Injection needs a {}", name);
+        let injection = Self {
+            mjd: mjd.ok_or_else(|| missing("mjd (or in)"))?,
+            dm: dm.ok_or_else(|| missing("dm"))?,
+            fluence: fluence.ok_or_else(|| missing("fluence"))?,
+            width: width.ok_or_else(|| missing("width"))?,
+            scattering,
+            spectral_index,
+        };
+        injection.validate()?;
+        Ok(injection)
+    }
+}
+
+// Abramowitz & Stegun 7.1.26 for erfc(x) * exp(x^2) with x >= 0, the error in erfc(x) is
+// about 1e-7 so it's only good where exp(x^2) is small
+fn erfcx_poly(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.327_591_1 * x);
+    t * (0.254_829_592
+        + t * (-0.284_496_736 + t * (1.421_413_741 + t * (-1.453_152_027 + t * 1.061_405_429))))
+}
+
+fn erf(x: f64) -> f64 {
+    let y = 1.0 - erfcx_poly(x.abs()) * (-x * x).exp();
+    y.copysign(x)
+}
+
+/// The scaled complementary error function erfc(x) * exp(x^2) for x >= 0, which stays
+/// representable long after erfc(x) has underflowed
+fn erfcx(x: f64) -> f64 {
+    if x < 1.0 {
+        return erfcx_poly(x);
+    }
+    // The continued fraction (A&S 7.1.14) converges quickly from here on
+    let mut f = x;
+    for k in (1..=60).rev() {
+        f = x + k as f64 / 2.0 / f;
+    }
+    1.0 / (std::f64::consts::PI.sqrt() * f)
+}
+
+fn normal_cdf(z: f64) -> f64 {
+    0.5 * (1.0 + erf(z / std::f64::consts::SQRT_2))
+}
+
+/// The fraction of a pulse (Gaussian with `sigma`, scattered with `tau`) that arrived by `t`
+/// seconds after its peak
+fn pulse_cdf(t: f64, sigma: f64, tau: f64) -> f64 {
+    if t < -GAUSSIAN_EXTENT * sigma {
+        return 0.0;
+    }
+    if tau == 0.0 {
+        return normal_cdf(t / sigma);
+    }
+    // The tail is exp(sigma^2 / 2tau^2 - t/tau) * normal_cdf(t/sigma - sigma/tau), which is a huge
+    // number times one that's underflowed when the scattering is small. Writing the CDF with
+    // erfcx, the exponentials cancel down to the Gaussian.
+    let u = (sigma / tau - t / sigma) / std::f64::consts::SQRT_2;
+    let tail = if u > 0.0 {
+        0.5 * erfcx(u) * (-t * t / (2.0 * sigma * sigma)).exp()
+    } else {
+        (sigma * sigma / (2.0 * tau * tau) - t / tau).exp() * normal_cdf(t / sigma - sigma / tau)
+    };
+    normal_cdf(t / sigma) - tail
+}
+
+// An injection being added to the data, with everything per channel worked out
+struct Active {
+    injection: Injection,
+    peak: Epoch,
+    // Seconds after the peak that the pulse is done everywhere
+    duration: f64,
+    sigma: f64,
+    delays: Vec<f64>,
+    taus: Vec<f64>,
+    fluences: Vec<f64>,
+}
+
+impl Active {
+    fn new(injection: Injection, cc: &CaptureConfig) -> Self {
+        let native_foff = cc.bandwidth / cc.channels as f64;
+        let freqs: Vec<_> = (0..cc.channels)
+            .map(|c| cc.fch1 + c as f64 * native_foff)
+            .collect();
+        let f_top = freqs[cc.channels - 1];
+        let f_ref = cc.fcenter();
+        let sigma = injection.width * 1e-3 / FWHM_SIGMAS;
+        let delays: Vec<_> = freqs
+            .iter()
+            .map(|f| KDM * injection.dm * (f.powi(-2) - f_top.powi(-2)))
+            .collect();
+        let taus: Vec<_> = freqs
+            .iter()
+            .map(|f| injection.scattering * 1e-3 * (f / f_ref).powi(-4))
+            .collect();
+        let fluences = freqs
+            .iter()
+            .map(|f| injection.fluence * 1e-3 * (f / f_ref).powf(injection.spectral_index))
+            .collect();
+        // The lowest channel has the longest delay and scattering tail
+        let duration = delays[0] + GAUSSIAN_EXTENT * sigma + SCATTERING_EXTENT * taus[0];
+        Self {
+            peak: Epoch::from_mjd_utc(injection.mjd),
+            injection,
+            duration,
+            sigma,
+            delays,
+            taus,
+            fluences,
+        }
+    }
+
+    fn start(&self) -> Epoch {
+        self.peak - (GAUSSIAN_EXTENT * self.sigma).seconds()
+    }
+
+    fn end(&self) -> Epoch {
+        self.peak + self.duration.seconds()
+    }
+
+    // Add this pulse to the spectrum that starts `t` seconds after the peak
+    fn add(&self, spectrum: &mut [f32], t: f64, tsamp: f64) {
+        for (c, v) in spectrum.iter_mut().enumerate() {
+            let t = t - self.delays[c];
+            let fraction = pulse_cdf(t + tsamp, self.sigma, self.taus[c])
+                - pulse_cdf(t, self.sigma, self.taus[c]);
+            *v += (self.fluences[c] * fraction / tsamp) as f32;
+        }
+    }
+}
+
+/// Adds scheduled pulses to the averaged spectra as they go by
+pub struct Injector {
+    cc: CaptureConfig,
+    pending: Vec<Active>,
+    active: Vec<Active>,
+    log: Option<File>,
+}
+
+impl Injector {
+    /// Create an injector, logging injections to `log_path` if given
+    pub fn new(cc: &CaptureConfig, log_path: Option<&Path>) -> Self {
+        let log = log_path.and_then(|path| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| {
+                    warn!(
+                        "Couldn't open {}, injections won't be saved: {}",
+                        path.display(),
+                        e
+                    )
+                })
+                .ok()
+        });
+        Self {
+            cc: *cc,
+            pending: vec![],
+            active: vec![],
+            log,
+        }
+    }
+
+    pub fn schedule(&mut self, injection: Injection) {
+        info!("Scheduled injection {:?}", injection);
+        self.pending.push(Active::new(injection, &self.cc));
+    }
+
+    /// True if there's nothing scheduled or in progress, so there's no need to call `apply`
+    pub fn idle(&self) -> bool {
+        self.pending.is_empty() && self.active.is_empty()
+    }
+
+    fn started(&mut self, active: &Active, payload_n: u64) {
+        info!(
+            "Injecting {:?} starting at payload {}",
+            active.injection, payload_n
+        );
+        if let Some(log) = &mut self.log {
+            let record = json!({ "injection": active.injection, "payload_n": payload_n });
+            if let Err(e) = writeln!(log, "{}", record) {
+                warn!("Failed to log injection: {}", e);
+            }
+        }
+    }
+
+    /// Add any pulses that overlap the averaged spectrum starting at `start` (payload `payload_n`)
+    pub fn apply(&mut self, spectrum: &mut [f32], start: Epoch, payload_n: u64) {
+        let tsamp = self.cc.tsamp() as f64;
+        let end = start + tsamp.seconds();
+        let mut i = 0;
+        while i < self.pending.len() {
+            if self.pending[i].end() < start {
+                let missed = self.pending.swap_remove(i);
+                warn!("Skipping injection {:?} in the past", missed.injection);
+            } else if self.pending[i].start() < end {
+                let active = self.pending.swap_remove(i);
+                self.started(&active, payload_n);
+                self.active.push(active);
+            } else {
+                i += 1;
+            }
+        }
+        for active in &self.active {
+            active.add(spectrum, (start - active.peak).to_seconds(), tsamp);
+        }
+        self.active.retain(|active| active.end() >= end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_parse() {
+        let now = Epoch::from_mjd_utc(59_000.0);
+        let injection =
+            Injection::parse(&["in=86400", "dm=100", "fluence=5", "width=1"], now).unwrap();
+        assert!((injection.mjd - 59_001.0).abs() < 1e-9);
+        assert_eq!(injection.scattering, 0.0);
+        assert!(Injection::parse(&["mjd=59000", "dm=100", "fluence=5"], now).is_err());
+        assert!(Injection::parse(&["mjd=59000", "dm=-1", "fluence=5", "width=1"], now).is_err());
+        assert!(Injection::parse(&["bogus=1"], now).is_err());
+    }
+
+    #[test]
+    fn test_erfcx() {
+        for (x, expected) in [
+            (0.0, 1.0),
+            (0.5, 0.615_690_344),
+            (1.0, 0.427_583_576),
+            (2.0, 0.255_395_676),
+            (5.0, 0.110_704_638),
+            (50.0, 0.011_281_536),
+        ] {
+            assert!((erfcx(x) / expected - 1.0).abs() < 1e-6, "erfcx({})", x);
+        }
+    }
+
+    #[test]
+    fn test_pulse_cdf() {
+        let sigma = 1.0;
+        // Just above where the tail used to overflow against an underflowed CDF
+        for tau in [0.0, 0.01, 0.101, 0.5, 2.0] {
+            let mut last = 0.0;
+            for i in -80..400 {
+                let cdf = pulse_cdf(i as f64 * 0.1, sigma, tau);
+                assert!((last..=1.0 + 1e-6).contains(&cdf), "tau {} t {}", tau, i);
+                last = cdf;
+            }
+            assert!(last > 0.999);
+        }
+        // Small scattering barely delays the pulse
+        let (plain, scattered) = (pulse_cdf(0.5, sigma, 0.0), pulse_cdf(0.5, sigma, 0.101));
+        assert!(scattered < plain && plain - scattered < 0.05);
+        // Both sides of the switch agree
+        let t = sigma * sigma / 0.5;
+        let (before, after) = (
+            pulse_cdf(t - 1e-9, sigma, 0.5),
+            pulse_cdf(t + 1e-9, sigma, 0.5),
+        );
+        assert!((before - after).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_injection() {
+        let cc = CaptureConfig {
+            channels: 64,
+            samples: 1024,
+            ..Config::default().capture_config()
+        };
+        let tsamp = cc.tsamp() as f64;
+        let start = Epoch::from_mjd_utc(59_000.0);
+        let mut injector = Injector::new(&cc, None);
+        for scattering in [0.0, 0.5] {
+            injector.schedule(Injection {
+                mjd: (start + 0.01.seconds()).to_mjd_utc_days(),
+                dm: 50.0,
+                fluence: 2.0,
+                width: 0.2,
+                scattering,
+                spectral_index: 0.0,
+            });
+        }
+        let mut total = vec![0f64; cc.channels];
+        let mut peaks = vec![(0usize, 0f32); cc.channels];
+        for t in 0..4096 {
+            let mut spectrum = vec![0f32; cc.channels];
+            injector.apply(&mut spectrum, start + (t as f64 * tsamp).seconds(), t * 4);
+            for (c, v) in spectrum.into_iter().enumerate() {
+                total[c] += v as f64 * tsamp * 1e3;
+                if v > peaks[c].1 {
+                    peaks[c] = (t as usize, v);
+                }
+            }
+        }
+        assert!(injector.idle());
+        // All of both pulses made it in, everywhere
+        assert!(total.iter().all(|t| (t - 4.0).abs() < 1e-2));
+        // Arriving later at lower frequencies
+        let peak_top = (0.01 / tsamp).round() as usize;
+        assert!(peaks[63].0.abs_diff(peak_top) <= 1);
+        let delay = KDM * 50.0 * (cc.fch1.powi(-2) - (cc.fch1 + 63.0 * 250.0 / 64.0).powi(-2));
+        assert!(
+            peaks[0]
+                .0
+                .abs_diff(peak_top + (delay / tsamp).round() as usize)
+                <= 2
+        );
+    }
+}
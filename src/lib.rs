@@ -1,26 +1,71 @@
 pub mod args;
+pub mod calibration;
 pub mod capture;
 pub mod complex;
+pub mod config;
+pub mod control;
+pub mod convert;
+pub mod dump;
 pub mod exfil;
+pub mod fpga;
+pub mod health;
+pub mod inject;
+pub mod mask;
+pub mod merge;
+pub mod metadata;
 pub mod monitoring;
+pub mod packet;
+pub mod pointing;
+pub mod polcal;
+pub mod preflight;
+pub mod psrfits;
+pub mod reduce;
+pub mod search;
+pub mod timing;
+pub mod vdif;
+pub mod voltage;
+pub mod wait;
+pub mod web;
+
+use capture::PACKET_CHANNELS;
+use reduce::Combine;
+
+/// Dispersion constant in MHz^2 pc^-1 cm^3 s
+pub const KDM: f64 = 4.148808e3;
 
 #[derive(Debug, Copy, Clone)]
 /// Contains all the state for how to shape the data we're capturing
 pub struct CaptureConfig {
-    /// Number of frequency channels
+    /// Number of frequency channels we capture
     pub channels: usize,
     /// Number of samples to exfil
     pub samples: usize,
     /// Samples per average (downsampling)
     pub avgs: usize,
+    /// Adjacent channels combined into each output channel (decimation)
+    pub decimate: usize,
+    /// How channels are combined when decimating
+    pub combine: Combine,
     /// Seconds per packet
     pub cadence: f32,
+    /// Center frequency of the first captured channel in MHz
+    pub fch1: f64,
+    /// Total bandwidth in MHz
+    pub bandwidth: f64,
 }
 
 impl CaptureConfig {
+    /// Number of boards whose sub-bands make up the channels
+    pub fn boards(&self) -> usize {
+        self.channels / PACKET_CHANNELS
+    }
+    /// Number of frequency channels we write out, after decimation
+    pub fn out_channels(&self) -> usize {
+        self.channels / self.decimate
+    }
     /// The size of the output buffer window in samples (not bytes)
     pub fn window_size(&self) -> usize {
-        self.channels * self.samples
+        self.out_channels() * self.samples
     }
     /// The sample time after averaging
     pub fn tsamp(&self) -> f32 {
@@ -30,4 +75,17 @@ impl CaptureConfig {
     pub fn twindow(&self) -> f32 {
         self.tsamp() * self.samples as f32
     }
+    /// Center frequency of the first output channel in MHz
+    pub fn fch1(&self) -> f64 {
+        let native_foff = self.bandwidth / self.channels as f64;
+        self.fch1 + (self.decimate - 1) as f64 * native_foff / 2.0
+    }
+    /// Output channel width in MHz
+    pub fn foff(&self) -> f64 {
+        self.bandwidth / self.out_channels() as f64
+    }
+    /// Center frequency of the band in MHz
+    pub fn fcenter(&self) -> f64 {
+        self.fch1() + (self.bandwidth - self.foff()) / 2.0
+    }
 }
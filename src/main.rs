@@ -1,43 +1,102 @@
 use byte_slurper::{
-    args::{convert_filter, Args},
-    capture::{capture_udp, PAYLOAD_SIZE},
-    exfil::{dada_consumer, filterbank_consumer},
+    args::{convert_filter, Args, Command},
+    calibration,
+    capture::{capture_udp, RejectSampler, Router, PAYLOAD_SIZE},
+    config::{Config, OutputFormat},
+    control::{control_server, Runtime},
+    convert::convert,
+    dump::VoltageDump,
+    exfil::{dada_consumer, filterbank_consumer, psrfits_consumer, Processor},
+    fpga,
+    health::{watchdog, Health, HealthLimits},
+    inject::Injector,
+    metadata::{FpgaInfo, Metadata},
     monitoring::listen_consumer,
-    CaptureConfig,
+    pointing::pointing_watcher,
+    polcal::polcal,
+    preflight::{self, HostInfo},
+    search::search_consumer,
+    timing::TimingMonitor,
+    voltage::VoltageRecorder,
+    wait::{Waiter, Waker},
+    web::{http_server, WebState},
 };
 use casperfpga::transport::{tapcp::Tapcp, Transport};
-use clap::Parser;
-use crossbeam_channel::bounded;
-use hifitime::Epoch;
+use clap::{CommandFactory, ErrorKind, Parser};
+use crossbeam_channel::{bounded, never};
 use rtrb::RingBuffer;
-use tracing::info;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tracing::{error, info, warn};
+
+// How long to wait for the output to wrap up on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 fn main() {
     // Parse args
     let args = Args::parse();
 
-    // Build the cap config from the args
-    let cc = CaptureConfig {
-        channels: args.channels,
-        samples: args.samples,
-        avgs: args.avgs,
-        cadence: args.cadence,
-    };
+    // Load the config file and apply the command line overrides
+    let config = Config::load(&args)
+        .unwrap_or_else(|e| Args::command().error(ErrorKind::InvalidValue, e).exit());
+    if args.print_config {
+        print!("{}", config.to_toml());
+        return;
+    }
 
     // Setup logging
     tracing_subscriber::fmt()
         .with_max_level(convert_filter(args.verbose.log_level_filter()))
         .init();
 
+    // Reprocess packet dumps instead of capturing
+    if let Some(Command::Convert(convert_args)) = &args.command {
+        if let Err(e) = convert(convert_args, &config) {
+            error!("Conversion failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(Command::Polcal(polcal_args)) = &args.command {
+        if let Err(e) = polcal(polcal_args, &config) {
+            error!("Polarization calibration failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Build the cap config and the wait strategy for the consumer
+    let cc = config.capture_config();
+    let ws = config.wait_strategy();
+    // These were all checked in validation
+    let device_name = config.capture.device_name.clone().unwrap();
+    let boards = config.boards();
+    let key = config.dada_key().transpose().unwrap();
+    let mask = config.channel_mask().unwrap();
+    let monitoring = &config.monitoring;
+    let dump_len = config.outputs.dump_len;
+
     // Print some useful information
-    info!("Starting packet capture!\nDownsample factor: {}\nDownsampled sample time: {}us\nChannels: {}\nDADA chunk size: {}\nDADA chunk time: {}s", cc.avgs, cc.tsamp()*1e6, cc.channels, cc.samples, cc.twindow());
+    info!("Starting packet capture!\nDownsample factor: {}\nDownsampled sample time: {}us\nChannels: {}\nOutput channels: {}\nDADA chunk size: {}\nDADA chunk time: {}s", cc.avgs, cc.tsamp()*1e6, cc.channels, cc.out_channels(), cc.samples, cc.twindow());
+
+    // Make sure the host can keep up before we start
+    let findings = preflight::check(
+        &device_name,
+        &HostInfo::read(&device_name),
+        &cc,
+        config.capture.capacity,
+        config.capture.buffer_time,
+    );
+    if !preflight::report(&findings, config.capture.strict_preflight) {
+        error!("Preflight checks failed, fix the above or pass --lenient-preflight");
+        std::process::exit(1);
+    }
 
     // Grab the pcap device that matches this interface
     let device = pcap::Device::list()
         .expect("Error listing devices from Pcap")
         .into_iter()
-        .find(|d| d.name == args.device_name)
-        .unwrap_or_else(|| panic!("Device named {} not found", args.device_name));
+        .find(|d| d.name == device_name)
+        .unwrap_or_else(|| panic!("Device named {} not found", device_name));
 
     // Create the "capture"
     let mut cap = pcap::Capture::from_device(device)
@@ -48,39 +107,201 @@ fn main() {
         .unwrap();
 
     // Add the port filter
-    cap.filter(&format!("dst port {}", args.port), true)
+    let router = Router::new(&boards);
+    cap.filter(&router.filter(), true)
         .expect("Error creating port filter");
+    let sampler = config.capture.reject_file.as_ref().map(|path| {
+        RejectSampler::new(&cap, path, config.capture.reject_samples).unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1);
+        })
+    });
 
     // Create rtrb pairs
-    let (producer, consumer) = RingBuffer::new(args.capacity);
+    let (producer, consumer) = RingBuffer::new(config.capture.capacity);
+    let waker = Arc::new(Waker::default());
+    let waiter = Waiter::new(ws, waker.clone());
+    let wait_stats = waiter.stats();
 
     // Setup the monitoring channel
     let (tcp_s, tcp_r) = bounded(1);
 
-    // Signal the FPGA to start on the next rising PPS edge
-    let mut transport = Tapcp::connect(args.fpga_addr).expect("UDP Connection to the FPGA failed");
-    assert!(
-        transport.is_running().unwrap(),
-        "SNAP board is not programmed/running"
+    // Signal the FPGAs to start on the next rising PPS edge
+    let mut transports: Vec<_> = boards
+        .iter()
+        .map(|board| {
+            Tapcp::connect(board.fpga_addr).unwrap_or_else(|e| {
+                panic!(
+                    "UDP Connection to the FPGA at {} failed: {}",
+                    board.fpga_addr, e
+                )
+            })
+        })
+        .collect();
+    let fpga_info: Vec<_> = boards
+        .iter()
+        .zip(transports.iter_mut())
+        .map(|(board, transport)| {
+            let running = transport.is_running().unwrap();
+            assert!(
+                running,
+                "SNAP board at {} is not programmed/running",
+                board.fpga_addr
+            );
+            FpgaInfo {
+                addr: board.fpga_addr,
+                running,
+            }
+        })
+        .collect();
+    let payload_start = match fpga::arm(&mut transports) {
+        Ok(payload_start) => payload_start,
+        Err(e) => {
+            error!("Couldn't arm the FPGA: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Setup the runtime state we can change from the control channel
+    let runtime = Arc::new(Runtime::new(
+        payload_start,
+        mask,
+        monitoring.integrations,
+        config.pointing.clone(),
+        dump_len > 0,
+    ));
+    if let Err(e) = calibration::load(&config.calibration, cc.channels, &runtime) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    if let Some(path) = config.telescope.pointing_file.clone() {
+        let pointing_runtime = runtime.clone();
+        std::thread::spawn(move || pointing_watcher(path, pointing_runtime));
+    }
+    let dump = (dump_len > 0).then(|| VoltageDump::new(dump_len));
+
+    // Spawn the quick-look search
+    let (search_s, search_r) = bounded(config.search.gulp);
+    let (search_sender, candidates) = if config.search.enabled {
+        let (cand_s, cand_r) = bounded(16);
+        let search = config.search.clone();
+        let search_runtime = runtime.clone();
+        std::thread::spawn(move || search_consumer(search_r, &cc, &search, search_runtime, cand_s));
+        (Some(search_s), cand_r)
+    } else {
+        (None, never())
+    };
+    let voltages = config
+        .voltages
+        .enabled
+        .then(|| VoltageRecorder::new(&cc, &config.voltages, runtime.clone()));
+    let mut injector = Injector::new(&cc, Some(&config.injection.log_file));
+    for pulse in config.injection.pulses.iter().cloned() {
+        injector.schedule(pulse);
+    }
+    let health = Arc::new(Health::new(boards.len()));
+    let processor = Processor::new(
+        &cc,
+        runtime.clone(),
+        health.clone(),
+        tcp_s,
+        search_sender,
+        injector,
+        dump,
+        voltages,
     );
-    // FIXME replace 32 bit word with bool
-    transport.write("master_rst", 0, &1u32).unwrap();
-    transport.write("master_rst", 0, &0u32).unwrap();
-    // FIXME, actually time this
-    let payload_start = Epoch::now().unwrap();
-    transport.write("pps_trig", 0, &1u32).unwrap();
-    transport.write("pps_trig", 0, &0u32).unwrap();
+
+    // Spawn the watchdog
+    let stall = Duration::from_secs_f32(monitoring.stall_timeout);
+    let limits = HealthLimits {
+        packet: stall / 5,
+        stall,
+        commit: stall.max(Duration::from_secs_f32(2.0 * cc.twindow())),
+    };
+    let rearm = monitoring
+        .rearm_after
+        .map(|secs| (transports, Duration::from_secs_f32(secs)));
+    let watchdog_health = health.clone();
+    let watchdog_runtime = runtime.clone();
+    std::thread::spawn(move || watchdog(watchdog_health, limits, watchdog_runtime, rearm));
+
+    // Keep an eye on when the packets arrive
+    let timing = TimingMonitor::new(
+        runtime.clone(),
+        health.clone(),
+        boards.len(),
+        cc.cadence,
+        monitoring.max_clock_offset,
+    );
+
+    // Describe every recording, finishing off the current one when we're shut down
+    let metadata = Arc::new(Metadata::new(
+        config.clone(),
+        fpga_info,
+        runtime.clone(),
+        health.clone(),
+    ));
+    let shutdown_metadata = metadata.clone();
+    let shutdown_runtime = runtime.clone();
+    let (done_s, done_r) = bounded(1);
+    ctrlc::set_handler(move || {
+        info!("Shutting down");
+        // Let the consumer finish what it's writing (and tear down PSRDADA if we made it)
+        shutdown_runtime.request_shutdown();
+        if done_r.recv_timeout(SHUTDOWN_TIMEOUT).is_err() {
+            warn!("Output didn't finish cleanly, is data still coming in?");
+        }
+        shutdown_metadata.stop();
+        std::process::exit(0);
+    })
+    .expect("Error setting the shutdown handler");
 
     // Spawn the exfil thread
-    if let Some(key) = args.key {
-        std::thread::spawn(move || dada_consumer(key, consumer, tcp_s, &cc, payload_start));
+    let exfil_health = health.clone();
+    if let Some(key) = key {
+        std::thread::spawn(move || {
+            dada_consumer(
+                key,
+                consumer,
+                &cc,
+                waiter,
+                processor,
+                exfil_health,
+                metadata,
+            );
+            let _ = done_s.send(());
+        });
+    } else if config.outputs.format == OutputFormat::Psrfits {
+        std::thread::spawn(move || {
+            psrfits_consumer(consumer, &cc, waiter, processor, exfil_health, metadata);
+            let _ = done_s.send(());
+        });
     } else {
-        std::thread::spawn(move || filterbank_consumer(consumer, tcp_s, &cc, payload_start));
+        std::thread::spawn(move || {
+            filterbank_consumer(consumer, &cc, waiter, processor, exfil_health, metadata);
+            let _ = done_s.send(());
+        });
     }
 
+    // Spawn the control thread
+    let control_addr = SocketAddr::new(monitoring.control_addr, monitoring.control_port);
+    let control_runtime = runtime.clone();
+    let control_health = health.clone();
+    std::thread::spawn(move || {
+        control_server(control_addr, control_runtime, wait_stats, control_health)
+    });
+
     // Spawn the monitoring thread
-    std::thread::spawn(move || listen_consumer(tcp_r, args.listen_port, &cc));
+    let listen_addr = SocketAddr::new(monitoring.listen_addr, monitoring.listen_port);
+    let web = monitoring.http_port.map(|port| {
+        let state = Arc::new(WebState::new(monitoring.waterfall_len, health.clone()));
+        let http_state = state.clone();
+        let http_addr = SocketAddr::new(monitoring.listen_addr, port);
+        std::thread::spawn(move || http_server(http_addr, http_state));
+        state
+    });
+    std::thread::spawn(move || listen_consumer(tcp_r, candidates, listen_addr, &cc, web, runtime));
 
     // Startup the main capture thread
-    capture_udp(cap, producer);
+    capture_udp(cap, router, sampler, timing, producer, waker, health);
 }
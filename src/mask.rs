@@ -0,0 +1,129 @@
+//! Frequency channel masking
+
+use std::ops::RangeInclusive;
+
+// Number of channels at either edge of the band we zero because of aliasing
+const EDGE_CHANNELS: usize = 251;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Which channels get zeroed before we write spectra out
+pub struct ChannelMask(Vec<bool>);
+
+impl ChannelMask {
+    /// A mask with nothing masked
+    pub fn new(channels: usize) -> Self {
+        Self(vec![false; channels])
+    }
+
+    /// The default mask, zeroing the aliased edges of every board's sub-band of `band` channels
+    pub fn edges(channels: usize, band: usize) -> Self {
+        let mut mask = Self::new(channels);
+        for sub_band in mask.0.chunks_mut(band) {
+            let edge = EDGE_CHANNELS.min(sub_band.len());
+            let len = sub_band.len();
+            sub_band[..edge].fill(true);
+            sub_band[len - edge..].fill(true);
+        }
+        mask
+    }
+
+    /// Mask (or unmask) an inclusive range of channels
+    pub fn set_range(&mut self, range: RangeInclusive<usize>, masked: bool) -> Result<(), String> {
+        if range.is_empty() || *range.end() >= self.0.len() {
+            return Err(format!(
+                "Channel range {}-{} is out of bounds for {} channels",
+                range.start(),
+                range.end(),
+                self.0.len()
+            ));
+        }
+        self.0[range].fill(masked);
+        Ok(())
+    }
+
+    /// Zero all the masked channels of a spectrum
+    pub fn apply(&self, spectrum: &mut [f32]) {
+        spectrum
+            .iter_mut()
+            .zip(&self.0)
+            .filter(|(_, &m)| m)
+            .for_each(|(v, _)| *v = 0.0);
+    }
+
+    /// Number of masked channels
+    pub fn count(&self) -> usize {
+        self.0.iter().filter(|&&m| m).count()
+    }
+
+    pub fn channels(&self) -> usize {
+        self.0.len()
+    }
+
+    /// The masked channels as inclusive ranges, in the form [`parse_range`] accepts
+    pub fn ranges(&self) -> Vec<String> {
+        let mut ranges = vec![];
+        let mut start = None;
+        for (i, &m) in self.0.iter().chain([&false]).enumerate() {
+            match (m, start) {
+                (true, None) => start = Some(i),
+                (false, Some(s)) if s == i - 1 => {
+                    ranges.push(s.to_string());
+                    start = None;
+                }
+                (false, Some(s)) => {
+                    ranges.push(format!("{}-{}", s, i - 1));
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+        ranges
+    }
+}
+
+/// Parse an inclusive channel range like `10-20` or a single channel like `10`
+pub fn parse_range(s: &str) -> Result<RangeInclusive<usize>, String> {
+    let parse = |v: &str| {
+        v.trim()
+            .parse::<usize>()
+            .map_err(|_| format!("Invalid channel number `{}`", v))
+    };
+    match s.split_once('-') {
+        Some((start, stop)) => Ok(parse(start)?..=parse(stop)?),
+        None => parse(s).map(|c| c..=c),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edges() {
+        let mask = ChannelMask::edges(2048, 2048);
+        let mut spectrum = vec![1f32; 2048];
+        mask.apply(&mut spectrum);
+        assert_eq!(spectrum[250], 0.0);
+        assert_eq!(spectrum[251], 1.0);
+        assert_eq!(spectrum[1796], 1.0);
+        assert_eq!(spectrum[1797], 0.0);
+        assert_eq!(mask.count(), 502);
+        // Every sub-band has its own aliased edges
+        let mask = ChannelMask::edges(4096, 2048);
+        assert_eq!(mask.count(), 1004);
+        assert_eq!(mask.ranges(), ["0-250", "1797-2298", "3845-4095"]);
+    }
+
+    #[test]
+    fn test_ranges() {
+        assert_eq!(parse_range("10-20").unwrap(), 10..=20);
+        assert_eq!(parse_range("7").unwrap(), 7..=7);
+        assert!(parse_range("a-2").is_err());
+        let mut mask = ChannelMask::new(16);
+        assert!(mask.set_range(10..=16, true).is_err());
+        mask.set_range(10..=15, true).unwrap();
+        assert_eq!(mask.count(), 6);
+        mask.set_range(3..=3, true).unwrap();
+        assert_eq!(mask.ranges(), ["3", "10-15"]);
+    }
+}
@@ -0,0 +1,313 @@
+//! Stitching the sub-bands from several boards back together into one wide-band spectrum.
+//!
+//! Every board is armed on the same PPS edge, so the same payload number from each of them
+//! covers the same slice of time. We hold on to partial spectra for a little while to let the
+//! stragglers catch up, then zero whatever sub-bands never showed up.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use tracing::{info, warn};
+
+use crate::{
+    capture::{payload_number, unpack, Packet, PACKET_CHANNELS},
+    complex::ComplexByte,
+    health::Health,
+};
+
+// Payloads to wait on a board before giving up on it for that spectrum (about half a millisecond)
+const REORDER_WINDOW: u64 = 64;
+// Payloads a board can fall behind the others before we stop waiting on it at all (about 0.1s)
+const DROPOUT: u64 = 12288;
+
+/// A spectrum we're still collecting sub-bands for
+struct Pending {
+    pol_a: Vec<ComplexByte>,
+    pol_b: Vec<ComplexByte>,
+    present: Vec<bool>,
+}
+
+pub struct Merger {
+    boards: usize,
+    health: Arc<Health>,
+    pending: BTreeMap<u64, Pending>,
+    // Finished spectra, to reuse the allocations
+    spare: Vec<Pending>,
+    // Most recent payload number from each board, empty until the first packet
+    latest: Vec<u64>,
+    up: Vec<bool>,
+    // Boards whose payload numbers have gone back since we started over
+    restarted: Vec<bool>,
+    // Payload number of the last stitched spectrum
+    emitted: Option<u64>,
+}
+
+impl Merger {
+    pub fn new(boards: usize, health: Arc<Health>) -> Self {
+        Self {
+            boards,
+            health,
+            pending: BTreeMap::new(),
+            spare: vec![],
+            latest: vec![],
+            up: vec![true; boards],
+            restarted: vec![false; boards],
+            emitted: None,
+        }
+    }
+
+    /// Add the next packet from any of the boards. If that finishes off a spectrum, its
+    /// polarizations are written to `pol_a` and `pol_b` and its payload number is returned.
+    pub fn push(
+        &mut self,
+        packet: &Packet,
+        pol_a: &mut [ComplexByte],
+        pol_b: &mut [ComplexByte],
+    ) -> Option<u64> {
+        let board = packet.board;
+        let payload_n = payload_number(&packet.payload);
+        if self.latest.is_empty() {
+            // Everyone should be starting about now
+            self.latest = vec![payload_n; self.boards];
+        }
+        // Further back than a straggler could be means this board was re-armed
+        if payload_n + REORDER_WINDOW < self.latest[board] {
+            self.restarted[board] = true;
+        }
+        self.latest[board] = payload_n;
+        // Once every board that's still sending has been re-armed, start over
+        let rearmed = self
+            .restarted
+            .iter()
+            .zip(&self.up)
+            .all(|(&restarted, &up)| restarted || !up);
+        if rearmed {
+            info!("Payload numbers restarted, starting over");
+            self.reset();
+            return None;
+        }
+        // Its new payloads are no use until the rest have been re-armed too, and they aren't late
+        if self.restarted[board] {
+            return None;
+        }
+        if self.emitted.is_some_and(|emitted| payload_n <= emitted) {
+            self.health.late(board);
+            return None;
+        }
+        self.check_boards();
+        let spare = &mut self.spare;
+        let boards = self.boards;
+        let pending = self.pending.entry(payload_n).or_insert_with(|| {
+            let mut pending = spare.pop().unwrap_or_else(|| Pending {
+                pol_a: vec![ComplexByte::default(); boards * PACKET_CHANNELS],
+                pol_b: vec![ComplexByte::default(); boards * PACKET_CHANNELS],
+                present: vec![false; boards],
+            });
+            pending.pol_a.fill(ComplexByte::default());
+            pending.pol_b.fill(ComplexByte::default());
+            pending.present.fill(false);
+            pending
+        });
+        let band = board * PACKET_CHANNELS..(board + 1) * PACKET_CHANNELS;
+        let mut n = 0;
+        unpack(
+            &packet.payload,
+            &mut pending.pol_a[band.clone()],
+            &mut pending.pol_b[band],
+            &mut n,
+        );
+        pending.present[board] = true;
+        self.pop(pol_a, pol_b)
+    }
+
+    /// Mark boards that have fallen too far behind as down, and ones that caught up as up
+    fn check_boards(&mut self) {
+        // Boards that have been re-armed ahead of the others aren't behind, just waiting
+        let newest = self
+            .latest
+            .iter()
+            .zip(&self.restarted)
+            .filter(|(_, &restarted)| !restarted)
+            .map(|(&latest, _)| latest)
+            .max()
+            .unwrap();
+        for (board, &latest) in self.latest.iter().enumerate() {
+            if self.restarted[board] {
+                continue;
+            }
+            let lag = newest - latest;
+            if self.up[board] && lag > DROPOUT {
+                warn!("Board {} stopped sending, zeroing its sub-band", board);
+                self.up[board] = false;
+            } else if !self.up[board] && lag <= REORDER_WINDOW {
+                info!("Board {} is back", board);
+                self.up[board] = true;
+            } else {
+                continue;
+            }
+            self.health.set_up(board, self.up[board]);
+        }
+    }
+
+    /// Stitch together the oldest spectrum if we've heard from every board that's up,
+    /// or we've waited long enough
+    fn pop(&mut self, pol_a: &mut [ComplexByte], pol_b: &mut [ComplexByte]) -> Option<u64> {
+        let newest = *self.latest.iter().max().unwrap();
+        let (&payload_n, pending) = self.pending.first_key_value()?;
+        let complete = pending
+            .present
+            .iter()
+            .zip(&self.up)
+            .all(|(&present, &up)| present || !up);
+        if !complete && newest - payload_n <= REORDER_WINDOW {
+            return None;
+        }
+        let (_, pending) = self.pending.pop_first().unwrap();
+        for (board, &present) in pending.present.iter().enumerate() {
+            if !present {
+                self.health.filled(board);
+            }
+        }
+        pol_a.copy_from_slice(&pending.pol_a);
+        pol_b.copy_from_slice(&pending.pol_b);
+        self.spare.push(pending);
+        self.emitted = Some(payload_n);
+        Some(payload_n)
+    }
+
+    fn reset(&mut self) {
+        while let Some((_, pending)) = self.pending.pop_first() {
+            self.spare.push(pending);
+        }
+        self.latest.clear();
+        self.restarted.fill(false);
+        self.emitted = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{PAYLOAD_SIZE, TIMESTAMP_SIZE};
+
+    fn packet(board: usize, payload_n: u64) -> Packet {
+        let mut payload = [board as u8 + 1; PAYLOAD_SIZE];
+        payload[..TIMESTAMP_SIZE].copy_from_slice(&payload_n.to_be_bytes());
+        Packet { board, payload }
+    }
+
+    #[test]
+    fn test_merge() {
+        let health = Arc::new(Health::new(2));
+        let mut merger = Merger::new(2, health.clone());
+        let mut pol_a = vec![ComplexByte::default(); 2 * PACKET_CHANNELS];
+        let mut pol_b = pol_a.clone();
+        let mut push =
+            |merger: &mut Merger, board, n| merger.push(&packet(board, n), &mut pol_a, &mut pol_b);
+        // Waits for both halves, in either order
+        assert_eq!(push(&mut merger, 0, 100), None);
+        assert_eq!(push(&mut merger, 1, 101), None);
+        assert_eq!(push(&mut merger, 1, 100), Some(100));
+        assert_eq!(push(&mut merger, 0, 101), Some(101));
+        // Late packets are dropped
+        assert_eq!(push(&mut merger, 0, 99), None);
+        // Board 1 misses one, which gets zero filled once board 0 is far enough ahead
+        for n in 102..102 + REORDER_WINDOW + 1 {
+            assert_eq!(push(&mut merger, 0, n), None);
+            if n != 102 {
+                assert_eq!(push(&mut merger, 1, n), None);
+            }
+        }
+        assert_eq!(push(&mut merger, 0, 103 + REORDER_WINDOW), Some(102));
+        let stats = health.board_stats();
+        assert_eq!(stats[0].late, 1);
+        assert_eq!(stats[1].filled, 1);
+        assert_eq!(stats[0].filled, 0);
+    }
+
+    #[test]
+    fn test_dropout() {
+        let health = Arc::new(Health::new(2));
+        let mut merger = Merger::new(2, health.clone());
+        let mut pol_a = vec![ComplexByte::default(); 2 * PACKET_CHANNELS];
+        let mut pol_b = pol_a.clone();
+        // Board 1 never shows up, so we stop waiting for it
+        let mut last = 0;
+        for n in 0..DROPOUT + 2 {
+            if let Some(n) = merger.push(&packet(0, n), &mut pol_a, &mut pol_b) {
+                last = n;
+            }
+        }
+        assert!(!health.board_stats()[1].up);
+        assert_eq!(health.board_stats()[1].filled, last + 1);
+        // The stitched spectra have board 0's half and zeros for board 1
+        assert_eq!(pol_a[0], ComplexByte::new(1, 1));
+        assert_eq!(pol_b[PACKET_CHANNELS], ComplexByte::default());
+        // And it comes back when it catches up
+        for n in DROPOUT + 2..DROPOUT + 200 {
+            for board in [1, 0] {
+                if let Some(n) = merger.push(&packet(board, n), &mut pol_a, &mut pol_b) {
+                    assert!(n > last);
+                    last = n;
+                }
+            }
+        }
+        assert!(health.board_stats()[1].up);
+        assert_eq!(last, DROPOUT + 199);
+        assert_eq!(pol_a[PACKET_CHANNELS], ComplexByte::new(2, 2));
+        // Re-arming starts everything over
+        assert_eq!(merger.push(&packet(0, 5), &mut pol_a, &mut pol_b), None);
+        assert_eq!(merger.push(&packet(1, 5), &mut pol_a, &mut pol_b), None);
+        assert_eq!(merger.push(&packet(0, 6), &mut pol_a, &mut pol_b), None);
+        assert_eq!(merger.push(&packet(1, 6), &mut pol_a, &mut pol_b), Some(6));
+    }
+
+    #[test]
+    fn test_early_rearm() {
+        let health = Arc::new(Health::new(2));
+        let mut merger = Merger::new(2, health.clone());
+        let mut pol_a = vec![ComplexByte::default(); 2 * PACKET_CHANNELS];
+        let mut pol_b = pol_a.clone();
+        let mut push =
+            |merger: &mut Merger, board, n| merger.push(&packet(board, n), &mut pol_a, &mut pol_b);
+        // Re-armed well before the boards would count as dropped out
+        for n in 0..1000 {
+            push(&mut merger, 0, n);
+            push(&mut merger, 1, n);
+        }
+        assert_eq!(push(&mut merger, 0, 3), None);
+        // A straggler from the other board isn't a re-arm
+        assert_eq!(push(&mut merger, 1, 990), None);
+        assert_eq!(push(&mut merger, 1, 3), None);
+        assert_eq!(push(&mut merger, 0, 4), None);
+        assert_eq!(push(&mut merger, 1, 4), Some(4));
+        assert_eq!(health.board_stats()[1].late, 1);
+    }
+
+    #[test]
+    fn test_staggered_rearm() {
+        let health = Arc::new(Health::new(2));
+        let mut merger = Merger::new(2, health.clone());
+        let mut pol_a = vec![ComplexByte::default(); 2 * PACKET_CHANNELS];
+        let mut pol_b = pol_a.clone();
+        let mut push =
+            |merger: &mut Merger, board, n| merger.push(&packet(board, n), &mut pol_a, &mut pol_b);
+        // Well into an observation, board 0 gets re-armed a while before board 1
+        let start = 1_000_000_000;
+        for n in start..start + 100 {
+            push(&mut merger, 0, n);
+            push(&mut merger, 1, n);
+        }
+        for n in 0..100 {
+            assert_eq!(push(&mut merger, 0, n), None);
+            push(&mut merger, 1, start + 100 + n);
+        }
+        // Board 0 is waiting for the others rather than down or late
+        let stats = health.board_stats();
+        assert!(stats[0].up);
+        assert_eq!(stats[0].late, 0);
+        assert_eq!(push(&mut merger, 1, 0), None);
+        assert_eq!(push(&mut merger, 0, 100), None);
+        assert_eq!(push(&mut merger, 1, 100), Some(100));
+        assert!(health.board_stats()[0].up);
+    }
+}
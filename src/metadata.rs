@@ -0,0 +1,281 @@
+//! JSON metadata sidecars written next to every recording, so the data products describe
+//! themselves. A sidecar is written when a recording starts and rewritten with the stop time and
+//! packet statistics when it ends (including on shutdown). It's also rewritten whenever the
+//! calibration changes partway through, with the time and the new tables appended.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use hifitime::Epoch;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    calibration::CalibrationInfo,
+    config::Config,
+    control::Runtime,
+    exfil::payload_epoch,
+    health::{Health, PacketStats},
+    pointing::Pointing,
+    timing::TimingStats,
+    CaptureConfig,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Software {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub git_hash: &'static str,
+}
+
+impl Default for Software {
+    fn default() -> Self {
+        Self {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: env!("GIT_HASH"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FpgaInfo {
+    pub addr: SocketAddr,
+    /// Whether the gateware was programmed and running when we started
+    pub running: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Time {
+    pub utc: String,
+    pub mjd: f64,
+}
+
+impl From<Epoch> for Time {
+    fn from(epoch: Epoch) -> Self {
+        Self {
+            utc: epoch.to_string(),
+            mjd: epoch.to_mjd_utc_days(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CalibrationChange {
+    /// Of the first spectrum calibrated this way
+    pub time: Time,
+    pub calibration: CalibrationInfo,
+}
+
+#[derive(Debug, Serialize)]
+struct Sidecar<'a> {
+    software: Software,
+    /// The data file (or PSRDADA key) this describes
+    data: String,
+    start: Time,
+    /// Not set until the recording ends
+    stop: Option<Time>,
+    /// Epoch of payload zero
+    reference_epoch: Time,
+    /// One per board, in sub-band order, and empty for offline conversions
+    fpga: &'a [FpgaInfo],
+    /// At the start of the recording
+    pointing: &'a Pointing,
+    /// Over the course of this recording
+    packets: PacketStats,
+    /// When the sidecar was written, and all zero for offline conversions
+    timing: TimingStats,
+    masked_channels: Vec<String>,
+    /// At the start of the recording
+    calibration: &'a CalibrationInfo,
+    /// Every change of calibration since, in order
+    calibration_changes: &'a [CalibrationChange],
+    config: &'a Config,
+}
+
+struct Recording {
+    data: String,
+    path: PathBuf,
+    start: Epoch,
+    pointing: Pointing,
+    calibration: CalibrationInfo,
+    calibration_version: u64,
+    calibration_changes: Vec<CalibrationChange>,
+    stats: PacketStats,
+}
+
+/// Everything we know about the observation, shared between the consumers and the shutdown handler
+pub struct Metadata {
+    config: Config,
+    cc: CaptureConfig,
+    fpga: Vec<FpgaInfo>,
+    runtime: Arc<Runtime>,
+    health: Arc<Health>,
+    current: Mutex<Option<Recording>>,
+}
+
+/// The sidecar file for a given data file
+pub fn sidecar_path(data: &Path) -> PathBuf {
+    data.with_extension("json")
+}
+
+impl Metadata {
+    pub fn new(
+        config: Config,
+        fpga: Vec<FpgaInfo>,
+        runtime: Arc<Runtime>,
+        health: Arc<Health>,
+    ) -> Self {
+        Self {
+            cc: config.capture_config(),
+            config,
+            fpga,
+            runtime,
+            health,
+            current: Mutex::new(None),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Start describing a new recording (finishing the last one), with the sidecar at `path`
+    pub fn start(&self, data: &str, path: PathBuf, start: Epoch) {
+        let mut current = self.current.lock().unwrap();
+        if let Some(rec) = current.take() {
+            self.write(&rec, true);
+        }
+        let mut calibration_version = 0;
+        let calibration = self
+            .runtime
+            .calibration_update(&mut calibration_version)
+            .unwrap_or_default();
+        let rec = Recording {
+            data: data.to_owned(),
+            path,
+            start,
+            pointing: self.runtime.pointing(),
+            calibration: calibration.info(),
+            calibration_version,
+            calibration_changes: vec![],
+            stats: self.health.packet_stats(),
+        };
+        self.write(&rec, false);
+        *current = Some(rec);
+    }
+
+    /// Note a change of calibration in the current recording's sidecar, if there's been one since
+    /// we last checked
+    pub fn check_calibration(&self) {
+        let mut current = self.current.lock().unwrap();
+        if let Some(rec) = current.as_mut() {
+            if let Some(calibration) = self
+                .runtime
+                .calibration_update(&mut rec.calibration_version)
+            {
+                info!("Calibration changed during {}", rec.data);
+                rec.calibration_changes.push(CalibrationChange {
+                    time: self.latest().into(),
+                    calibration: calibration.info(),
+                });
+                self.write(rec, false);
+            }
+        }
+    }
+
+    // The epoch of the last payload we processed
+    fn latest(&self) -> Epoch {
+        payload_epoch(
+            self.runtime.payload_start(),
+            self.runtime.payload_n(),
+            &self.cc,
+        )
+    }
+
+    /// Finish the current recording, if there is one
+    pub fn stop(&self) {
+        if let Some(rec) = self.current.lock().unwrap().take() {
+            self.write(&rec, true);
+            info!("Finished metadata for {}", rec.data);
+        }
+    }
+
+    fn write(&self, rec: &Recording, finished: bool) {
+        // The end of the recording is the last payload we processed
+        let stop = finished.then(|| self.latest().into());
+        let sidecar = Sidecar {
+            software: Software::default(),
+            data: rec.data.clone(),
+            start: rec.start.into(),
+            stop,
+            reference_epoch: self.runtime.payload_start().into(),
+            fpga: &self.fpga,
+            pointing: &rec.pointing,
+            packets: self.health.packet_stats() - rec.stats,
+            timing: self.health.timing(),
+            masked_channels: self.runtime.mask().ranges(),
+            calibration: &rec.calibration,
+            calibration_changes: &rec.calibration_changes,
+            config: &self.config,
+        };
+        let json = serde_json::to_string_pretty(&sidecar).unwrap();
+        // Losing the metadata shouldn't take the data down with it
+        if let Err(e) = std::fs::write(&rec.path, json) {
+            warn!("Couldn't write metadata to {}: {}", rec.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        calibration::{CalInfo, CalTable},
+        complex::Complex,
+    };
+
+    #[test]
+    fn test_calibration_change() {
+        let config = Config::default();
+        let cc = config.capture_config();
+        let runtime = Arc::new(Runtime::new(
+            Epoch::from_unix_seconds(1_690_000_000.0),
+            config.channel_mask().unwrap(),
+            1,
+            Pointing::default(),
+            false,
+        ));
+        let metadata = Metadata::new(config, vec![], runtime.clone(), Arc::new(Health::default()));
+        let path = std::env::temp_dir().join(format!("sidecar-test-{}.json", std::process::id()));
+        metadata.start("test.fil", path.clone(), runtime.payload_start());
+        // Nothing changed yet
+        metadata.check_calibration();
+        runtime.set_payload_n(1000);
+        let gains = [
+            vec![Complex::new(1.0, 0.0); cc.channels],
+            vec![Complex::new(1.0, 0.0); cc.channels],
+        ];
+        let info = CalInfo {
+            source: "gains.txt".to_owned(),
+            checksum: String::new(),
+        };
+        runtime.set_gains(Some(CalTable::new(gains, info)));
+        metadata.check_calibration();
+        let sidecar: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(sidecar["calibration"]["gains"].is_null());
+        let changes = sidecar["calibration_changes"].as_array().unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(
+            changes[0]["calibration"]["gains"]["source"],
+            serde_json::json!("gains.txt")
+        );
+        let expected = payload_epoch(runtime.payload_start(), 1000, &cc).to_mjd_utc_days();
+        assert_eq!(changes[0]["time"]["mjd"].as_f64(), Some(expected));
+    }
+}
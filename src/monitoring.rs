@@ -2,46 +2,290 @@
 //! This includes getting drop data from libpcap as well as various runtime stats.
 //! Additionally, we'll hold on to a chunk of average spectra so it can be queried
 //! from some TCP listener.
+//!
+//! Every integration is sent to all connected clients as a little-endian frame of
+//! [`FrameHeader`] followed by `nchan` `f32` values. Single pulse search candidates are sent
+//! on the same socket as frames with [`FLAG_CANDIDATE`] set, see [`candidate_frame`].
 
-use crate::CaptureConfig;
-use byte_slice_cast::AsByteSlice;
-use crossbeam_channel::Receiver;
-use std::{io::Write, net::TcpListener};
-use tracing::info;
+use crate::{
+    control::Runtime, exfil::payload_epoch, search::Candidate, web::WebState, CaptureConfig,
+};
+use crossbeam_channel::{never, select, Receiver};
+use serde::Serialize;
+use std::{
+    io::Write,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{info, warn};
 
-// At incoming samples at 8us, if we're averaging over there by 4, this is about 62.5ms
-const TCP_CLIENT_AVG: usize = 2048;
+/// Magic bytes at the start of every monitoring frame
+pub const MAGIC: [u8; 4] = *b"GRXM";
+/// Version of the monitoring frame layout. Version 2 added candidate frames.
+pub const PROTOCOL_VERSION: u16 = 2;
+/// Size of the encoded frame header in bytes
+pub const HEADER_SIZE: usize = 48;
+/// Set if payloads were lost before they made it into the spectra of this integration
+pub const FLAG_GAP: u16 = 1 << 0;
+/// Set if this frame is a search candidate instead of a spectrum
+pub const FLAG_CANDIDATE: u16 = 1 << 1;
 
-pub fn listen_consumer(rx: Receiver<Vec<f32>>, port: u16, cc: &CaptureConfig) {
-    let mut avg = vec![0f32; cc.channels];
-    // Setup listeners
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).unwrap();
+// Clients that can't keep up get dropped instead of stalling the rest
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// An averaged spectrum from the exfil thread
+#[derive(Debug, Clone)]
+pub struct Spectrum {
+    /// Payload number of the last packet in this average
+    pub payload_n: u64,
+    /// Payloads that never arrived in the span of this average
+    pub missing: u64,
+    pub data: Vec<f32>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+/// The header that precedes every frame sent to monitoring clients, encoded little-endian as
+///
+/// | Bytes | Field          | Type    |
+/// |-------|----------------|---------|
+/// | 0..4  | [`MAGIC`]      | `[u8]`  |
+/// | 4..6  | `version`      | `u16`   |
+/// | 6..8  | `flags`        | `u16`   |
+/// | 8..16 | `payload_n`    | `u64`   |
+/// | 16..24| `mjd`          | `f64`   |
+/// | 24..28| `integrations` | `u32`   |
+/// | 28..32| `nchan`        | `u32`   |
+/// | 32..40| `fch1`         | `f64`   |
+/// | 40..48| `foff`         | `f64`   |
+///
+/// followed by `nchan` `f32` values. If [`FLAG_CANDIDATE`] is set the frame is a search
+/// candidate instead: `integrations` is the boxcar width in samples, `nchan` is 3, `fch1` and
+/// `foff` are NaN, and the values are the DM, the S/N and the number of trials above threshold.
+pub struct FrameHeader {
+    pub version: u16,
+    pub flags: u16,
+    /// Payload number of the first packet in this integration
+    pub payload_n: u64,
+    /// MJD (UTC) of the start of this integration
+    pub mjd: f64,
+    /// Number of averaged spectra summed into this integration. Spectra are dropped on the way
+    /// here when we can't keep up, so this can span more time than `integrations` spectra.
+    pub integrations: u32,
+    pub nchan: u32,
+    /// Center frequency of the first channel in MHz
+    pub fch1: f64,
+    /// Channel width in MHz
+    pub foff: f64,
+}
+
+impl FrameHeader {
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0u8; HEADER_SIZE];
+        buf[0..4].copy_from_slice(&MAGIC);
+        buf[4..6].copy_from_slice(&self.version.to_le_bytes());
+        buf[6..8].copy_from_slice(&self.flags.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.payload_n.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.mjd.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.integrations.to_le_bytes());
+        buf[28..32].copy_from_slice(&self.nchan.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.fch1.to_le_bytes());
+        buf[40..48].copy_from_slice(&self.foff.to_le_bytes());
+        buf
+    }
+
+    /// Decode a header, returning None if the magic doesn't match
+    pub fn decode(buf: &[u8; HEADER_SIZE]) -> Option<Self> {
+        if buf[0..4] != MAGIC {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes(buf[i..i + 2].try_into().unwrap());
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(buf[i..i + 8].try_into().unwrap());
+        Some(Self {
+            version: u16_at(4),
+            flags: u16_at(6),
+            payload_n: u64_at(8),
+            mjd: f64::from_bits(u64_at(16)),
+            integrations: u32_at(24),
+            nchan: u32_at(28),
+            fch1: f64::from_bits(u64_at(32)),
+            foff: f64::from_bits(u64_at(40)),
+        })
+    }
+}
+
+/// Encode a full frame (header + little-endian spectrum)
+pub fn encode_frame(header: &FrameHeader, data: &[f32]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_SIZE + data.len() * 4);
+    frame.extend_from_slice(&header.encode());
+    data.iter()
+        .for_each(|v| frame.extend_from_slice(&v.to_le_bytes()));
+    frame
+}
+
+/// Encode a search candidate as a frame. The header's `payload_n` and `mjd` are the start of
+/// the pulse, see [`FrameHeader`] for the rest of the layout.
+pub fn candidate_frame(candidate: &Candidate) -> Vec<u8> {
+    let header = FrameHeader {
+        version: PROTOCOL_VERSION,
+        flags: FLAG_CANDIDATE,
+        payload_n: candidate.payload_n,
+        mjd: candidate.mjd,
+        integrations: candidate.width as u32,
+        nchan: 3,
+        fch1: f64::NAN,
+        foff: f64::NAN,
+    };
+    encode_frame(
+        &header,
+        &[candidate.dm, candidate.snr, candidate.members as f32],
+    )
+}
+
+// Send to everyone, dropping the clients that went away
+fn broadcast(clients: &Mutex<Vec<TcpStream>>, frame: &[u8]) {
+    clients.lock().unwrap().retain_mut(|socket| {
+        let ok = socket.write_all(frame).is_ok();
+        if !ok {
+            info!("Monitoring client {:?} disconnected", socket.peer_addr());
+        }
+        ok
+    });
+}
+
+fn accept_clients(listener: TcpListener, clients: Arc<Mutex<Vec<TcpStream>>>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Failed to accept monitoring client: {}", e);
+                continue;
+            }
+        };
+        info!("New monitoring client {:?}", stream.peer_addr());
+        let _ = stream.set_nodelay(true);
+        let _ = stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT));
+        clients.lock().unwrap().push(stream);
+    }
+}
+
+pub fn listen_consumer(
+    rx: Receiver<Spectrum>,
+    mut candidates: Receiver<Candidate>,
+    addr: SocketAddr,
+    cc: &CaptureConfig,
+    web: Option<Arc<WebState>>,
+    runtime: Arc<Runtime>,
+) {
+    let mut avg = vec![0f32; cc.out_channels()];
     let mut avg_cnt = 0usize;
+    let mut integrations = runtime.monitor_avgs();
+    let mut flags = 0u16;
+    let mut first_payload_n = 0u64;
+    // Setup listeners
+    let listener = TcpListener::bind(addr).unwrap();
+    info!("Monitoring listening on {}", addr);
+    let clients = Arc::new(Mutex::new(vec![]));
+    let accept_clients_handle = clients.clone();
+    std::thread::spawn(move || accept_clients(listener, accept_clients_handle));
     loop {
-        info!("Listen thread waiting for new client");
-        // Wait for new connections
-        let mut socket = if let Ok((sock, _)) = listener.accept() {
-            sock
-        } else {
-            continue;
+        // Grab next stokes sample and add to avg, passing along any candidates in between
+        let spectrum = select! {
+            recv(rx) -> spectrum => spectrum.unwrap(),
+            recv(candidates) -> candidate => {
+                match candidate {
+                    Ok(candidate) => broadcast(&clients, &candidate_frame(&candidate)),
+                    // The search has stopped, so stop asking it
+                    Err(_) => candidates = never(),
+                }
+                continue;
+            }
         };
-        info!("New listen client - starting monitoring");
-        loop {
-            // Grab next stokes sample and add to avg
-            rx.recv()
-                .unwrap()
-                .into_iter()
-                .enumerate()
-                .for_each(|(i, v)| avg[i] += v as f32 / TCP_CLIENT_AVG as f32);
-            avg_cnt += 1;
-            if avg_cnt == TCP_CLIENT_AVG {
-                avg_cnt = 0;
-                match socket.write_all(avg.as_byte_slice()) {
-                    Ok(_) => (),
-                    Err(_) => break,
-                };
-                avg.fill(0.0);
+        // Spectra we dropped ourselves aren't a gap in the data, just in what we've summed
+        if spectrum.missing > 0 {
+            flags |= FLAG_GAP;
+        }
+        if avg_cnt == 0 {
+            // Integration length can be changed from the control channel
+            integrations = runtime.monitor_avgs();
+            first_payload_n = (spectrum.payload_n + 1).saturating_sub(cc.avgs as u64);
+        }
+        spectrum
+            .data
+            .into_iter()
+            .enumerate()
+            .for_each(|(i, v)| avg[i] += v / integrations as f32);
+        avg_cnt += 1;
+        if avg_cnt == integrations {
+            avg_cnt = 0;
+            let header = FrameHeader {
+                version: PROTOCOL_VERSION,
+                flags,
+                payload_n: first_payload_n,
+                mjd: payload_epoch(runtime.payload_start(), first_payload_n, cc).to_mjd_utc_days(),
+                integrations: integrations as u32,
+                nchan: cc.out_channels() as u32,
+                fch1: cc.fch1(),
+                foff: cc.foff(),
+            };
+            if let Some(web) = &web {
+                web.publish(&header, &avg);
             }
+            broadcast(&clients, &encode_frame(&header, &avg));
+            avg.fill(0.0);
+            flags = 0;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let header = FrameHeader {
+            version: PROTOCOL_VERSION,
+            flags: FLAG_GAP,
+            payload_n: 1234,
+            mjd: 59_000.5,
+            integrations: 2048,
+            nchan: 2,
+            fch1: 1280.06103516,
+            foff: 0.1220703125,
+        };
+        let frame = encode_frame(&header, &[1.0, 2.0]);
+        assert_eq!(frame.len(), HEADER_SIZE + 8);
+        let decoded = FrameHeader::decode(frame[..HEADER_SIZE].try_into().unwrap()).unwrap();
+        assert_eq!(header, decoded);
+        assert_eq!(&frame[HEADER_SIZE..HEADER_SIZE + 4], &1f32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_candidate_roundtrip() {
+        let candidate = Candidate {
+            mjd: 59_000.25,
+            payload_n: 5678,
+            dm: 56.7,
+            width: 4,
+            snr: 12.5,
+            members: 9,
+        };
+        let frame = candidate_frame(&candidate);
+        assert_eq!(frame.len(), HEADER_SIZE + 12);
+        let header = FrameHeader::decode(frame[..HEADER_SIZE].try_into().unwrap()).unwrap();
+        assert_eq!(header.version, PROTOCOL_VERSION);
+        assert_eq!(header.flags, FLAG_CANDIDATE);
+        assert_eq!(header.payload_n, 5678);
+        assert_eq!(header.mjd, 59_000.25);
+        assert_eq!(header.integrations, 4);
+        assert_eq!(header.nchan, 3);
+        assert!(header.fch1.is_nan() && header.foff.is_nan());
+        let values: Vec<_> = frame[HEADER_SIZE..]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(values, [56.7, 12.5, 9.0]);
+    }
+}
@@ -0,0 +1,275 @@
+//! Parsing the Ethernet, IP and UDP headers around each payload
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const ETHERNET_HEADER_SIZE: usize = 14;
+const IPV4_MIN_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const UDP_HEADER_SIZE: usize = 8;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+// 802.1Q, and the outer tag of 802.1ad (QinQ)
+const ETHERTYPE_VLAN: [u16; 2] = [0x8100, 0x88a8];
+
+const PROTOCOL_UDP: u8 = 17;
+// IPv6 extension headers we can skip over on the way to the UDP header
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_DESTINATION: u8 = 60;
+
+/// Why a packet was thrown away
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Reject {
+    /// Shorter than its headers say it should be
+    Truncated,
+    /// Neither IPv4 nor IPv6
+    NotIp,
+    /// An IP fragment, which we never reassemble
+    Fragment,
+    /// Not UDP
+    NotUdp,
+    /// A UDP payload that isn't the size of one of ours
+    Size,
+    /// Not from (or to) any of the boards we're capturing
+    Unknown,
+    /// Cut short by the capture's snap length
+    Snapped,
+    /// A payload number nowhere near the last one from the same board
+    Sequence,
+}
+
+impl Reject {
+    /// Number of reasons, for keeping a count of each
+    pub const COUNT: usize = Reject::Sequence as usize + 1;
+}
+
+/// The parts of the headers we care about, and the UDP payload they wrap
+#[derive(Debug, PartialEq, Eq)]
+pub struct Udp<'a> {
+    pub source: IpAddr,
+    pub source_port: u16,
+    pub dest_port: u16,
+    pub payload: &'a [u8],
+}
+
+fn be16(data: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([data[at], data[at + 1]])
+}
+
+/// Find the UDP payload in an Ethernet frame, going by the lengths in the headers
+pub fn parse(frame: &[u8]) -> Result<Udp<'_>, Reject> {
+    if frame.len() < ETHERNET_HEADER_SIZE {
+        return Err(Reject::Truncated);
+    }
+    // Skip over any VLAN tags
+    let mut offset = 12;
+    let mut ethertype = be16(frame, offset);
+    while ETHERTYPE_VLAN.contains(&ethertype) {
+        offset += 4;
+        if frame.len() < offset + 2 {
+            return Err(Reject::Truncated);
+        }
+        ethertype = be16(frame, offset);
+    }
+    let ip = &frame[offset + 2..];
+    let (source, udp) = match ethertype {
+        ETHERTYPE_IPV4 => ipv4(ip)?,
+        ETHERTYPE_IPV6 => ipv6(ip)?,
+        _ => return Err(Reject::NotIp),
+    };
+    if udp.len() < UDP_HEADER_SIZE {
+        return Err(Reject::Truncated);
+    }
+    // The frame can be padded past the end of the datagram
+    let length = be16(udp, 4) as usize;
+    if length < UDP_HEADER_SIZE || length > udp.len() {
+        return Err(Reject::Truncated);
+    }
+    Ok(Udp {
+        source,
+        source_port: be16(udp, 0),
+        dest_port: be16(udp, 2),
+        payload: &udp[UDP_HEADER_SIZE..length],
+    })
+}
+
+/// Like [`parse`], for a packet straight from libpcap
+pub fn parse_captured<'a>(packet: &pcap::Packet<'a>) -> Result<Udp<'a>, Reject> {
+    if packet.header.caplen < packet.header.len {
+        return Err(Reject::Snapped);
+    }
+    parse(packet.data)
+}
+
+/// The source address and the IP payload
+fn ipv4(ip: &[u8]) -> Result<(IpAddr, &[u8]), Reject> {
+    if ip.len() < IPV4_MIN_HEADER_SIZE {
+        return Err(Reject::Truncated);
+    }
+    if ip[0] >> 4 != 4 {
+        return Err(Reject::NotIp);
+    }
+    // The header length includes any options
+    let header = (ip[0] & 0x0f) as usize * 4;
+    let total = be16(ip, 2) as usize;
+    if header < IPV4_MIN_HEADER_SIZE || total < header || total > ip.len() {
+        return Err(Reject::Truncated);
+    }
+    // More fragments, or a fragment offset
+    if be16(ip, 6) & 0x3fff != 0 {
+        return Err(Reject::Fragment);
+    }
+    if ip[9] != PROTOCOL_UDP {
+        return Err(Reject::NotUdp);
+    }
+    let source = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+    Ok((IpAddr::V4(source), &ip[header..total]))
+}
+
+/// The source address and the IP payload
+fn ipv6(ip: &[u8]) -> Result<(IpAddr, &[u8]), Reject> {
+    if ip.len() < IPV6_HEADER_SIZE {
+        return Err(Reject::Truncated);
+    }
+    if ip[0] >> 4 != 6 {
+        return Err(Reject::NotIp);
+    }
+    let end = IPV6_HEADER_SIZE + be16(ip, 4) as usize;
+    if end > ip.len() {
+        return Err(Reject::Truncated);
+    }
+    let source: [u8; 16] = ip[8..24].try_into().unwrap();
+    let mut next = ip[6];
+    let mut offset = IPV6_HEADER_SIZE;
+    loop {
+        match next {
+            PROTOCOL_UDP => break,
+            IPV6_FRAGMENT => return Err(Reject::Fragment),
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DESTINATION => {
+                if end < offset + 2 {
+                    return Err(Reject::Truncated);
+                }
+                next = ip[offset];
+                offset += (ip[offset + 1] as usize + 1) * 8;
+                if offset > end {
+                    return Err(Reject::Truncated);
+                }
+            }
+            _ => return Err(Reject::NotUdp),
+        }
+    }
+    Ok((IpAddr::V6(Ipv6Addr::from(source)), &ip[offset..end]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn udp(payload: &[u8]) -> Vec<u8> {
+        let mut udp = vec![0xea, 0x60, 0xea, 0x61];
+        udp.extend(((payload.len() + UDP_HEADER_SIZE) as u16).to_be_bytes());
+        udp.extend([0, 0]);
+        udp.extend(payload);
+        udp
+    }
+
+    fn ipv4_frame(vlan: bool, options: usize, flags: u16, udp: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; 12];
+        if vlan {
+            frame.extend([0x81, 0x00, 0x00, 0x2a]);
+        }
+        frame.extend(ETHERTYPE_IPV4.to_be_bytes());
+        let header = IPV4_MIN_HEADER_SIZE + options;
+        frame.push(0x40 | (header / 4) as u8);
+        frame.push(0);
+        frame.extend(((header + udp.len()) as u16).to_be_bytes());
+        frame.extend([0, 0]);
+        frame.extend(flags.to_be_bytes());
+        frame.extend([64, PROTOCOL_UDP, 0, 0, 192, 168, 1, 10, 192, 168, 1, 1]);
+        frame.extend(vec![1u8; options]);
+        frame.extend(udp);
+        frame
+    }
+
+    fn ipv6_frame(extension: Option<u8>, udp: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; 12];
+        frame.extend(ETHERTYPE_IPV6.to_be_bytes());
+        let extension_size = if extension.is_some() { 8 } else { 0 };
+        frame.extend([0x60, 0, 0, 0]);
+        frame.extend(((extension_size + udp.len()) as u16).to_be_bytes());
+        frame.extend([extension.unwrap_or(PROTOCOL_UDP), 64]);
+        frame.extend(Ipv6Addr::LOCALHOST.octets());
+        frame.extend(Ipv6Addr::LOCALHOST.octets());
+        if extension.is_some() {
+            frame.extend([PROTOCOL_UDP, 0, 0, 0, 0, 0, 0, 0]);
+        }
+        frame.extend(udp);
+        frame
+    }
+
+    #[test]
+    fn test_parse() {
+        let payload = [7u8; 16];
+        let udp = udp(&payload);
+        let frame = ipv4_frame(false, 0, 0, &udp);
+        assert_eq!(
+            parse(&frame).unwrap(),
+            Udp {
+                source: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)),
+                source_port: 60000,
+                dest_port: 60001,
+                payload: &payload,
+            }
+        );
+        // VLAN tags, IP options and Ethernet padding don't get in the way
+        let mut frame = ipv4_frame(true, 8, 0x4000, &udp);
+        frame.extend([0u8; 20]);
+        assert_eq!(parse(&frame).unwrap().payload, payload);
+        let frame = ipv6_frame(None, &udp);
+        let parsed = parse(&frame).unwrap();
+        assert_eq!(parsed.source, IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert_eq!(parsed.payload, payload);
+        let frame = ipv6_frame(Some(IPV6_DESTINATION), &udp);
+        assert_eq!(parse(&frame).unwrap().payload, payload);
+        // And everything we can't use
+        assert_eq!(
+            parse(&ipv4_frame(false, 0, 0x2000, &udp)),
+            Err(Reject::Fragment)
+        );
+        assert_eq!(
+            parse(&ipv6_frame(Some(IPV6_FRAGMENT), &udp)),
+            Err(Reject::Fragment)
+        );
+        let frame = ipv4_frame(false, 0, 0, &udp);
+        assert_eq!(parse(&frame[..frame.len() - 1]), Err(Reject::Truncated));
+        let mut not_udp = frame.clone();
+        not_udp[23] = 6;
+        assert_eq!(parse(&not_udp), Err(Reject::NotUdp));
+        let mut not_ip = frame;
+        not_ip[12..14].copy_from_slice(&0x0806u16.to_be_bytes());
+        assert_eq!(parse(&not_ip), Err(Reject::NotIp));
+        assert_eq!(parse(&[0u8; 10]), Err(Reject::Truncated));
+    }
+
+    #[test]
+    fn test_parse_captured() {
+        let frame = ipv4_frame(false, 0, 0, &udp(&[7u8; 16]));
+        let mut header = pcap::PacketHeader {
+            ts: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            caplen: frame.len() as u32,
+            len: frame.len() as u32,
+        };
+        let parsed = parse_captured(&pcap::Packet::new(&header, &frame));
+        assert_eq!(parsed.unwrap().payload, [7u8; 16]);
+        // Cut short by the snap length, even if what's left would parse
+        header.len += 100;
+        let snapped = parse_captured(&pcap::Packet::new(&header, &frame));
+        assert_eq!(snapped, Err(Reject::Snapped));
+        assert_eq!(Reject::COUNT, 8);
+    }
+}
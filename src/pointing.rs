@@ -0,0 +1,186 @@
+//! Where the telescope is looking, for the output headers.
+//!
+//! The pointing starts out as whatever is in the config and can be updated while running, either
+//! from the control channel or by the telescope control system rewriting a pointing file.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::control::Runtime;
+
+// How often we check the pointing file for changes
+const POLL_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// Anything not set is left out of the headers
+pub struct Pointing {
+    pub source_name: Option<String>,
+    /// Right ascension (J2000) in degrees
+    pub ra: Option<f64>,
+    /// Declination (J2000) in degrees
+    pub dec: Option<f64>,
+    /// Azimuth in degrees
+    pub az: Option<f64>,
+    /// Zenith angle in degrees
+    pub za: Option<f64>,
+}
+
+fn check_range(name: &str, v: Option<f64>, min: f64, max: f64) -> Result<(), String> {
+    match v {
+        Some(v) if !(min..=max).contains(&v) => Err(format!(
+            "{} of {} is outside of {} to {} degrees",
+            name, v, min, max
+        )),
+        _ => Ok(()),
+    }
+}
+
+impl Pointing {
+    pub fn validate(&self) -> Result<(), String> {
+        check_range("RA", self.ra, 0.0, 360.0)?;
+        if self.ra == Some(360.0) {
+            return Err("RA must be below 360 degrees".to_owned());
+        }
+        check_range("Dec", self.dec, -90.0, 90.0)?;
+        check_range("Azimuth", self.az, 0.0, 360.0)?;
+        check_range("Zenith angle", self.za, 0.0, 180.0)
+    }
+
+    /// Update fields from `key=value` pairs (source, ra, dec, az, za)
+    pub fn update(&mut self, pairs: &[&str]) -> Result<(), String> {
+        let mut new = self.clone();
+        for pair in pairs {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got `{}`", pair))?;
+            let parse = || {
+                value
+                    .parse::<f64>()
+                    .map_err(|_| format!("Invalid angle `{}`", value))
+            };
+            match key {
+                "source" => new.source_name = Some(value.to_owned()),
+                "ra" => new.ra = Some(parse()?),
+                "dec" => new.dec = Some(parse()?),
+                "az" => new.az = Some(parse()?),
+                "za" => new.za = Some(parse()?),
+                _ => return Err(format!("Unknown pointing field `{}`", key)),
+            }
+        }
+        new.validate()?;
+        *self = new;
+        Ok(())
+    }
+}
+
+// Split degrees into (sign, whole, minutes, seconds), with the seconds rounded to `decimals`
+// places. Rounding first carries into the minutes and degrees, so we never get 60 seconds.
+fn sexagesimal(v: f64, decimals: i32) -> (f64, f64, f64, f64) {
+    let sign = if v < 0.0 { -1.0 } else { 1.0 };
+    let scale = 10f64.powi(decimals);
+    let total = (v.abs() * 3600.0 * scale).round() / scale;
+    let whole = (total / 3600.0).floor();
+    let minutes = ((total - whole * 3600.0) / 60.0).floor();
+    let seconds = total - whole * 3600.0 - minutes * 60.0;
+    (sign, whole, minutes, seconds)
+}
+
+/// RA in degrees as sigproc's packed hhmmss.s
+pub fn sigproc_ra(ra: f64) -> f64 {
+    let (_, h, m, s) = sexagesimal(ra / 15.0, 6);
+    // Just below 24h can round up to it
+    let h = h % 24.0;
+    h * 1e4 + m * 1e2 + s
+}
+
+/// Dec in degrees as sigproc's packed ddmmss.s
+pub fn sigproc_dec(dec: f64) -> f64 {
+    let (sign, d, m, s) = sexagesimal(dec, 6);
+    sign * (d * 1e4 + m * 1e2 + s)
+}
+
+/// RA in degrees as hh:mm:ss.ssss for DADA headers
+pub fn dada_ra(ra: f64) -> String {
+    let (_, h, m, s) = sexagesimal(ra / 15.0, 4);
+    // Just below 24h can round up to it
+    let h = h % 24.0;
+    format!("{:02}:{:02}:{:07.4}", h, m, s)
+}
+
+/// Dec in degrees as +dd:mm:ss.sss for DADA headers
+pub fn dada_dec(dec: f64) -> String {
+    let (sign, d, m, s) = sexagesimal(dec, 3);
+    let sign = if sign < 0.0 { '-' } else { '+' };
+    format!("{}{:02}:{:02}:{:06.3}", sign, d, m, s)
+}
+
+fn read_pointing(path: &Path) -> Result<Pointing, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let pointing: Pointing = toml::from_str(&contents).map_err(|e| e.to_string())?;
+    pointing.validate()?;
+    Ok(pointing)
+}
+
+/// Watch a TOML pointing file (written by the telescope control system) for changes
+pub fn pointing_watcher(path: PathBuf, runtime: Arc<Runtime>) {
+    info!("Watching {} for pointing updates", path.display());
+    let mut last_modified: Option<SystemTime> = None;
+    loop {
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified != last_modified {
+            last_modified = modified;
+            match read_pointing(&path) {
+                Ok(pointing) => runtime.set_pointing(pointing),
+                Err(e) => warn!("Ignoring pointing file {}: {}", path.display(), e),
+            }
+        }
+        std::thread::sleep(POLL_PERIOD);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        // 12h30m15s, -45d15m30s
+        let ra = 187.5625;
+        let dec = -(45.0 + 15.5 / 60.0);
+        assert!((sigproc_ra(ra) - 123015.0).abs() < 1e-6);
+        assert!((sigproc_dec(dec) + 451530.0).abs() < 1e-6);
+        assert_eq!(dada_ra(ra), "12:30:15.0000");
+        assert_eq!(dada_dec(dec), "-45:15:30.000");
+        // Seconds that round up carry all the way over
+        let ra = (12.0 + 34.0 / 60.0 + 59.99999 / 3600.0) * 15.0;
+        assert_eq!(dada_ra(ra), "12:35:00.0000");
+        let ra = 360.0 - 1e-9;
+        assert_eq!(dada_ra(ra), "00:00:00.0000");
+        assert!(sigproc_ra(ra).abs() < 1e-6);
+        assert_eq!(
+            dada_dec(89.0 + 59.0 / 60.0 + 59.9999 / 3600.0),
+            "+90:00:00.000"
+        );
+        assert_eq!(dada_dec(-(59.9999 / 3600.0)), "-00:01:00.000");
+    }
+
+    #[test]
+    fn test_update() {
+        let mut pointing = Pointing::default();
+        pointing
+            .update(&["source=B0329+54", "ra=53.2", "dec=54.6"])
+            .unwrap();
+        assert_eq!(pointing.source_name.as_deref(), Some("B0329+54"));
+        assert!(pointing.update(&["dec=91"]).is_err());
+        assert!(pointing.update(&["ra=360"]).is_err());
+        assert!(pointing.update(&["bogus=1"]).is_err());
+        assert_eq!(pointing.dec, Some(54.6));
+    }
+}
@@ -0,0 +1,461 @@
+//! Polarization calibration with a 2x2 complex Jones matrix per channel, correcting the leakage
+//! and differential gain/phase between `pol_a` and `pol_b`.
+//!
+//! A solution is the instrumental response `J` of each channel (measured = `J` x true), and its
+//! inverse is applied to the unpacked voltages before detection. Solutions are JSON (written by
+//! the `polcal` subcommand) or text files with a line of `j00 j01 j10 j11` per channel, each
+//! element as `re im`.
+//!
+//! `polcal` derives solutions from dumps of a calibrator with known fractional Stokes. With the
+//! measured coherency `C = J S J^H`, `J = chol(C) chol(S)^-1` brings every channel back to `S`.
+//! That's only unique up to a rotation, which an unpolarized source can't tell us about.
+//! With `C = <v v^H>`, Stokes follow the PSR/IEEE convention for linear feeds (van Straten et al.
+//! 2010), `S = [[I+Q, U-iV], [U+iV, I-Q]] / 2`.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    args::PolcalArgs,
+    calibration::{checksum, CalInfo},
+    capture::{unpack, PayloadBytes},
+    complex::{Complex, ComplexByte},
+    config::Config,
+    convert::read_payloads,
+};
+
+pub type Jones = [[Complex<f32>; 2]; 2];
+
+type Matrix = [[Complex<f64>; 2]; 2];
+
+const IDENTITY: Jones = [
+    [Complex { re: 1.0, im: 0.0 }, Complex { re: 0.0, im: 0.0 }],
+    [Complex { re: 0.0, im: 0.0 }, Complex { re: 1.0, im: 0.0 }],
+];
+
+/// Per-channel Jones matrices, and the inverses we actually apply
+#[derive(Debug, Clone, PartialEq)]
+pub struct JonesTable {
+    pub jones: Vec<Jones>,
+    pub info: CalInfo,
+    inverse: Vec<Jones>,
+}
+
+/// The JSON solution file, each matrix element as `[re, im]`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JonesFile {
+    #[serde(default)]
+    comment: String,
+    jones: Vec<[[[f32; 2]; 2]; 2]>,
+}
+
+fn invert(j: &Jones) -> Option<Jones> {
+    let det = j[0][0] * j[1][1] - j[0][1] * j[1][0];
+    if !(det.norm_sqr() > 0.0 && det.norm_sqr().is_finite()) {
+        return None;
+    }
+    let inv = det.inv();
+    Some([
+        [j[1][1] * inv, -j[0][1] * inv],
+        [-j[1][0] * inv, j[0][0] * inv],
+    ])
+}
+
+impl JonesTable {
+    pub fn new(jones: Vec<Jones>, info: CalInfo) -> Result<Self, String> {
+        let inverse = jones
+            .iter()
+            .enumerate()
+            .map(|(c, j)| invert(j).ok_or(format!("The Jones matrix of channel {} is singular", c)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            jones,
+            info,
+            inverse,
+        })
+    }
+
+    /// Parse a solution for `channels` channels, as JSON or the text format
+    pub fn parse(text: &str, channels: usize, source: &str, json: bool) -> Result<Self, String> {
+        let jones: Vec<Jones> = if json {
+            let file: JonesFile =
+                serde_json::from_str(text).map_err(|e| format!("{}: {}", source, e))?;
+            file.jones
+                .iter()
+                .map(|m| m.map(|row| row.map(|[re, im]| Complex::new(re, im))))
+                .collect()
+        } else {
+            let lines = text
+                .lines()
+                .enumerate()
+                .map(|(i, line)| (i + 1, line.split('#').next().unwrap().trim()))
+                .filter(|(_, line)| !line.is_empty());
+            let mut jones = vec![];
+            for (n, line) in lines {
+                let values = line
+                    .split_whitespace()
+                    .map(|v| v.parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("{}:{}: {}", source, n, e))?;
+                if values.len() != 8 {
+                    return Err(format!(
+                        "{}:{}: expected eight numbers (j00 j01 j10 j11 as re im)",
+                        source, n
+                    ));
+                }
+                let c = |i: usize| Complex::new(values[2 * i], values[2 * i + 1]);
+                jones.push([[c(0), c(1)], [c(2), c(3)]]);
+            }
+            jones
+        };
+        if jones.len() != channels {
+            return Err(format!(
+                "{} has Jones matrices for {} channels, not {}",
+                source,
+                jones.len(),
+                channels
+            ));
+        }
+        if jones
+            .iter()
+            .flatten()
+            .flatten()
+            .any(|c| !(c.re.is_finite() && c.im.is_finite()))
+        {
+            return Err(format!("{} has non-finite Jones matrices", source));
+        }
+        let info = CalInfo {
+            source: source.to_owned(),
+            checksum: checksum(text),
+        };
+        Self::new(jones, info).map_err(|e| format!("{}: {}", source, e))
+    }
+
+    /// Load a solution, as JSON if the extension is `.json`
+    pub fn load(path: &Path, channels: usize) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        let json = path.extension().is_some_and(|ext| ext == "json");
+        Self::parse(&text, channels, &path.to_string_lossy(), json)
+    }
+
+    /// Write a new solution to `path` as JSON, which becomes its source
+    pub fn create(path: &Path, jones: Vec<Jones>, comment: &str) -> Result<Self, String> {
+        let file = JonesFile {
+            comment: comment.to_owned(),
+            jones: jones
+                .iter()
+                .map(|m| m.map(|row| row.map(|c| [c.re, c.im])))
+                .collect(),
+        };
+        let text = serde_json::to_string_pretty(&file).unwrap();
+        std::fs::write(path, &text)
+            .map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+        let info = CalInfo {
+            source: path.to_string_lossy().into_owned(),
+            checksum: checksum(&text),
+        };
+        Self::new(jones, info)
+    }
+
+    /// The corrected voltages of channel `c`
+    pub fn correct(&self, c: usize, a: ComplexByte, b: ComplexByte) -> [Complex<f32>; 2] {
+        let [[i00, i01], [i10, i11]] = self.inverse[c];
+        let (a, b) = (Complex::from(a), Complex::from(b));
+        [i00 * a + i01 * b, i10 * a + i11 * b]
+    }
+}
+
+/// The average coherency matrix `<v v^H>` of every channel
+pub struct Coherency {
+    aa: Vec<f64>,
+    bb: Vec<f64>,
+    ab: Vec<Complex<f64>>,
+    payloads: u64,
+}
+
+fn to_f64(c: ComplexByte) -> Complex<f64> {
+    Complex::new(c.re as f64, c.im as f64)
+}
+
+impl Coherency {
+    pub fn new(channels: usize) -> Self {
+        Self {
+            aa: vec![0.0; channels],
+            bb: vec![0.0; channels],
+            ab: vec![Complex::default(); channels],
+            payloads: 0,
+        }
+    }
+
+    pub fn push(&mut self, pol_a: &[ComplexByte], pol_b: &[ComplexByte]) {
+        for (c, (a, b)) in pol_a.iter().zip(pol_b).enumerate() {
+            let (a, b) = (to_f64(*a), to_f64(*b));
+            self.aa[c] += a.norm_sqr();
+            self.bb[c] += b.norm_sqr();
+            self.ab[c] = self.ab[c] + a * b.conj();
+        }
+        self.payloads += 1;
+    }
+
+    fn matrix(&self, c: usize) -> Matrix {
+        let n = self.payloads.max(1) as f64;
+        let ab = self.ab[c].scale(n.recip());
+        [
+            [Complex::new(self.aa[c] / n, 0.0), ab],
+            [ab.conj(), Complex::new(self.bb[c] / n, 0.0)],
+        ]
+    }
+
+    /// Solve for the Jones matrices, given the fractional Stokes (Q, U, V) of the source.
+    /// Channels we can't solve for (no signal) get the identity, and are counted in the second
+    /// return value.
+    pub fn solve(&self, stokes: [f64; 3]) -> Result<(Vec<Jones>, usize), String> {
+        let [q, u, v] = stokes;
+        if !(0.0..1.0).contains(&(q * q + u * u + v * v)) {
+            return Err("The calibrator must be partially polarized, Q^2 + U^2 + V^2 < 1".into());
+        }
+        if self.payloads == 0 {
+            return Err("No payloads to solve from".to_owned());
+        }
+        // Scale the source so the corrected total power is the median of what we measured
+        let mut powers: Vec<_> = (0..self.aa.len())
+            .map(|c| self.aa[c] + self.bb[c])
+            .filter(|p| *p > 0.0)
+            .collect();
+        powers.sort_by(|a, b| a.total_cmp(b));
+        let i = powers.get(powers.len() / 2).copied().unwrap_or_default() / self.payloads as f64;
+        let source = [
+            [
+                Complex::new(i * (1.0 + q) / 2.0, 0.0),
+                Complex::new(i * u / 2.0, -i * v / 2.0),
+            ],
+            [
+                Complex::new(i * u / 2.0, i * v / 2.0),
+                Complex::new(i * (1.0 - q) / 2.0, 0.0),
+            ],
+        ];
+        let source_inv = match cholesky(&source) {
+            Some(l) => lower_inverse(&l),
+            None => return Err("The calibrator has no signal".to_owned()),
+        };
+        let mut failed = 0;
+        let jones = (0..self.aa.len())
+            .map(|c| match cholesky(&self.matrix(c)) {
+                Some(l) => mul(&l, &source_inv)
+                    .map(|row| row.map(|x| Complex::new(x.re as f32, x.im as f32))),
+                None => {
+                    failed += 1;
+                    IDENTITY
+                }
+            })
+            .collect();
+        Ok((jones, failed))
+    }
+}
+
+/// Lower triangular `L` with `L L^H = m`, if `m` is positive definite
+fn cholesky(m: &Matrix) -> Option<Matrix> {
+    let l00 = m[0][0].re.sqrt();
+    if l00.is_nan() || l00 <= 0.0 {
+        return None;
+    }
+    let l10 = m[1][0].scale(l00.recip());
+    let l11 = (m[1][1].re - l10.norm_sqr()).sqrt();
+    // Not positive definite (or down in the rounding), so there's no solution
+    if l11.is_nan() || l11 <= 1e-9 * l00 {
+        return None;
+    }
+    let zero = Complex::default();
+    Some([
+        [Complex::new(l00, 0.0), zero],
+        [l10, Complex::new(l11, 0.0)],
+    ])
+}
+
+fn lower_inverse(l: &Matrix) -> Matrix {
+    let (l00, l11) = (l[0][0].re, l[1][1].re);
+    [
+        [Complex::new(l00.recip(), 0.0), Complex::default()],
+        [
+            -l[1][0].scale((l00 * l11).recip()),
+            Complex::new(l11.recip(), 0.0),
+        ],
+    ]
+}
+
+fn mul(x: &Matrix, y: &Matrix) -> Matrix {
+    let e = |r: usize, c: usize| x[r][0] * y[0][c] + x[r][1] * y[1][c];
+    [[e(0, 0), e(0, 1)], [e(1, 0), e(1, 1)]]
+}
+
+/// Derive solutions from the packet dumps given on the command line
+pub fn polcal(args: &PolcalArgs, config: &Config) -> Result<(), String> {
+    if config.capture_config().boards() > 1 {
+        return Err("Polarization calibration only handles dumps from a single board".to_owned());
+    }
+    let channels = config.averaging.channels;
+    let mut pol_a = vec![ComplexByte::default(); channels];
+    let mut pol_b = vec![ComplexByte::default(); channels];
+    let mut payload_n = 0;
+    let mut coherency = Coherency::new(channels);
+    read_payloads(&args.files, config.capture.port, |data| {
+        // Anything that isn't a payload is skipped
+        if let Some(payload) = data
+            .ok()
+            .and_then(|data| <&PayloadBytes>::try_from(data).ok())
+        {
+            unpack(payload, &mut pol_a, &mut pol_b, &mut payload_n);
+            coherency.push(&pol_a, &pol_b);
+        }
+        Ok(())
+    })?;
+    let [q, u, v] = args.stokes.unwrap_or_default();
+    let (jones, failed) = coherency.solve([q as f64, u as f64, v as f64])?;
+    if failed > 0 {
+        warn!("No solution for {} channels, leaving them alone", failed);
+    }
+    let comment = format!(
+        "Solved from {} payloads of a source with fractional Q, U, V = {}, {}, {}",
+        coherency.payloads, q, u, v
+    );
+    let table = JonesTable::create(&args.output, jones, &comment)?;
+    info!("Wrote Jones matrices to {}", table.info.source);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn to_f64_matrix(j: &Jones) -> Matrix {
+        j.map(|row| row.map(|c| Complex::new(c.re as f64, c.im as f64)))
+    }
+
+    fn hermitian(m: &Matrix) -> Matrix {
+        [
+            [m[0][0].conj(), m[1][0].conj()],
+            [m[0][1].conj(), m[1][1].conj()],
+        ]
+    }
+
+    #[test]
+    fn test_parse() {
+        let text = "# leaky\n1 0 0.1 0.1 0 -0.1 0.9 0\n1 0 0 0 0 0 1 0 # identity\n";
+        let table = JonesTable::parse(text, 2, "jones.txt", false).unwrap();
+        assert_eq!(table.jones[0][1][0], Complex::new(0.0, -0.1));
+        assert_eq!(table.jones[1], IDENTITY);
+        assert_eq!(
+            table.correct(1, Complex::new(3, -4), Complex::new(1, 2)),
+            [Complex::new(3.0, -4.0), Complex::new(1.0, 2.0)]
+        );
+        // The inverse undoes the leakage
+        let [a, b] = table.correct(0, Complex::new(10, 1), Complex::new(0, 1));
+        let j = table.jones[0];
+        let (a2, b2) = (j[0][0] * a + j[0][1] * b, j[1][0] * a + j[1][1] * b);
+        assert!((a2 - Complex::new(10.0, 1.0)).norm_sqr() < 1e-9);
+        assert!((b2 - Complex::new(0.0, 1.0)).norm_sqr() < 1e-9);
+        assert!(JonesTable::parse(text, 3, "jones.txt", false).is_err());
+        assert!(JonesTable::parse("1 0 1 0 1 0 1 0\n", 1, "jones.txt", false).is_err());
+        assert!(JonesTable::parse("1 0 0 0 0 0 1\n", 1, "jones.txt", false).is_err());
+        // Round trip through JSON
+        let path = std::env::temp_dir().join(format!("jones-test-{}.json", std::process::id()));
+        let created = JonesTable::create(&path, table.jones.clone(), "test").unwrap();
+        let loaded = JonesTable::load(&path, 2).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, created);
+        assert_eq!(loaded.jones, table.jones);
+    }
+
+    #[test]
+    fn test_solve() {
+        let mut rng = StdRng::seed_from_u64(46);
+        let channels = 16;
+        let stokes = [0.2, -0.1, 0.05];
+        let [q, u, v] = stokes;
+        let i = 100.0;
+        let source = [
+            [
+                Complex::new(i * (1.0 + q) / 2.0, 0.0),
+                Complex::new(i * u / 2.0, -i * v / 2.0),
+            ],
+            [
+                Complex::new(i * u / 2.0, i * v / 2.0),
+                Complex::new(i * (1.0 - q) / 2.0, 0.0),
+            ],
+        ];
+        // Random instrumental responses, with the last channel dead
+        let mut coherency = Coherency::new(channels);
+        coherency.payloads = 1;
+        for c in 0..channels - 1 {
+            let mut element = || Complex::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+            let mut j: Matrix = [[element(), element()], [element(), element()]];
+            j[0][0].re += 2.0;
+            j[1][1].re += 2.0;
+            let m = mul(&mul(&j, &source), &hermitian(&j));
+            coherency.aa[c] = m[0][0].re;
+            coherency.bb[c] = m[1][1].re;
+            coherency.ab[c] = m[0][1];
+        }
+        let (jones, failed) = coherency.solve(stokes).unwrap();
+        assert_eq!(failed, 1);
+        assert_eq!(jones[channels - 1], IDENTITY);
+        // Correcting the measured coherency gives back the source (scaled to the median power)
+        let table = JonesTable::new(
+            jones,
+            CalInfo {
+                source: "test".to_owned(),
+                checksum: String::new(),
+            },
+        )
+        .unwrap();
+        let mut traces = vec![];
+        for c in 0..channels - 1 {
+            let inv = to_f64_matrix(&table.inverse[c]);
+            let corrected = mul(&mul(&inv, &coherency.matrix(c)), &hermitian(&inv));
+            let trace = corrected[0][0].re + corrected[1][1].re;
+            traces.push(trace);
+            let scaled = |x: Complex<f64>| x.scale(i / trace);
+            for (r, row) in corrected.iter().enumerate() {
+                for (k, x) in row.iter().enumerate() {
+                    assert!(
+                        (scaled(*x) - source[r][k]).norm_sqr() < 1e-6,
+                        "{} {:?}",
+                        c,
+                        corrected
+                    );
+                }
+            }
+        }
+        assert!(traces.windows(2).all(|w| (w[0] - w[1]).abs() < 1e-3 * w[0]));
+        assert!(coherency.solve([0.8, 0.6, 0.0]).is_err());
+    }
+
+    #[test]
+    fn test_solve_circular() {
+        // Half the power in `b = i a` (V = +I) and half unpolarized, through an ideal instrument
+        let mut coherency = Coherency::new(1);
+        coherency.push(&[Complex::new(4, 0)], &[Complex::new(0, 4)]);
+        coherency.push(&[Complex::new(4, 0)], &[Complex::new(0, 0)]);
+        coherency.push(&[Complex::new(0, 0)], &[Complex::new(4, 0)]);
+        let (jones, failed) = coherency.solve([0.0, 0.0, 0.5]).unwrap();
+        assert_eq!(failed, 0);
+        for (r, row) in jones[0].iter().enumerate() {
+            for (k, x) in row.iter().enumerate() {
+                let expected = if r == k { 1.0 } else { 0.0 };
+                assert!(
+                    (*x - Complex::new(expected, 0.0)).norm_sqr() < 1e-9,
+                    "{:?}",
+                    jones
+                );
+            }
+        }
+        // The opposite handedness needs a leaky instrument to explain it
+        let (jones, _) = coherency.solve([0.0, 0.0, -0.5]).unwrap();
+        assert!(jones[0][1][0].norm_sqr() > 0.1);
+    }
+}
@@ -0,0 +1,150 @@
+//! Startup checks of the host configuration, so a misconfigured machine fails loudly
+//! instead of quietly dropping packets.
+
+use std::{fs, path::Path};
+
+use tracing::{error, warn};
+
+use crate::{capture::PAYLOAD_SIZE, CaptureConfig};
+
+// IPv4 + UDP header sizes, which have to fit in the MTU along with the payload
+const IP_UDP_HEADER_SIZE: usize = 28;
+// Below this the kernel can't absorb much of a burst on the socket
+const RECOMMENDED_RMEM_MAX: usize = 32 * 1024 * 1024;
+
+/// What we could find out about the capture interface and the kernel
+#[derive(Debug, Clone, Default)]
+pub struct HostInfo {
+    pub mtu: Option<usize>,
+    /// Link state from `/sys/class/net/<dev>/operstate` (up, down, unknown, ..)
+    pub operstate: Option<String>,
+    pub rmem_max: Option<usize>,
+}
+
+fn read_sys(path: impl AsRef<Path>) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_owned())
+}
+
+impl HostInfo {
+    pub fn read(device_name: &str) -> Self {
+        let net = Path::new("/sys/class/net").join(device_name);
+        Self {
+            mtu: read_sys(net.join("mtu")).and_then(|s| s.parse().ok()),
+            operstate: read_sys(net.join("operstate")),
+            rmem_max: read_sys("/proc/sys/net/core/rmem_max").and_then(|s| s.parse().ok()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Finding {
+    /// We'll definitely lose data
+    Fatal(String),
+    /// Might be fine, but probably isn't what you want
+    Warning(String),
+}
+
+/// The number of packets the ring buffer needs to hold `buffer_time` seconds of data,
+/// with every board's packets going through the same buffer
+pub fn required_capacity(cc: &CaptureConfig, buffer_time: f32) -> usize {
+    (buffer_time as f64 / cc.cadence as f64).ceil() as usize * cc.boards().max(1)
+}
+
+/// Check the host and the ring buffer sizing against the incoming data rate
+pub fn check(
+    device_name: &str,
+    host: &HostInfo,
+    cc: &CaptureConfig,
+    capacity: usize,
+    buffer_time: f32,
+) -> Vec<Finding> {
+    let mut findings = vec![];
+    let packet_size = PAYLOAD_SIZE + IP_UDP_HEADER_SIZE;
+    match host.mtu {
+        Some(mtu) if mtu < packet_size => findings.push(Finding::Fatal(format!(
+            "The MTU of {} is {}, but packets are {} bytes. Set it with `ip link set dev {} mtu 9000`",
+            device_name, mtu, packet_size, device_name
+        ))),
+        Some(_) => (),
+        None => findings.push(Finding::Warning(format!(
+            "Couldn't read the MTU of {}, make sure it's at least {}",
+            device_name, packet_size
+        ))),
+    }
+    match host.operstate.as_deref() {
+        Some("up") => (),
+        // Loopback and some virtual interfaces don't report a state
+        Some("unknown") | None => findings.push(Finding::Warning(format!(
+            "Couldn't tell if the link on {} is up",
+            device_name
+        ))),
+        Some(state) => findings.push(Finding::Fatal(format!(
+            "The link on {} is {}. Check the cable/transceiver and `ip link set dev {} up`",
+            device_name, state, device_name
+        ))),
+    }
+    match host.rmem_max {
+        Some(rmem) if rmem < RECOMMENDED_RMEM_MAX => findings.push(Finding::Warning(format!(
+            "net.core.rmem_max is only {} bytes. Raise it with `sysctl -w net.core.rmem_max={}`",
+            rmem, RECOMMENDED_RMEM_MAX
+        ))),
+        Some(_) => (),
+        None => findings.push(Finding::Warning(
+            "Couldn't read net.core.rmem_max".to_owned(),
+        )),
+    }
+    let required = required_capacity(cc, buffer_time);
+    if capacity < required {
+        findings.push(Finding::Fatal(format!(
+            "A ring buffer capacity of {} packets only holds {:.1}ms of data, increase it to at least {} to buffer {:.1}ms",
+            capacity,
+            capacity as f32 * cc.cadence * 1e3 / cc.boards().max(1) as f32,
+            required,
+            buffer_time * 1e3
+        )));
+    }
+    findings
+}
+
+/// Log all the findings, returning false if we shouldn't start.
+/// With `strict` unset, fatal findings are only logged.
+pub fn report(findings: &[Finding], strict: bool) -> bool {
+    let mut ok = true;
+    for finding in findings {
+        match finding {
+            Finding::Fatal(msg) if strict => {
+                error!("Preflight: {}", msg);
+                ok = false;
+            }
+            Finding::Fatal(msg) | Finding::Warning(msg) => warn!("Preflight: {}", msg),
+        }
+    }
+    ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_check() {
+        let cc = Config::default().capture_config();
+        assert_eq!(required_capacity(&cc, 0.1), 12208);
+        let good = HostInfo {
+            mtu: Some(9000),
+            operstate: Some("up".to_owned()),
+            rmem_max: Some(RECOMMENDED_RMEM_MAX),
+        };
+        assert!(check("eth0", &good, &cc, 16384, 0.1).is_empty());
+        let bad = HostInfo {
+            mtu: Some(1500),
+            operstate: Some("down".to_owned()),
+            rmem_max: Some(212992),
+        };
+        let findings = check("eth0", &bad, &cc, 256, 0.1);
+        assert_eq!(findings.len(), 4);
+        assert!(!report(&findings, true));
+        assert!(report(&findings, false));
+    }
+}
@@ -0,0 +1,589 @@
+//! PSRFITS search-mode output, for PRESTO, PSRCHIVE and the other FITS based tools.
+//!
+//! The FITS is written by hand rather than through cfitsio: a primary HDU with the observation
+//! metadata, then a SUBINT binary table with a row for every `nsblk` averaged spectra. The
+//! spectra are quantized to 8 bits per row, with the per-channel offsets and scales in DAT_OFFS
+//! and DAT_SCL (so the real value is `data * scl + offs`), and fully masked channels get a
+//! weight of zero in DAT_WTS.
+//!
+//! NAXIS2 is updated after every row, so a file is readable up to its last complete row even
+//! if we never got to finish it. Finishing writes out the last partial row too, padded with the
+//! mean of its spectra. Its TSUBINT only covers the real spectra, and NSTOT gives the total number
+//! of real samples in the file.
+
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use chrono::{TimeZone, Utc};
+use hifitime::Epoch;
+
+use crate::{
+    config::TelescopeSection,
+    metadata::Software,
+    pointing::{dada_dec, dada_ra, Pointing},
+    CaptureConfig,
+};
+
+/// FITS files are made of blocks of this many bytes
+pub const FITS_BLOCK: usize = 2880;
+const CARD: usize = 80;
+// Quantized samples per standard deviation, so we cover +-8 sigma around the mean
+const LEVELS_PER_SIGMA: f32 = 16.0;
+// Unix time of MJD 0 is this many days before the unix epoch
+const MJD_UNIX_EPOCH: i64 = 40587;
+
+/// A FITS header, as a list of 80 character cards
+#[derive(Debug, Default)]
+pub struct FitsHeader {
+    cards: Vec<String>,
+}
+
+// Format a FITS float, which needs an explicit exponent
+fn fits_float(v: f64) -> String {
+    format!("{:.14E}", v)
+}
+
+impl FitsHeader {
+    fn card(&mut self, key: &str, value: &str, comment: &str) -> &mut Self {
+        let mut card = format!("{:<8}= {:>20}", key, value);
+        if !comment.is_empty() {
+            card.push_str(" / ");
+            card.push_str(comment);
+        }
+        card.truncate(CARD);
+        self.cards.push(format!("{:<80}", card));
+        self
+    }
+
+    pub fn logical(&mut self, key: &str, value: bool, comment: &str) -> &mut Self {
+        self.card(key, if value { "T" } else { "F" }, comment)
+    }
+
+    pub fn int(&mut self, key: &str, value: i64, comment: &str) -> &mut Self {
+        self.card(key, &value.to_string(), comment)
+    }
+
+    pub fn float(&mut self, key: &str, value: f64, comment: &str) -> &mut Self {
+        self.card(key, &fits_float(value), comment)
+    }
+
+    pub fn string(&mut self, key: &str, value: &str, comment: &str) -> &mut Self {
+        // Strings are left justified, at least 8 characters and have their quotes doubled
+        let value = format!("'{:<8}'", value.replace('\'', "''"));
+        self.card(key, &format!("{:<20}", value), comment)
+    }
+
+    /// The position of the (first) card with `key`, in bytes from the start of the header
+    pub fn offset_of(&self, key: &str) -> Option<usize> {
+        self.cards
+            .iter()
+            .position(|c| c[..8].trim_end() == key)
+            .map(|i| i * CARD)
+    }
+
+    /// The header with END, padded to a whole number of blocks
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = self.cards.concat().into_bytes();
+        bytes.extend_from_slice(format!("{:<80}", "END").as_bytes());
+        bytes.resize(bytes.len().next_multiple_of(FITS_BLOCK), b' ');
+        bytes
+    }
+}
+
+/// The MJD of `epoch` as (whole day, whole second of the day, fractional second)
+pub fn split_mjd(epoch: Epoch) -> (i64, i64, f64) {
+    let unix = epoch.to_unix_seconds();
+    let whole = unix.floor();
+    let day = (whole as i64).div_euclid(86400);
+    (
+        MJD_UNIX_EPOCH + day,
+        whole as i64 - day * 86400,
+        unix - whole,
+    )
+}
+
+fn fits_date(epoch: Epoch, subsecond: bool) -> String {
+    let unix = epoch.to_unix_seconds();
+    let time = Utc.timestamp_opt(unix.floor() as i64, 0).unwrap();
+    let date = time.format("%Y-%m-%dT%H:%M:%S").to_string();
+    if subsecond {
+        // Three decimal places, without ever rounding up to a whole second
+        let ms = ((unix - unix.floor()) * 1e3).round().min(999.0);
+        format!("{}.{:03}", date, ms as u32)
+    } else {
+        date
+    }
+}
+
+fn primary_header(
+    cc: &CaptureConfig,
+    tstart: Epoch,
+    pointing: &Pointing,
+    telescope: &TelescopeSection,
+) -> FitsHeader {
+    let (imjd, smjd, offs) = split_mjd(tstart);
+    let mut h = FitsHeader::default();
+    h.logical("SIMPLE", true, "file does conform to FITS standard")
+        .int("BITPIX", 8, "number of bits per data pixel")
+        .int("NAXIS", 0, "number of data axes")
+        .logical("EXTEND", true, "FITS dataset may contain extensions")
+        .string("HDRVER", "6.1", "Header version")
+        .string(
+            "FITSTYPE",
+            "PSRFITS",
+            "FITS definition for pulsar data files",
+        )
+        .string(
+            "DATE",
+            &fits_date(Epoch::now().unwrap_or(tstart), false),
+            "File creation date (YYYY-MM-DDThh:mm:ss UTC)",
+        )
+        .string(
+            "TELESCOP",
+            telescope.name.as_deref().unwrap_or("unknown"),
+            "Telescope name",
+        )
+        .string("BACKEND", Software::default().name, "Backend ID")
+        .string("FD_POLN", "LIN", "LIN or CIRC")
+        .string("OBS_MODE", "SEARCH", "(PSR, CAL, SEARCH)")
+        .string(
+            "DATE-OBS",
+            &fits_date(tstart, true),
+            "Date of observation (YYYY-MM-DDThh:mm:ss UTC)",
+        )
+        .float(
+            "OBSFREQ",
+            cc.fcenter(),
+            "[MHz] Centre frequency for observation",
+        )
+        .float("OBSBW", cc.bandwidth, "[MHz] Bandwidth for observation")
+        .int(
+            "OBSNCHAN",
+            cc.out_channels() as i64,
+            "Number of frequency channels (original)",
+        )
+        .string(
+            "SRC_NAME",
+            pointing.source_name.as_deref().unwrap_or("unknown"),
+            "Source or scan ID",
+        )
+        .string("TRK_MODE", "TRACK", "Track mode (TRACK, SCANGC, SCANLAT)");
+    if let Some(ra) = pointing.ra {
+        h.string("RA", &dada_ra(ra), "Right ascension (hh:mm:ss.ssss)");
+    }
+    if let Some(dec) = pointing.dec {
+        h.string("DEC", &dada_dec(dec), "Declination (-dd:mm:ss.sss)");
+    }
+    h.int("STT_IMJD", imjd, "Start MJD (UTC days) (J - long integer)")
+        .int("STT_SMJD", smjd, "[s] Start time (sec past UTC 00h) (J)")
+        .float("STT_OFFS", offs, "[s] Start time offset (D)");
+    h
+}
+
+// (name, format, unit, dimensions) of every SUBINT column
+fn columns(
+    nchan: usize,
+    nsblk: usize,
+) -> Vec<(&'static str, String, &'static str, Option<String>)> {
+    vec![
+        ("TSUBINT", "1D".to_owned(), "s", None),
+        ("OFFS_SUB", "1D".to_owned(), "s", None),
+        ("RA_SUB", "1D".to_owned(), "deg", None),
+        ("DEC_SUB", "1D".to_owned(), "deg", None),
+        ("TEL_AZ", "1E".to_owned(), "deg", None),
+        ("TEL_ZEN", "1E".to_owned(), "deg", None),
+        ("DAT_FREQ", format!("{}D", nchan), "MHz", None),
+        ("DAT_WTS", format!("{}E", nchan), "", None),
+        ("DAT_OFFS", format!("{}E", nchan), "", None),
+        ("DAT_SCL", format!("{}E", nchan), "", None),
+        (
+            "DATA",
+            format!("{}B", nchan * nsblk),
+            "Jy",
+            Some(format!("({},1,{})", nchan, nsblk)),
+        ),
+    ]
+}
+
+/// Bytes in each SUBINT row
+pub fn row_size(nchan: usize, nsblk: usize) -> usize {
+    4 * 8 + 2 * 4 + nchan * 8 + 3 * nchan * 4 + nchan * nsblk
+}
+
+fn subint_header(cc: &CaptureConfig, nsblk: usize) -> FitsHeader {
+    let nchan = cc.out_channels();
+    let columns = columns(nchan, nsblk);
+    let mut h = FitsHeader::default();
+    h.string("XTENSION", "BINTABLE", "binary table extension")
+        .int("BITPIX", 8, "8-bit bytes")
+        .int("NAXIS", 2, "2-dimensional binary table")
+        .int(
+            "NAXIS1",
+            row_size(nchan, nsblk) as i64,
+            "width of table in bytes",
+        )
+        .int("NAXIS2", 0, "Number of rows in table (NSUBINT)")
+        .int("PCOUNT", 0, "size of special data area")
+        .int("GCOUNT", 1, "one data group (required keyword)")
+        .int("TFIELDS", columns.len() as i64, "Number of fields per row");
+    for (i, (name, format, unit, dim)) in columns.iter().enumerate() {
+        let n = i + 1;
+        h.string(&format!("TTYPE{}", n), name, "");
+        h.string(&format!("TFORM{}", n), format, "");
+        if !unit.is_empty() {
+            h.string(&format!("TUNIT{}", n), unit, "");
+        }
+        if let Some(dim) = dim {
+            h.string(&format!("TDIM{}", n), dim, "");
+        }
+    }
+    h.string("EXTNAME", "SUBINT", "name of this binary table extension")
+        .string(
+            "INT_TYPE",
+            "TIME",
+            "Time axis (TIME, BINPHSPERI, BINLNGASC, etc)",
+        )
+        .string("INT_UNIT", "SEC", "Unit of time axis (SEC, PHS (0-1), DEG)")
+        .int("NPOL", 1, "Nr of polarisations")
+        .string("POL_TYPE", "AA+BB", "Polarisation identifier")
+        .float("TBIN", cc.tsamp() as f64, "[s] Time per bin or sample")
+        .int("NBIN", 1, "Nr of bins (PSR/CAL mode; else 1)")
+        .int(
+            "NCHAN",
+            nchan as i64,
+            "Number of channels/sub-bands in this file",
+        )
+        .float("CHAN_BW", cc.foff(), "[MHz] Channel/sub-band width")
+        .int("NCHNOFFS", 0, "Channel/sub-band offset for split files")
+        .int("NSBLK", nsblk as i64, "Samples/row (SEARCH mode, else 1)")
+        .int("NSTOT", 0, "Total number of samples (SEARCH mode, else 1)")
+        .int("NBITS", 8, "Nr of bits/datum (SEARCH mode data, else 1)")
+        .int(
+            "NSUBOFFS",
+            0,
+            "Subint offset (Contiguous SEARCH-mode files)",
+        )
+        .float("ZERO_OFF", 0.0, "Zero offset for SEARCH-mode data")
+        .int(
+            "SIGNINT",
+            0,
+            "1 for signed ints in SEARCH-mode data, else 0",
+        );
+    h
+}
+
+/// Quantize a block of time-major spectra to 8 bits, returning the per-channel
+/// (weight, offset, scale)
+fn quantize(block: &[f32], nchan: usize, out: &mut [u8]) -> Vec<(f32, f32, f32)> {
+    let nsamp = block.len() / nchan;
+    let params: Vec<_> = (0..nchan)
+        .map(|c| {
+            let samples = || block.iter().skip(c).step_by(nchan).map(|&v| v as f64);
+            let mean = samples().sum::<f64>() / nsamp as f64;
+            let var = samples().map(|v| (v - mean).powi(2)).sum::<f64>() / nsamp as f64;
+            let std = var.sqrt() as f32;
+            if std == 0.0 {
+                // Nothing here (probably masked), so zero weight
+                (0.0, mean as f32, 1.0)
+            } else {
+                let scale = std / LEVELS_PER_SIGMA;
+                (1.0, mean as f32 - 128.0 * scale, scale)
+            }
+        })
+        .collect();
+    for (i, (&v, q)) in block.iter().zip(out.iter_mut()).enumerate() {
+        let (_, offs, scl) = params[i % nchan];
+        *q = ((v - offs) / scl).round().clamp(0.0, 255.0) as u8;
+    }
+    params
+}
+
+/// Writes a PSRFITS search-mode file, a row at a time
+pub struct PsrfitsWriter {
+    file: File,
+    nchan: usize,
+    nsblk: usize,
+    tbin: f64,
+    freqs: Vec<f64>,
+    pointing: Pointing,
+    // Where the NAXIS2 and NSTOT cards are in the file
+    naxis2_offset: u64,
+    nstot_offset: u64,
+    rows: u64,
+    // The spectra for the next row
+    block: Vec<f32>,
+    row: Vec<u8>,
+}
+
+impl PsrfitsWriter {
+    /// Create the file and write the headers
+    pub fn create(
+        path: &Path,
+        cc: &CaptureConfig,
+        nsblk: usize,
+        tstart: Epoch,
+        pointing: &Pointing,
+        telescope: &TelescopeSection,
+    ) -> std::io::Result<Self> {
+        let nchan = cc.out_channels();
+        let mut file = File::create(path)?;
+        let primary = primary_header(cc, tstart, pointing, telescope).to_bytes();
+        let subint = subint_header(cc, nsblk);
+        file.write_all(&primary)?;
+        file.write_all(&subint.to_bytes())?;
+        let card_offset = |key| (primary.len() + subint.offset_of(key).unwrap()) as u64;
+        Ok(Self {
+            file,
+            nchan,
+            nsblk,
+            tbin: cc.tsamp() as f64,
+            freqs: (0..nchan)
+                .map(|c| cc.fch1() + c as f64 * cc.foff())
+                .collect(),
+            pointing: pointing.clone(),
+            naxis2_offset: card_offset("NAXIS2"),
+            nstot_offset: card_offset("NSTOT"),
+            rows: 0,
+            block: Vec::with_capacity(nchan * nsblk),
+            row: Vec::with_capacity(row_size(nchan, nsblk)),
+        })
+    }
+
+    /// Number of complete rows written
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Add a spectrum, writing out a row once we have `nsblk` of them
+    pub fn push(&mut self, spectrum: &[f32]) -> std::io::Result<()> {
+        assert_eq!(spectrum.len(), self.nchan);
+        self.block.extend_from_slice(spectrum);
+        if self.block.len() == self.nchan * self.nsblk {
+            self.write_row(self.nsblk)?;
+            self.block.clear();
+        }
+        Ok(())
+    }
+
+    // Write out the block as a row, of which the first `nsamp` spectra are real
+    fn write_row(&mut self, nsamp: usize) -> std::io::Result<()> {
+        let tsubint = nsamp as f64 * self.tbin;
+        let offs_sub = self.rows as f64 * self.nsblk as f64 * self.tbin + tsubint / 2.0;
+        let mut data = vec![0u8; self.block.len()];
+        let params = quantize(&self.block, self.nchan, &mut data);
+        let row = &mut self.row;
+        row.clear();
+        row.extend_from_slice(&tsubint.to_be_bytes());
+        row.extend_from_slice(&offs_sub.to_be_bytes());
+        row.extend_from_slice(&self.pointing.ra.unwrap_or_default().to_be_bytes());
+        row.extend_from_slice(&self.pointing.dec.unwrap_or_default().to_be_bytes());
+        row.extend_from_slice(&(self.pointing.az.unwrap_or_default() as f32).to_be_bytes());
+        row.extend_from_slice(&(self.pointing.za.unwrap_or_default() as f32).to_be_bytes());
+        self.freqs
+            .iter()
+            .for_each(|f| row.extend_from_slice(&f.to_be_bytes()));
+        for i in 0..3 {
+            params.iter().for_each(|p| {
+                let v = [p.0, p.1, p.2][i];
+                row.extend_from_slice(&v.to_be_bytes())
+            });
+        }
+        row.extend_from_slice(&data);
+        self.file.write_all(row)?;
+        let nstot = self.rows * self.nsblk as u64 + nsamp as u64;
+        self.rows += 1;
+        // Keep the counts up to date, so the file is readable as it is
+        let mut cards = FitsHeader::default();
+        cards
+            .int(
+                "NAXIS2",
+                self.rows as i64,
+                "Number of rows in table (NSUBINT)",
+            )
+            .int(
+                "NSTOT",
+                nstot as i64,
+                "Total number of samples (SEARCH mode, else 1)",
+            );
+        self.file.seek(SeekFrom::Start(self.naxis2_offset))?;
+        self.file.write_all(cards.cards[0].as_bytes())?;
+        self.file.seek(SeekFrom::Start(self.nstot_offset))?;
+        self.file.write_all(cards.cards[1].as_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    /// Write out any incomplete row, padded with the mean of its spectra, then pad the table
+    /// out to a whole number of blocks
+    pub fn finish(mut self) -> std::io::Result<()> {
+        let nsamp = self.block.len() / self.nchan;
+        if nsamp > 0 {
+            let mut mean = vec![0f32; self.nchan];
+            for spectrum in self.block.chunks_exact(self.nchan) {
+                for (m, v) in mean.iter_mut().zip(spectrum) {
+                    *m += v / nsamp as f32;
+                }
+            }
+            for _ in nsamp..self.nsblk {
+                self.block.extend_from_slice(&mean);
+            }
+            self.write_row(nsamp)?;
+        }
+        let len = self.file.seek(SeekFrom::End(0))? as usize;
+        let padding = len.next_multiple_of(FITS_BLOCK) - len;
+        self.file.write_all(&vec![0u8; padding])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::collections::HashMap;
+
+    // Just enough of a FITS reader to check what we wrote: the header cards of each HDU
+    // and where its data starts
+    fn read_hdus(bytes: &[u8]) -> Vec<(HashMap<String, String>, usize)> {
+        let mut hdus = vec![];
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let mut cards = HashMap::new();
+            loop {
+                let card = std::str::from_utf8(&bytes[pos..pos + CARD]).unwrap();
+                pos += CARD;
+                if card.trim_end() == "END" {
+                    break;
+                }
+                let value = card[10..].split(" / ").next().unwrap().trim();
+                cards.insert(
+                    card[..8].trim_end().to_owned(),
+                    value.trim_matches('\'').trim_end().to_owned(),
+                );
+            }
+            pos = pos.next_multiple_of(FITS_BLOCK);
+            let data_size = match cards.get("NAXIS").map(|s| s.as_str()) {
+                Some("2") => {
+                    cards["NAXIS1"].parse::<usize>().unwrap()
+                        * cards["NAXIS2"].parse::<usize>().unwrap()
+                }
+                _ => 0,
+            };
+            hdus.push((cards, pos));
+            pos = (pos + data_size).next_multiple_of(FITS_BLOCK);
+        }
+        hdus
+    }
+
+    #[test]
+    fn test_header() {
+        let mut h = FitsHeader::default();
+        h.string("SRC_NAME", "B0329+54", "Source")
+            .float("TBIN", 3.2768e-5, "");
+        let bytes = h.to_bytes();
+        assert_eq!(bytes.len(), FITS_BLOCK);
+        assert_eq!(
+            &bytes[..CARD],
+            format!("{:<80}", "SRC_NAME= 'B0329+54'           / Source").as_bytes()
+        );
+        let card = std::str::from_utf8(&bytes[CARD..2 * CARD]).unwrap();
+        assert_eq!(&card[..10], "TBIN    = ");
+        assert_eq!(card[10..30].trim_start(), "3.27680000000000E-5");
+        assert_eq!(h.offset_of("TBIN"), Some(CARD));
+        assert_eq!(
+            split_mjd(Epoch::from_unix_seconds(86400.0 * 1.5)),
+            (40588, 43200, 0.0)
+        );
+    }
+
+    #[test]
+    fn test_psrfits() {
+        let cc = CaptureConfig {
+            channels: 16,
+            samples: 1024,
+            decimate: 2,
+            ..Config::default().capture_config()
+        };
+        let nchan = cc.out_channels();
+        let nsblk = 64;
+        let path = std::env::temp_dir().join(format!("psrfits-test-{}.fits", std::process::id()));
+        let pointing = Pointing {
+            source_name: Some("test".to_owned()),
+            ra: Some(53.2),
+            ..Default::default()
+        };
+        let tstart = Epoch::from_unix_seconds(1_660_000_000.25);
+        let mut writer =
+            PsrfitsWriter::create(&path, &cc, nsblk, tstart, &pointing, &Default::default())
+                .unwrap();
+        // Two and a half rows, with the last channel masked
+        let spectrum = |t: usize| -> Vec<f32> {
+            (0..nchan)
+                .map(|c| {
+                    if c == nchan - 1 {
+                        0.0
+                    } else {
+                        (c * 10 + t % 7) as f32
+                    }
+                })
+                .collect()
+        };
+        for t in 0..nsblk * 5 / 2 {
+            writer.push(&spectrum(t)).unwrap();
+        }
+        assert_eq!(writer.rows(), 2);
+        // The last half row gets padded out
+        writer.finish().unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bytes.len() % FITS_BLOCK, 0);
+        let hdus = read_hdus(&bytes);
+        assert_eq!(hdus.len(), 2);
+        let (primary, _) = &hdus[0];
+        assert_eq!(primary["OBS_MODE"], "SEARCH");
+        assert_eq!(primary["SRC_NAME"], "test");
+        assert_eq!(primary["RA"], "03:32:48.0000");
+        assert_eq!(primary["STT_IMJD"], "59799");
+        assert_eq!(primary["STT_SMJD"], "83200");
+        assert_eq!(primary["DATE-OBS"], "2022-08-08T23:06:40.250");
+        let (subint, start) = &hdus[1];
+        assert_eq!(subint["EXTNAME"], "SUBINT");
+        assert_eq!(subint["NAXIS2"], "3");
+        assert_eq!(subint["NSTOT"], (nsblk * 5 / 2).to_string());
+        assert_eq!(subint["NAXIS1"], row_size(nchan, nsblk).to_string());
+        // Check the second row decodes back to what we put in
+        let row = &bytes[start + row_size(nchan, nsblk)..][..row_size(nchan, nsblk)];
+        let f64_at = |i: usize| f64::from_be_bytes(row[i..i + 8].try_into().unwrap());
+        let f32_at = |i: usize| f32::from_be_bytes(row[i..i + 4].try_into().unwrap());
+        let tsubint = nsblk as f64 * cc.tsamp() as f64;
+        assert_eq!(f64_at(0), tsubint);
+        assert!((f64_at(8) - 1.5 * tsubint).abs() < 1e-12);
+        // Only half of the last row is real
+        let last = &bytes[start + 2 * row_size(nchan, nsblk)..];
+        let last_f64_at = |i: usize| f64::from_be_bytes(last[i..i + 8].try_into().unwrap());
+        assert_eq!(last_f64_at(0), tsubint / 2.0);
+        assert!((last_f64_at(8) - 2.25 * tsubint).abs() < 1e-12);
+        assert_eq!(f64_at(40), cc.fch1());
+        let wts = 40 + nchan * 8;
+        let offs = wts + nchan * 4;
+        let scl = offs + nchan * 4;
+        let data = scl + nchan * 4;
+        assert_eq!(f32_at(wts), 1.0);
+        assert_eq!(f32_at(wts + (nchan - 1) * 4), 0.0);
+        for t in 0..nsblk {
+            let expected = spectrum(nsblk + t);
+            for c in 0..nchan {
+                let value =
+                    row[data + t * nchan + c] as f32 * f32_at(scl + c * 4) + f32_at(offs + c * 4);
+                assert!(
+                    (value - expected[c]).abs() < 0.2,
+                    "{} {}",
+                    value,
+                    expected[c]
+                );
+            }
+        }
+    }
+}
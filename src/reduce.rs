@@ -0,0 +1,66 @@
+//! Reduction of the spectra in time and frequency before they're written out, so searches that
+//! don't need full resolution (like at low DMs) can run on less data.
+//!
+//! The time stage is the averaging of `CaptureConfig::avgs` payloads in [`crate::exfil`], which is
+//! always a mean, and the frequency stage then combines `CaptureConfig::decimate` adjacent channels
+//! as set by [`Combine`].
+//!
+//! Either way the spectra stay 32-bit floats (`NBIT=32` in filterbanks and PSRDADA), so the choice
+//! only sets their scale: `Mean` keeps the level of a single channel, while `Sum` scales it up by
+//! `decimate`. PSRFITS quantizes each row to 8 bits with its own scales, so it looks the same for
+//! both.
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// How channels are combined when decimating
+pub enum Combine {
+    /// Average, keeping the same scale as the input
+    Mean,
+    /// Sum, scaling the output up by the decimation factor
+    Sum,
+}
+
+impl Combine {
+    /// The factor to scale the sum of `n` samples by
+    pub fn scale(self, n: usize) -> f32 {
+        match self {
+            Combine::Mean => 1.0 / n as f32,
+            Combine::Sum => 1.0,
+        }
+    }
+}
+
+/// Combine adjacent channels of `input` into the (smaller by an integer factor) `output`
+pub fn decimate_channels(input: &[f32], output: &mut [f32], combine: Combine) {
+    let factor = input.len() / output.len();
+    assert_eq!(
+        input.len(),
+        output.len() * factor,
+        "Output channels must evenly divide the input channels"
+    );
+    let scale = combine.scale(factor);
+    for (out, chans) in output.iter_mut().zip(input.chunks_exact(factor)) {
+        *out = chans.iter().sum::<f32>() * scale;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decimate() {
+        let input = [1.0, 3.0, 2.0, 2.0, 0.0, 8.0];
+        let mut output = [0f32; 3];
+        decimate_channels(&input, &mut output, Combine::Mean);
+        assert_eq!(output, [2.0, 2.0, 4.0]);
+        decimate_channels(&input, &mut output, Combine::Sum);
+        assert_eq!(output, [4.0, 4.0, 8.0]);
+        let mut same = [0f32; 6];
+        decimate_channels(&input, &mut same, Combine::Sum);
+        assert_eq!(same, input);
+    }
+}
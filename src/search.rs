@@ -0,0 +1,453 @@
+//! A quick-look single pulse search on the averaged Stokes I stream.
+//!
+//! This isn't meant to compete with heimdall, it's a sanity check that the pipeline is producing
+//! something a detector can find. Spectra are incoherently dedispersed over a linear grid of
+//! trial DMs in gulps, every power of two boxcar up to `max_width` is matched against the
+//! dedispersed time series, and the best candidate above threshold in each gulp is reported.
+//! The cost scales with DMs * channels * samples, so spectra are averaged in time before they're
+//! searched to keep it under `max_rate`. Spectra the search was too slow to get are filled in from
+//! the average spectrum rather than starting over, so falling behind never stalls it.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::Arc,
+};
+
+use crossbeam_channel::{Receiver, Sender};
+use hifitime::Epoch;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    config::SearchSection, control::Runtime, exfil::payload_epoch, monitoring::Spectrum,
+    CaptureConfig, KDM,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Candidate {
+    /// MJD (UTC) of the start of the pulse at the top of the band
+    pub mjd: f64,
+    /// Payload number of the first packet in the pulse at the top of the band
+    pub payload_n: u64,
+    /// Dispersion measure in pc cm^-3
+    pub dm: f32,
+    /// Boxcar width in (averaged) samples
+    pub width: usize,
+    pub snr: f32,
+    /// Number of DM/width/time trials above threshold in this gulp
+    pub members: usize,
+}
+
+/// Delays of every channel (in samples of `tsamp` seconds) relative to the top of the band
+fn channel_delays(cc: &CaptureConfig, dm: f32, tsamp: f64) -> Vec<usize> {
+    let f_top = cc.fch1() + (cc.out_channels() - 1) as f64 * cc.foff();
+    (0..cc.out_channels())
+        .map(|c| {
+            let f = cc.fch1() + c as f64 * cc.foff();
+            let delay = KDM * dm as f64 * (f.powi(-2) - f_top.powi(-2));
+            (delay / tsamp).round() as usize
+        })
+        .collect()
+}
+
+/// The search state, fed one averaged spectrum at a time
+pub struct Dedisperser {
+    cc: CaptureConfig,
+    // Averaged spectra summed into each searched sample
+    factor: usize,
+    dms: Vec<f32>,
+    // Per-DM, per-channel delays in samples
+    delays: Vec<Vec<usize>>,
+    max_delay: usize,
+    widths: Vec<usize>,
+    threshold: f32,
+    gulp: usize,
+    // Time-major samples and the payload number of the last packet of each
+    history: Vec<f32>,
+    payload_ns: Vec<u64>,
+    // The sample being summed, and the last spectrum we got
+    sum: Vec<f32>,
+    summed: usize,
+    last: Option<u64>,
+    // Scratch for the dedispersed time series and its running sum
+    series: Vec<f32>,
+    cumsum: Vec<f64>,
+}
+
+impl Dedisperser {
+    pub fn new(cc: &CaptureConfig, search: &SearchSection) -> Self {
+        let ndm = ((search.dm_max - search.dm_min) / search.dm_step).floor() as usize + 1;
+        let dms: Vec<_> = (0..ndm)
+            .map(|i| search.dm_min + i as f32 * search.dm_step)
+            .collect();
+        // Average in time until there are few enough sums to keep up
+        let sums = (ndm * cc.out_channels()) as f64 / cc.tsamp() as f64;
+        let factor = (sums / search.max_rate).ceil().max(1.0) as usize;
+        let tsamp = cc.tsamp() as f64 * factor as f64;
+        let delays: Vec<_> = dms
+            .iter()
+            .map(|&dm| channel_delays(cc, dm, tsamp))
+            .collect();
+        let max_delay = delays
+            .iter()
+            .flat_map(|d| d.iter())
+            .copied()
+            .max()
+            .unwrap_or(0);
+        let widths = std::iter::successors(Some(1), |w| Some(w * 2))
+            .take_while(|&w| w <= search.max_width)
+            .collect();
+        Self {
+            cc: *cc,
+            factor,
+            dms,
+            delays,
+            max_delay,
+            widths,
+            threshold: search.threshold,
+            gulp: search.gulp,
+            history: vec![],
+            payload_ns: vec![],
+            sum: vec![0.0; cc.out_channels()],
+            summed: 0,
+            last: None,
+            series: vec![],
+            cumsum: vec![],
+        }
+    }
+
+    /// Number of trial DMs
+    pub fn ndm(&self) -> usize {
+        self.dms.len()
+    }
+
+    /// The largest dispersion delay across the band in samples
+    pub fn max_delay(&self) -> usize {
+        self.max_delay
+    }
+
+    /// Number of averaged spectra in each searched sample
+    pub fn factor(&self) -> usize {
+        self.factor
+    }
+
+    // Spectra needed to search a full gulp
+    fn needed(&self) -> usize {
+        self.gulp + self.max_delay + self.widths.last().unwrap_or(&1) - 1
+    }
+
+    /// Add the next spectrum, returning a candidate if this completed a gulp that had one
+    pub fn push(&mut self, spectrum: &Spectrum, payload_start: Epoch) -> Option<Candidate> {
+        let step = self.cc.avgs as u64;
+        let mut candidate = None;
+        match self.last {
+            Some(last) if spectrum.payload_n > last => {
+                let missed = ((spectrum.payload_n - last) / step).saturating_sub(1);
+                if missed >= (self.needed() * self.factor) as u64 {
+                    // Nothing we have would be searched with what's next
+                    self.reset();
+                } else if missed > 0 {
+                    // Zeros would look like a deep dip against the rest, so fill the spectra we
+                    // didn't get with the average one
+                    let baseline = self.baseline();
+                    for i in 1..=missed {
+                        candidate = self
+                            .add(&baseline, last + i * step, payload_start)
+                            .or(candidate);
+                    }
+                }
+            }
+            // The payload numbers went backwards, so the FPGA was re-armed
+            Some(_) => self.reset(),
+            None => (),
+        }
+        self.last = Some(spectrum.payload_n);
+        self.add(&spectrum.data, spectrum.payload_n, payload_start)
+            .or(candidate)
+    }
+
+    fn reset(&mut self) {
+        self.history.clear();
+        self.payload_ns.clear();
+        self.sum.fill(0.0);
+        self.summed = 0;
+    }
+
+    // The average of the spectra we're holding on to
+    fn baseline(&self) -> Vec<f32> {
+        let nchan = self.cc.out_channels();
+        let mut baseline = vec![0f32; nchan];
+        let rows = self.payload_ns.len();
+        if rows > 0 {
+            for sample in self.history.chunks_exact(nchan) {
+                for (b, v) in baseline.iter_mut().zip(sample) {
+                    *b += v / rows as f32;
+                }
+            }
+        } else if self.summed > 0 {
+            for (b, v) in baseline.iter_mut().zip(&self.sum) {
+                *b = v / self.summed as f32;
+            }
+        }
+        baseline
+    }
+
+    // Sum a spectrum into the next sample, searching if that completed a gulp
+    fn add(&mut self, data: &[f32], payload_n: u64, payload_start: Epoch) -> Option<Candidate> {
+        for (s, v) in self.sum.iter_mut().zip(data) {
+            *s += v;
+        }
+        self.summed += 1;
+        if self.summed < self.factor {
+            return None;
+        }
+        let scale = 1.0 / self.factor as f32;
+        self.history.extend(self.sum.iter().map(|s| s * scale));
+        self.payload_ns.push(payload_n);
+        self.sum.fill(0.0);
+        self.summed = 0;
+        if self.payload_ns.len() < self.needed() {
+            return None;
+        }
+        let candidate = self.search(payload_start);
+        // Keep what the next gulp needs
+        self.history.drain(..self.gulp * self.cc.out_channels());
+        self.payload_ns.drain(..self.gulp);
+        candidate
+    }
+
+    fn search(&mut self, payload_start: Epoch) -> Option<Candidate> {
+        let nchan = self.cc.out_channels();
+        let len = self.gulp + self.widths.last().unwrap_or(&1) - 1;
+        let mut best: Option<(f32, usize, usize, usize)> = None;
+        let mut members = 0;
+        for (d, delays) in self.delays.iter().enumerate() {
+            // Dedisperse
+            self.series.clear();
+            self.series.extend((0..len).map(|t| {
+                delays
+                    .iter()
+                    .enumerate()
+                    .map(|(c, delay)| self.history[(t + delay) * nchan + c])
+                    .sum::<f32>()
+            }));
+            // Normalise against this gulp
+            let gulp = &self.series[..self.gulp];
+            let mean = gulp.iter().map(|&v| v as f64).sum::<f64>() / self.gulp as f64;
+            let var =
+                gulp.iter().map(|&v| (v as f64 - mean).powi(2)).sum::<f64>() / self.gulp as f64;
+            let std = var.sqrt();
+            if std == 0.0 {
+                continue;
+            }
+            self.cumsum.clear();
+            self.cumsum.push(0.0);
+            let mut acc = 0.0;
+            for &v in &self.series {
+                acc += v as f64 - mean;
+                self.cumsum.push(acc);
+            }
+            // Match boxcars
+            for &width in &self.widths {
+                let norm = std * (width as f64).sqrt();
+                for t in 0..self.gulp {
+                    let snr = ((self.cumsum[t + width] - self.cumsum[t]) / norm) as f32;
+                    if snr >= self.threshold {
+                        members += 1;
+                        if !matches!(best, Some((b, ..)) if b >= snr) {
+                            best = Some((snr, d, width, t));
+                        }
+                    }
+                }
+            }
+        }
+        best.map(|(snr, d, width, t)| {
+            let payloads = (self.cc.avgs * self.factor) as u64;
+            let payload_n = (self.payload_ns[t] + 1).saturating_sub(payloads);
+            Candidate {
+                mjd: payload_epoch(payload_start, payload_n, &self.cc).to_mjd_utc_days(),
+                payload_n,
+                dm: self.dms[d],
+                width: width * self.factor,
+                snr,
+                members,
+            }
+        })
+    }
+}
+
+fn write_candidate(file: &mut File, candidate: &Candidate) -> std::io::Result<()> {
+    let line = serde_json::to_string(candidate).expect("Candidates are always serializable");
+    writeln!(file, "{}", line)
+}
+
+/// Search the spectra from the exfil thread, sending candidates on to the monitoring socket
+pub fn search_consumer(
+    rx: Receiver<Spectrum>,
+    cc: &CaptureConfig,
+    search: &SearchSection,
+    runtime: Arc<Runtime>,
+    candidates: Sender<Candidate>,
+) {
+    let mut dedisperser = Dedisperser::new(cc, search);
+    info!(
+        "Searching {} DMs up to {} with a maximum delay of {} samples of {} spectra",
+        dedisperser.ndm(),
+        search.dm_max,
+        dedisperser.max_delay(),
+        dedisperser.factor()
+    );
+    let mut file = match OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&search.candidates_file)
+    {
+        Ok(f) => Some(f),
+        Err(e) => {
+            warn!(
+                "Couldn't open {}, candidates won't be saved: {}",
+                search.candidates_file.display(),
+                e
+            );
+            None
+        }
+    };
+    while let Ok(spectrum) = rx.recv() {
+        let candidate = match dedisperser.push(&spectrum, runtime.payload_start()) {
+            Some(c) => c,
+            None => continue,
+        };
+        info!(
+            "Candidate at MJD {:.8}: DM {} width {} S/N {:.1}",
+            candidate.mjd, candidate.dm, candidate.width, candidate.snr
+        );
+        if let Some(f) = &mut file {
+            if let Err(e) = write_candidate(f, &candidate) {
+                warn!("Failed to write candidate: {}", e);
+            }
+        }
+        let _ = candidates.try_send(candidate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use rand::{Rng, SeedableRng};
+
+    fn test_cc() -> CaptureConfig {
+        CaptureConfig {
+            channels: 64,
+            samples: 1024,
+            ..Config::default().capture_config()
+        }
+    }
+
+    fn test_search_section() -> SearchSection {
+        SearchSection {
+            enabled: true,
+            dm_min: 0.0,
+            dm_max: 100.0,
+            dm_step: 10.0,
+            max_width: 8,
+            threshold: 8.0,
+            gulp: 1024,
+            ..Default::default()
+        }
+    }
+
+    // Search noise with a DM 50 pulse 4 spectra wide, starting at the top of the band at
+    // spectrum 300, skipping the spectra in `dropped`
+    fn search_pulse(
+        dedisperser: &mut Dedisperser,
+        cc: &CaptureConfig,
+        dropped: &[std::ops::Range<usize>],
+    ) -> Vec<Candidate> {
+        let delays = channel_delays(cc, 50.0, cc.tsamp() as f64);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let payload_start = Epoch::from_unix_seconds(1.6e9);
+        let mut candidates = vec![];
+        for t in 0..dedisperser.needed() * dedisperser.factor() {
+            let data = (0..cc.channels)
+                .map(|c| {
+                    let pulse = (300..304).contains(&(t as isize - delays[c] as isize));
+                    rng.gen::<f32>() + if pulse { 1.0 } else { 0.0 }
+                })
+                .collect();
+            if dropped.iter().any(|r| r.contains(&t)) {
+                continue;
+            }
+            let spectrum = Spectrum {
+                payload_n: (t as u64 + 1) * 4 - 1,
+                missing: 0,
+                data,
+            };
+            candidates.extend(dedisperser.push(&spectrum, payload_start));
+        }
+        candidates
+    }
+
+    #[test]
+    fn test_search() {
+        let cc = test_cc();
+        let mut dedisperser = Dedisperser::new(&cc, &test_search_section());
+        assert_eq!(dedisperser.ndm(), 11);
+        assert_eq!(dedisperser.factor(), 1);
+        let delays = channel_delays(&cc, 50.0, cc.tsamp() as f64);
+        assert_eq!(delays[63], 0);
+        assert!(delays[0] > delays[1]);
+        let candidates = search_pulse(&mut dedisperser, &cc, &[]);
+        assert_eq!(candidates.len(), 1);
+        let candidate = &candidates[0];
+        assert_eq!(candidate.dm, 50.0);
+        assert_eq!(candidate.width, 4);
+        assert_eq!(candidate.payload_n, 300 * 4);
+        assert!(candidate.snr > 20.0);
+    }
+
+    #[test]
+    fn test_behind() {
+        // Spectra the search didn't get are filled in, so the gulp still completes
+        let cc = test_cc();
+        let mut dedisperser = Dedisperser::new(&cc, &test_search_section());
+        let candidates = search_pulse(&mut dedisperser, &cc, &[10..11, 100..164, 600..900]);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].dm, 50.0);
+        assert_eq!(candidates[0].payload_n, 300 * 4);
+        // Going backwards starts over
+        let spectrum = Spectrum {
+            payload_n: 3,
+            missing: 0,
+            data: vec![0.0; cc.channels],
+        };
+        dedisperser.push(&spectrum, Epoch::from_unix_seconds(1.6e9));
+        assert_eq!(dedisperser.payload_ns, [3]);
+    }
+
+    #[test]
+    fn test_rate() {
+        // The defaults are far too much to search every spectrum
+        let cc = CaptureConfig {
+            channels: 2048,
+            ..test_cc()
+        };
+        let search = SearchSection::default();
+        let dedisperser = Dedisperser::new(&cc, &search);
+        let sums = (dedisperser.ndm() * cc.channels) as f64 / cc.tsamp() as f64;
+        assert!(dedisperser.factor() > 1);
+        assert!(sums / dedisperser.factor() as f64 <= search.max_rate);
+        // A slower search still finds the pulse
+        let cc = test_cc();
+        let search = SearchSection {
+            max_rate: 1e7,
+            ..test_search_section()
+        };
+        let mut dedisperser = Dedisperser::new(&cc, &search);
+        assert_eq!(dedisperser.factor(), 3);
+        let candidates = search_pulse(&mut dedisperser, &cc, &[]);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].dm, 50.0);
+    }
+}
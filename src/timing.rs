@@ -0,0 +1,249 @@
+//! Checks on when the packets arrive, against when the host clock says they should.
+//!
+//! Payload `n` was sampled at `payload_start + n * cadence`, so the kernel's receive timestamp
+//! minus that is the time in flight plus any error in our idea of the start and cadence. The
+//! smallest offset over a window is our best guess at the latter, as the network only ever adds
+//! delay. That steadily changing means the cadence or the host clock is off, and it jumping by a
+//! second means a missed PPS.
+
+use std::{sync::Arc, time::Duration};
+
+use hifitime::Epoch;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{control::Runtime, health::Health};
+
+// Packets per window, after which the statistics are published (about 0.13s for one board)
+const WINDOW: u64 = 16384;
+
+/// How the packet arrival times compare to the host clock
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize)]
+pub struct TimingStats {
+    /// Smallest receive time minus expected time over the last window, in seconds
+    pub offset: f64,
+    /// Largest magnitude of that offset since the payload numbers started
+    pub max_offset: f64,
+    /// RMS of the inter-arrival times minus the cadence over the last window, in seconds
+    pub jitter: f64,
+    /// Long-term change in the offset, in parts per million
+    pub drift_ppm: f64,
+}
+
+/// Running least squares fit of the offset against time
+#[derive(Debug, Default)]
+struct Fit {
+    n: f64,
+    t: f64,
+    o: f64,
+    tt: f64,
+    to: f64,
+}
+
+impl Fit {
+    fn push(&mut self, t: f64, o: f64) {
+        self.n += 1.0;
+        self.t += t;
+        self.o += o;
+        self.tt += t * t;
+        self.to += t * o;
+    }
+
+    fn slope(&self) -> f64 {
+        let denominator = self.n * self.tt - self.t * self.t;
+        if self.n < 2.0 || denominator <= 0.0 {
+            return 0.0;
+        }
+        (self.n * self.to - self.t * self.o) / denominator
+    }
+}
+
+/// Fed every accepted packet by the capture thread
+pub struct TimingMonitor {
+    runtime: Arc<Runtime>,
+    health: Arc<Health>,
+    cadence: f64,
+    threshold: f64,
+    start: Epoch,
+    start_version: u64,
+    // The start as time since the unix epoch, to keep the precision of the timestamps
+    start_unix: Duration,
+    // Receive time (since the start) and payload number of the last packet from each board
+    last: Vec<Option<(f64, u64)>>,
+    count: u64,
+    min_offset: f64,
+    jitter_sq: f64,
+    intervals: u64,
+    max_offset: f64,
+    fit: Fit,
+    off: bool,
+}
+
+impl TimingMonitor {
+    pub fn new(
+        runtime: Arc<Runtime>,
+        health: Arc<Health>,
+        boards: usize,
+        cadence: f32,
+        threshold: f32,
+    ) -> Self {
+        let start = runtime.payload_start();
+        Self {
+            runtime,
+            health,
+            cadence: cadence as f64,
+            threshold: threshold as f64,
+            start,
+            start_version: 0,
+            start_unix: Duration::from_secs_f64(start.to_unix_seconds()),
+            last: vec![None; boards],
+            count: 0,
+            min_offset: f64::INFINITY,
+            jitter_sq: 0.0,
+            intervals: 0,
+            max_offset: 0.0,
+            fit: Fit::default(),
+            off: false,
+        }
+    }
+
+    /// Add a packet received at `received` (since the unix epoch)
+    pub fn packet(&mut self, board: usize, payload_n: u64, received: Duration) {
+        // The boards were re-armed if we've been given a new start, or if the payload numbers
+        // went back further than any reordering would (before the new start comes through).
+        // Either way the window has packets from both sides, so it's thrown away.
+        let start = self
+            .runtime
+            .payload_start_update(&mut self.start_version)
+            .filter(|start| *start != self.start);
+        let backwards = self.last[board].is_some_and(|(_, last_n)| payload_n + WINDOW < last_n);
+        if start.is_some() || backwards {
+            self.restart(start.unwrap_or(self.start));
+        }
+        let since_start =
+            (received.as_nanos() as i128 - self.start_unix.as_nanos() as i128) as f64 * 1e-9;
+        let offset = since_start - payload_n as f64 * self.cadence;
+        self.min_offset = self.min_offset.min(offset);
+        if let Some((last_time, last_n)) = self.last[board] {
+            if payload_n > last_n {
+                let expected = (payload_n - last_n) as f64 * self.cadence;
+                self.jitter_sq += (since_start - last_time - expected).powi(2);
+                self.intervals += 1;
+            }
+        }
+        self.last[board] = Some((since_start, payload_n));
+        self.count += 1;
+        if self.count == WINDOW {
+            self.publish(since_start);
+        }
+    }
+
+    /// Wrap up the window that ended `now` seconds after the start
+    fn publish(&mut self, now: f64) {
+        let offset = self.min_offset;
+        self.max_offset = self.max_offset.max(offset.abs());
+        self.fit.push(now, offset);
+        let jitter = if self.intervals > 0 {
+            (self.jitter_sq / self.intervals as f64).sqrt()
+        } else {
+            0.0
+        };
+        self.health.set_timing(TimingStats {
+            offset,
+            max_offset: self.max_offset,
+            jitter,
+            drift_ppm: self.fit.slope() * 1e6,
+        });
+        if offset.abs() > self.threshold && !self.off {
+            warn!(
+                "Packets are {:.3}s off the host clock, check the PPS, the cadence and NTP",
+                offset
+            );
+            self.off = true;
+        } else if offset.abs() <= self.threshold && self.off {
+            info!("Packets are back in line with the host clock");
+            self.off = false;
+        }
+        self.clear_window();
+    }
+
+    fn clear_window(&mut self) {
+        self.count = 0;
+        self.min_offset = f64::INFINITY;
+        self.jitter_sq = 0.0;
+        self.intervals = 0;
+    }
+
+    // Start over from the payload numbers starting at `start`
+    fn restart(&mut self, start: Epoch) {
+        info!("Payload numbers restarted, starting the timing over");
+        self.clear_window();
+        self.start = start;
+        self.start_unix = Duration::from_secs_f64(start.to_unix_seconds());
+        self.last.fill(None);
+        self.max_offset = 0.0;
+        self.fit = Fit::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mask::ChannelMask, pointing::Pointing};
+
+    #[test]
+    fn test_timing() {
+        let start = Epoch::from_unix_seconds(1_690_000_000.0);
+        let runtime = Arc::new(Runtime::new(
+            start,
+            ChannelMask::new(16),
+            1,
+            Pointing::default(),
+            false,
+        ));
+        let health = Arc::new(Health::default());
+        let cadence = 8.192e-6f32;
+        let mut monitor = TimingMonitor::new(runtime.clone(), health.clone(), 1, cadence, 0.01);
+        // A clock running 10ppm fast, 1ms of latency and a little alternating jitter
+        let received = |n: u64| {
+            let t = n as f64 * cadence as f64;
+            let jitter = if n.is_multiple_of(2) { 1e-6 } else { 0.0 };
+            Duration::from_secs(1_690_000_000)
+                + Duration::from_secs_f64(t * (1.0 + 1e-5) + 1e-3 + jitter)
+        };
+        for n in 0..10 * WINDOW {
+            monitor.packet(0, n, received(n));
+        }
+        let stats = health.timing();
+        // Plus the drift over the second or so we've been going
+        assert!((stats.offset - 1e-3).abs() < 5e-5, "{:?}", stats);
+        assert!((stats.jitter - 1e-6).abs() < 1e-7, "{:?}", stats);
+        assert!((stats.drift_ppm - 10.0).abs() < 0.5, "{:?}", stats);
+        assert!(!monitor.off);
+        // A missed PPS puts everything a second out
+        for n in 10 * WINDOW..11 * WINDOW {
+            monitor.packet(0, n, received(n) + Duration::from_secs(1));
+        }
+        assert!(monitor.off);
+        assert!(health.timing().max_offset > 1.0);
+        // Re-armed partway through a window, with the new start coming through a bit later
+        for n in 11 * WINDOW..11 * WINDOW + 100 {
+            monitor.packet(0, n, received(n));
+        }
+        let rearmed = Duration::from_secs(1_690_000_100);
+        let received = |n: u64| rearmed + Duration::from_secs_f64(n as f64 * cadence as f64 + 1e-3);
+        for n in 0..100 {
+            monitor.packet(0, n, received(n));
+        }
+        assert_eq!(monitor.count, 100);
+        runtime.set_payload_start(Epoch::from_unix_seconds(1_690_000_100.0));
+        for n in 100..WINDOW + 100 {
+            monitor.packet(0, n, received(n));
+        }
+        // Only the packets after the new start were in the window
+        let stats = health.timing();
+        assert!((stats.offset - 1e-3).abs() < 1e-5, "{:?}", stats);
+        assert!(stats.max_offset < 2e-3, "{:?}", stats);
+        assert!(!monitor.off);
+    }
+}
@@ -0,0 +1,293 @@
+//! VDIF (VLBI Data Interchange Format) framing of the voltages, for VLBI and pulsar tools.
+//!
+//! Each payload (one time sample of every channel) becomes one frame per polarization, with
+//! polarization A on thread 0 and B on thread 1. Samples are 8-bit complex, written as offset
+//! binary per the spec. VDIF assumes a whole number of frames per second, which our cadence
+//! doesn't give us (8.192us is about 122070.3 frames/s), so frames are numbered by the whole
+//! cadences since the start of their second. That's exact on the way back as long as the reader
+//! knows the cadence, see [`payload_n_from_header`].
+
+use chrono::{Datelike, TimeZone, Utc};
+use hifitime::Epoch;
+
+use crate::{
+    capture::unpack,
+    complex::{Complex, ComplexByte},
+    CaptureConfig,
+};
+
+/// Size of a (non-legacy) VDIF header
+pub const VDIF_HEADER_SIZE: usize = 32;
+/// Thread carrying each polarization
+pub const POL_THREADS: [u16; 2] = [0, 1];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VdifHeader {
+    /// Set if the data in this frame shouldn't be used
+    pub invalid: bool,
+    /// Seconds since the reference epoch
+    pub seconds: u32,
+    /// Number of half years since 2000
+    pub ref_epoch: u8,
+    /// Frame number within the second
+    pub frame: u32,
+    /// Frame length including the header in bytes (a multiple of 8)
+    pub frame_len: usize,
+    pub log2_nchan: u8,
+    pub bits_per_sample: u8,
+    pub complex: bool,
+    pub thread: u16,
+    /// Two character station code
+    pub station: u16,
+}
+
+impl VdifHeader {
+    pub fn encode(&self) -> [u8; VDIF_HEADER_SIZE] {
+        let words: [u32; 4] = [
+            (self.invalid as u32) << 31 | (self.seconds & 0x3fff_ffff),
+            ((self.ref_epoch as u32) & 0x3f) << 24 | (self.frame & 0xff_ffff),
+            ((self.log2_nchan as u32) & 0x1f) << 24 | ((self.frame_len / 8) as u32 & 0xff_ffff),
+            (self.complex as u32) << 31
+                | ((self.bits_per_sample as u32 - 1) & 0x1f) << 26
+                | ((self.thread as u32) & 0x3ff) << 16
+                | self.station as u32,
+        ];
+        // No extended user data
+        let mut buf = [0u8; VDIF_HEADER_SIZE];
+        for (chunk, word) in buf.chunks_exact_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        buf
+    }
+
+    /// Decode a header, returning None for legacy headers or versions we don't know
+    pub fn decode(buf: &[u8; VDIF_HEADER_SIZE]) -> Option<Self> {
+        let word = |i: usize| u32::from_le_bytes(buf[4 * i..4 * i + 4].try_into().unwrap());
+        if word(0) >> 30 & 1 == 1 || word(2) >> 29 != 0 {
+            return None;
+        }
+        Some(Self {
+            invalid: word(0) >> 31 == 1,
+            seconds: word(0) & 0x3fff_ffff,
+            ref_epoch: (word(1) >> 24 & 0x3f) as u8,
+            frame: word(1) & 0xff_ffff,
+            frame_len: (word(2) & 0xff_ffff) as usize * 8,
+            log2_nchan: (word(2) >> 24 & 0x1f) as u8,
+            bits_per_sample: (word(3) >> 26 & 0x1f) as u8 + 1,
+            complex: word(3) >> 31 == 1,
+            thread: (word(3) >> 16 & 0x3ff) as u16,
+            station: word(3) as u16,
+        })
+    }
+}
+
+/// Pack a two character station code into its 16 bit ID
+pub fn station_id(code: &str) -> Result<u16, String> {
+    match code.as_bytes() {
+        [a, b] if code.is_ascii() => Ok(u16::from_be_bytes([*a, *b])),
+        _ => Err(format!(
+            "VDIF station codes are two ASCII characters, not `{}`",
+            code
+        )),
+    }
+}
+
+/// The start of the VDIF reference epoch (half year since 2000) that `epoch` is in
+pub fn ref_epoch(epoch: Epoch) -> (u8, Epoch) {
+    let time = Utc
+        .timestamp_opt(epoch.to_unix_seconds().floor() as i64, 0)
+        .unwrap();
+    let half = time.month() > 6;
+    let start = Utc
+        .with_ymd_and_hms(time.year(), if half { 7 } else { 1 }, 1, 0, 0, 0)
+        .unwrap();
+    (
+        ((time.year() - 2000) * 2 + half as i32) as u8,
+        Epoch::from_unix_seconds(start.timestamp() as f64),
+    )
+}
+
+const NS_PER_SECOND: i128 = 1_000_000_000;
+
+// Frame timing is done in whole nanoseconds so it's exact at the second boundaries
+fn cadence_ns(cc: &CaptureConfig) -> i128 {
+    (cc.cadence as f64 * 1e9).round() as i128
+}
+
+// Nanoseconds from the start of the reference epoch to payload 0
+fn start_offset_ns(payload_start: Epoch) -> i128 {
+    let (_, ref_start) = ref_epoch(payload_start);
+    (payload_start - ref_start).total_nanoseconds()
+}
+
+/// The payload number of a frame, given the epoch of payload 0
+pub fn payload_n_from_header(header: &VdifHeader, payload_start: Epoch, cc: &CaptureConfig) -> u64 {
+    let cadence = cadence_ns(cc);
+    // The payload started somewhere in this cadence after the frame's nominal start,
+    // so it's the first one starting at or after it
+    let t = header.seconds as i128 * NS_PER_SECOND + header.frame as i128 * cadence;
+    let since_start = t - start_offset_ns(payload_start);
+    ((since_start + cadence - 1).div_euclid(cadence)) as u64
+}
+
+/// Turns payloads into VDIF frames
+pub struct VdifFramer {
+    cc: CaptureConfig,
+    station: u16,
+    ref_epoch: u8,
+    // Nanoseconds from the start of the reference epoch to payload 0
+    offset: i128,
+    pols: [Vec<ComplexByte>; 2],
+}
+
+impl VdifFramer {
+    pub fn new(cc: &CaptureConfig, station: u16, payload_start: Epoch) -> Self {
+        Self {
+            cc: *cc,
+            station,
+            ref_epoch: ref_epoch(payload_start).0,
+            offset: start_offset_ns(payload_start),
+            pols: [
+                vec![Complex::default(); cc.channels],
+                vec![Complex::default(); cc.channels],
+            ],
+        }
+    }
+
+    /// Bytes in each frame, including the header
+    pub fn frame_len(&self) -> usize {
+        VDIF_HEADER_SIZE + self.cc.channels * 2
+    }
+
+    /// Append a frame per polarization for payload `payload_n` to `out`.
+    /// A missing payload (None) gets frames marked invalid.
+    pub fn frames(&mut self, payload: Option<&[u8]>, payload_n: u64, out: &mut Vec<u8>) {
+        let cadence = cadence_ns(&self.cc);
+        let t = self.offset + payload_n as i128 * cadence;
+        let seconds = (t / NS_PER_SECOND) as u32;
+        let frame = (t % NS_PER_SECOND / cadence) as u32;
+        if let Some(payload) = payload {
+            let [pol_a, pol_b] = &mut self.pols;
+            let mut n = 0;
+            unpack(payload, pol_a, pol_b, &mut n);
+        }
+        for (thread, pol) in POL_THREADS.into_iter().zip(&self.pols) {
+            let header = VdifHeader {
+                invalid: payload.is_none(),
+                seconds,
+                ref_epoch: self.ref_epoch,
+                frame,
+                frame_len: self.frame_len(),
+                log2_nchan: self.cc.channels.trailing_zeros() as u8,
+                bits_per_sample: 8,
+                complex: true,
+                thread,
+                station: self.station,
+            };
+            out.extend_from_slice(&header.encode());
+            match payload {
+                // Offset binary
+                Some(_) => out.extend(
+                    pol.iter()
+                        .flat_map(|c| [c.re as u8 ^ 0x80, c.im as u8 ^ 0x80]),
+                ),
+                None => out.resize(out.len() + self.cc.channels * 2, 0x80),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        capture::{PAYLOAD_SIZE, TIMESTAMP_SIZE},
+        config::Config,
+    };
+    use hifitime::TimeUnits;
+    use rand::{Rng, SeedableRng};
+
+    // What a reader would do, turning a frame back into its header and offset binary samples
+    fn read_frame(frame: &[u8]) -> (VdifHeader, Vec<ComplexByte>) {
+        let header = VdifHeader::decode(frame[..VDIF_HEADER_SIZE].try_into().unwrap()).unwrap();
+        assert_eq!(header.frame_len, frame.len());
+        let samples = frame[VDIF_HEADER_SIZE..]
+            .chunks_exact(2)
+            .map(|c| Complex::new((c[0] ^ 0x80) as i8, (c[1] ^ 0x80) as i8))
+            .collect();
+        (header, samples)
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let header = VdifHeader {
+            invalid: true,
+            seconds: 12_345_678,
+            ref_epoch: 46,
+            frame: 122_069,
+            frame_len: 4128,
+            log2_nchan: 11,
+            bits_per_sample: 8,
+            complex: true,
+            thread: 1,
+            station: station_id("GX").unwrap(),
+        };
+        assert_eq!(VdifHeader::decode(&header.encode()), Some(header));
+        assert!(station_id("GRX").is_err());
+    }
+
+    #[test]
+    fn test_frames() {
+        let cc = CaptureConfig {
+            samples: 1024,
+            ..Config::default().capture_config()
+        };
+        // Some way into the second half of 2023
+        let payload_start = Epoch::from_unix_seconds(1_690_000_000.5);
+        let (ref_epoch_n, ref_start) = ref_epoch(payload_start);
+        assert_eq!(ref_epoch_n, 47);
+        assert!(ref_start < payload_start && payload_start - ref_start < 184.days());
+        let mut framer = VdifFramer::new(&cc, station_id("GX").unwrap(), payload_start);
+        let mut payload = [0u8; PAYLOAD_SIZE];
+        rand::rngs::StdRng::seed_from_u64(42).fill(&mut payload[TIMESTAMP_SIZE..]);
+        let mut pol_a = vec![ComplexByte::default(); cc.channels];
+        let mut pol_b = vec![ComplexByte::default(); cc.channels];
+        unpack(&payload, &mut pol_a, &mut pol_b, &mut 0);
+        // Straddle a second boundary
+        for payload_n in (61_000..61_040).chain([u64::from(u32::MAX) + 7]) {
+            let mut out = vec![];
+            framer.frames(Some(&payload), payload_n, &mut out);
+            framer.frames(None, payload_n + 1, &mut out);
+            assert_eq!(out.len(), 4 * framer.frame_len());
+            let frames: Vec<_> = out
+                .chunks_exact(framer.frame_len())
+                .map(read_frame)
+                .collect();
+            for (i, (header, samples)) in frames.iter().enumerate() {
+                assert_eq!(header.invalid, i >= 2);
+                assert_eq!(header.thread, POL_THREADS[i % 2]);
+                assert_eq!(header.log2_nchan, 11);
+                assert_eq!(
+                    payload_n_from_header(header, payload_start, &cc),
+                    payload_n + (i as u64 / 2)
+                );
+                // Invalid frames are all zeros
+                if header.invalid {
+                    assert!(samples.iter().all(|&c| c == ComplexByte::default()));
+                }
+            }
+            // The whole payload comes back out on the right threads
+            assert_eq!(frames[0].1, pol_a);
+            assert_eq!(frames[1].1, pol_b);
+        }
+        // Pol A is the first two bytes of every four, as offset binary
+        let mut out = vec![];
+        framer.frames(Some(&payload), 0, &mut out);
+        let data = &out[VDIF_HEADER_SIZE..];
+        let raw = &payload[TIMESTAMP_SIZE..];
+        assert_eq!(
+            data[..4],
+            [raw[0], raw[1], raw[4], raw[5]].map(|b| b ^ 0x80)
+        );
+    }
+}
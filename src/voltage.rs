@@ -0,0 +1,443 @@
+//! Continuous recording of the raw dual-polarization voltages, for coherent dedispersion and
+//! timing on short test observations.
+//!
+//! Every payload is copied to a ring buffer on the exfil thread and written out in packet order
+//! by a background thread, either to DADA files, VDIF files (see [`crate::vdif`]) or to a
+//! dedicated PSRDADA buffer. The payloads are already in TFP order (time, then channel, then
+//! polarization) of 8-bit complex samples, so for DADA they're written as-is without the header.
+//! Missing payloads are zero-filled (or marked invalid in VDIF) to keep the timing, and a re-arm
+//! (or a long gap) starts a new recording with a new UTC_START.
+//!
+//! At the full cadence this is about a gigabyte per second, so if the writer can't keep up the
+//! payloads it misses are zero-filled too.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::Arc,
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use lending_iterator::LendingIterator;
+use psrdada::client::DadaClient;
+use tracing::{error, info, warn};
+
+use crate::{
+    capture::{payload_number, PayloadBytes, SPECTRA_SIZE, TIMESTAMP_SIZE},
+    config::{VoltageFormat, VoltageSection},
+    control::Runtime,
+    exfil::{heimdall_timestamp, payload_epoch, utc_start_second, BlockFill},
+    pointing::{dada_dec, dada_ra},
+    vdif::{station_id, VdifFramer},
+    CaptureConfig,
+};
+
+/// Size of the ASCII header at the start of every DADA file
+pub const DADA_HEADER_SIZE: usize = 4096;
+// Gaps longer than this (in seconds) start a new recording instead of being zero-filled
+pub(crate) const MAX_GAP: f64 = 1.0;
+// How long the writer sleeps when it's caught up
+const POLL_PERIOD: Duration = Duration::from_micros(100);
+
+static ZEROS: [u8; SPECTRA_SIZE] = [0u8; SPECTRA_SIZE];
+
+/// How a payload follows on from the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Continuity {
+    /// Right after the last one
+    Next,
+    /// After this many missing payloads
+    Gap(u64),
+    /// Too far from the last one (or the first one), so the timing starts over
+    Restart,
+    /// Behind the last one, so it's dropped
+    Late,
+}
+
+pub(crate) struct Sequence {
+    next: Option<u64>,
+    max_gap: u64,
+}
+
+impl Sequence {
+    pub(crate) fn new(max_gap: u64) -> Self {
+        Self {
+            next: None,
+            max_gap,
+        }
+    }
+
+    pub(crate) fn check(&mut self, payload_n: u64) -> Continuity {
+        let continuity = match self.next {
+            None => Continuity::Restart,
+            Some(next) if payload_n == next => Continuity::Next,
+            // Payload numbers going way back means the FPGA was re-armed
+            Some(next) if payload_n < next && next - payload_n > self.max_gap => {
+                Continuity::Restart
+            }
+            Some(next) if payload_n < next => return Continuity::Late,
+            Some(next) if payload_n - next > self.max_gap => Continuity::Restart,
+            Some(next) => Continuity::Gap(payload_n - next),
+        };
+        self.next = Some(payload_n + 1);
+        continuity
+    }
+}
+
+/// The DADA header of a recording whose first payload is `payload_n`
+fn voltage_header(
+    cc: &CaptureConfig,
+    runtime: &Runtime,
+    payload_n: u64,
+) -> HashMap<String, String> {
+    let payload_start = runtime.payload_start();
+    let utc_start = utc_start_second(payload_start);
+    let start = payload_epoch(payload_start, payload_n, cc);
+    let offset = ((start - utc_start).to_seconds() / cc.cadence as f64).round() as u64;
+    let mut header = HashMap::from([
+        ("HDR_VERSION".to_owned(), "1.0".to_owned()),
+        ("HDR_SIZE".to_owned(), DADA_HEADER_SIZE.to_string()),
+        ("NCHAN".to_owned(), cc.channels.to_string()),
+        ("BW".to_owned(), cc.bandwidth.to_string()),
+        ("FREQ".to_owned(), cc.fcenter().to_string()),
+        ("NPOL".to_owned(), "2".to_owned()),
+        ("NDIM".to_owned(), "2".to_owned()),
+        ("NBIT".to_owned(), "8".to_owned()),
+        ("ORDER".to_owned(), "TFP".to_owned()),
+        ("TSAMP".to_owned(), (cc.cadence as f64 * 1e6).to_string()),
+        ("RESOLUTION".to_owned(), SPECTRA_SIZE.to_string()),
+        (
+            "BYTES_PER_SECOND".to_owned(),
+            (SPECTRA_SIZE as f64 / cc.cadence as f64).to_string(),
+        ),
+        ("UTC_START".to_owned(), heimdall_timestamp(&utc_start)),
+        (
+            "OBS_OFFSET".to_owned(),
+            (offset * SPECTRA_SIZE as u64).to_string(),
+        ),
+    ]);
+    let pointing = runtime.pointing();
+    for (key, value) in [
+        ("SOURCE", pointing.source_name),
+        ("RA", pointing.ra.map(dada_ra)),
+        ("DEC", pointing.dec.map(dada_dec)),
+    ] {
+        if let Some(value) = value {
+            header.insert(key.to_owned(), value);
+        }
+    }
+    header
+}
+
+/// Format a header as the fixed size ASCII block at the start of a DADA file
+pub(crate) fn header_bytes(header: &HashMap<String, String>) -> Vec<u8> {
+    let mut keys: Vec<_> = header.keys().collect();
+    keys.sort();
+    let mut bytes: Vec<u8> = keys
+        .into_iter()
+        .flat_map(|k| format!("{} {}\n", k, header[k]).into_bytes())
+        .collect();
+    assert!(
+        bytes.len() <= DADA_HEADER_SIZE,
+        "DADA header doesn't fit in {} bytes",
+        DADA_HEADER_SIZE
+    );
+    bytes.resize(DADA_HEADER_SIZE, 0);
+    bytes
+}
+
+// Wait for the next payload, returning None once the exfil thread is gone and we've caught up
+fn pop(consumer: &mut rtrb::Consumer<PayloadBytes>) -> Option<PayloadBytes> {
+    loop {
+        if let Ok(payload) = consumer.pop() {
+            return Some(payload);
+        }
+        if consumer.is_abandoned() {
+            return None;
+        }
+        std::thread::sleep(POLL_PERIOD);
+    }
+}
+
+// Writes the payloads to DADA or VDIF files of `file_length` seconds
+struct FileWriter {
+    cc: CaptureConfig,
+    directory: PathBuf,
+    payloads_per_file: u64,
+    runtime: Arc<Runtime>,
+    // Station ID if we're writing VDIF
+    vdif_station: Option<u16>,
+    // The current file and the number of payloads in it
+    file: Option<(BufWriter<File>, u64)>,
+    // Framing for the current VDIF file, and somewhere to put the frames
+    framer: Option<VdifFramer>,
+    frames: Vec<u8>,
+}
+
+impl FileWriter {
+    /// Write a payload (or zeros/invalid frames if it's missing)
+    fn write(&mut self, payload: Option<&PayloadBytes>, payload_n: u64) -> std::io::Result<()> {
+        if self
+            .file
+            .as_ref()
+            .is_some_and(|(_, n)| *n >= self.payloads_per_file)
+        {
+            self.end()?;
+        }
+        let (file, n) = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let payload_start = self.runtime.payload_start();
+                let start = payload_epoch(payload_start, payload_n, &self.cc);
+                let extension = if self.vdif_station.is_some() {
+                    "vdif"
+                } else {
+                    "dada"
+                };
+                let path = self.directory.join(format!(
+                    "grex-voltages-{}.{}",
+                    heimdall_timestamp(&start),
+                    extension
+                ));
+                info!("Recording voltages to {}", path.display());
+                let mut file = BufWriter::new(File::create(&path)?);
+                match self.vdif_station {
+                    Some(station) => {
+                        self.framer = Some(VdifFramer::new(&self.cc, station, payload_start))
+                    }
+                    None => file.write_all(&header_bytes(&voltage_header(
+                        &self.cc,
+                        &self.runtime,
+                        payload_n,
+                    )))?,
+                }
+                self.file.insert((file, 0))
+            }
+        };
+        match &mut self.framer {
+            Some(framer) => {
+                self.frames.clear();
+                framer.frames(payload.map(|p| &p[..]), payload_n, &mut self.frames);
+                file.write_all(&self.frames)?;
+            }
+            None => file.write_all(payload.map_or(&ZEROS[..], |p| &p[TIMESTAMP_SIZE..]))?,
+        }
+        *n += 1;
+        Ok(())
+    }
+
+    fn end(&mut self) -> std::io::Result<()> {
+        self.framer = None;
+        match self.file.take() {
+            Some((mut file, _)) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn file_writer(
+    mut consumer: rtrb::Consumer<PayloadBytes>,
+    mut writer: FileWriter,
+    mut sequence: Sequence,
+) -> std::io::Result<()> {
+    while let Some(payload) = pop(&mut consumer) {
+        let payload_n = payload_number(&payload);
+        match sequence.check(payload_n) {
+            Continuity::Late => continue,
+            Continuity::Restart => writer.end()?,
+            Continuity::Gap(missing) => {
+                for n in payload_n - missing..payload_n {
+                    writer.write(None, n)?;
+                }
+            }
+            Continuity::Next => (),
+        }
+        writer.write(Some(&payload), payload_n)?;
+    }
+    writer.end()
+}
+
+// Writes the payloads to a PSRDADA buffer, starting a new session (with end of data) on a restart
+fn dada_writer(
+    key: i32,
+    mut consumer: rtrb::Consumer<PayloadBytes>,
+    cc: &CaptureConfig,
+    runtime: &Runtime,
+    mut sequence: Sequence,
+) -> Result<(), String> {
+    let mut client = DadaClient::new(key)
+        .map_err(|e| format!("Couldn't connect to PSRDADA buffer {:x}: {:?}", key, e))?;
+    if client.data_buf_size() == 0 {
+        return Err(format!("PSRDADA buffer {:x} has empty blocks", key));
+    }
+    let mut fill = BlockFill::new(client.data_buf_size());
+    let (mut hc, mut dc) = client.split();
+    let mut writer = dc.writer();
+    let mut started = false;
+    // The payload (and how many zero payloads come before it) we couldn't write to the last block
+    let mut held: Option<(PayloadBytes, u64)> = None;
+    let mut split: Option<usize> = None;
+    loop {
+        let mut block = writer.next().unwrap();
+        loop {
+            let (payload, missing) = match held.take() {
+                Some(held) => held,
+                None => {
+                    let payload = match pop(&mut consumer) {
+                        Some(p) => p,
+                        None => {
+                            block.mark_eod();
+                            block.commit();
+                            return Ok(());
+                        }
+                    };
+                    match sequence.check(payload_number(&payload)) {
+                        Continuity::Late => continue,
+                        Continuity::Restart if started => {
+                            info!("Ending voltage DADA session");
+                            started = false;
+                            block.mark_eod();
+                            block.commit();
+                            fill.reset();
+                            held = Some((payload, 0));
+                            break;
+                        }
+                        Continuity::Gap(missing) => (payload, missing),
+                        Continuity::Restart | Continuity::Next => (payload, 0),
+                    }
+                }
+            };
+            let payload_n = payload_number(&payload);
+            if !started {
+                let header = voltage_header(cc, runtime, payload_n);
+                // Safety: All these header keys and values are valid
+                unsafe { hc.push_header(&header).unwrap() };
+                info!(
+                    "Started voltage DADA session (OBS_OFFSET {})",
+                    header["OBS_OFFSET"]
+                );
+                started = true;
+            }
+            // Write the zeros for any missing payloads, then the payload itself
+            let data = if missing > 0 {
+                &ZEROS[..]
+            } else {
+                &payload[TIMESTAMP_SIZE..]
+            };
+            let mut offset = split.take().unwrap_or(0);
+            let full = fill
+                .write(&mut block, data, &mut offset)
+                .map_err(|e| e.to_string())?;
+            if offset < data.len() {
+                split = Some(offset);
+                held = Some((payload, missing));
+            } else if missing > 0 {
+                held = Some((payload, missing - 1));
+            }
+            if full {
+                block.commit();
+                break;
+            }
+        }
+    }
+}
+
+/// The exfil thread's end of the voltage recording
+pub struct VoltageRecorder {
+    producer: Option<rtrb::Producer<PayloadBytes>>,
+    writer: Option<JoinHandle<()>>,
+    behind: bool,
+}
+
+impl VoltageRecorder {
+    /// Start the writer thread
+    pub fn new(cc: &CaptureConfig, voltages: &VoltageSection, runtime: Arc<Runtime>) -> Self {
+        let (producer, consumer) = rtrb::RingBuffer::new(voltages.capacity);
+        let sequence = Sequence::new((MAX_GAP / cc.cadence as f64) as u64);
+        let cc = *cc;
+        let key = voltages.dada_key();
+        let files = FileWriter {
+            cc,
+            directory: voltages.directory.clone(),
+            payloads_per_file: (voltages.file_length as f64 / cc.cadence as f64).ceil() as u64,
+            runtime: runtime.clone(),
+            vdif_station: (voltages.format == VoltageFormat::Vdif)
+                .then(|| station_id(&voltages.vdif_station).expect("Validated with the config")),
+            file: None,
+            framer: None,
+            frames: vec![],
+        };
+        let writer = std::thread::spawn(move || {
+            let result = match key {
+                Some(Ok(key)) => dada_writer(key, consumer, &cc, &runtime, sequence),
+                Some(Err(e)) => Err(e),
+                None => file_writer(consumer, files, sequence).map_err(|e| e.to_string()),
+            };
+            if let Err(e) = result {
+                error!("Voltage recording failed: {}", e);
+            }
+        });
+        Self {
+            producer: Some(producer),
+            writer: Some(writer),
+            behind: false,
+        }
+    }
+
+    /// Queue a payload to be written, dropping it (to be zero-filled) if the writer is behind
+    pub fn push(&mut self, payload: &PayloadBytes) {
+        let producer = self.producer.as_mut().expect("Only taken on drop");
+        match producer.push(*payload) {
+            Ok(_) => self.behind = false,
+            Err(_) if !self.behind => {
+                warn!("The voltage recording can't keep up, zero-filling dropped payloads");
+                self.behind = true;
+            }
+            Err(_) => (),
+        }
+    }
+}
+
+impl Drop for VoltageRecorder {
+    /// Let the writer finish off what's queued
+    fn drop(&mut self) {
+        self.producer = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence() {
+        let mut sequence = Sequence::new(100);
+        assert_eq!(sequence.check(10), Continuity::Restart);
+        assert_eq!(sequence.check(11), Continuity::Next);
+        assert_eq!(sequence.check(15), Continuity::Gap(3));
+        assert_eq!(sequence.check(14), Continuity::Late);
+        assert_eq!(sequence.check(16), Continuity::Next);
+        assert_eq!(sequence.check(500), Continuity::Restart);
+        // Re-armed
+        assert_eq!(sequence.check(0), Continuity::Restart);
+        assert_eq!(sequence.check(1), Continuity::Next);
+    }
+
+    #[test]
+    fn test_header_bytes() {
+        let header = HashMap::from([
+            ("NPOL".to_owned(), "2".to_owned()),
+            ("NBIT".to_owned(), "8".to_owned()),
+        ]);
+        let bytes = header_bytes(&header);
+        assert_eq!(bytes.len(), DADA_HEADER_SIZE);
+        assert!(bytes.starts_with(b"NBIT 8\nNPOL 2\n"));
+        assert_eq!(bytes[14], 0);
+    }
+}
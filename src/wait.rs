@@ -0,0 +1,330 @@
+//! Strategies for how the consumer threads wait on the capture ringbuffer when it runs dry.
+//!
+//! The production path wants to spin so we never give time back to the kernel, but that
+//! burns a whole core which is wasteful on test machines and when replaying data.
+
+use std::{
+    sync::{
+        atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    thread::Thread,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+// Upper bound on how long a parked consumer sleeps before checking the ringbuffer itself
+const PARK_TIMEOUT: Duration = Duration::from_millis(1);
+// Longest a partial batch sits in the ringbuffer before we drain it anyway, so the tail of a
+// stream doesn't wait forever on a batch that never fills
+const BATCH_TIMEOUT: Duration = Duration::from_millis(10);
+// How often the waiter reports its idle time
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WaitMode {
+    /// Busy wait on the ringbuffer, lowest latency but pegs the CPU at 100%
+    Spin,
+    /// Spin for a while, then yield to the scheduler
+    Yield,
+    /// Spin for a while, then park until the producer wakes us up
+    Park,
+    /// Park until the producer has queued up a whole batch of payloads, then drain it.
+    /// A partial batch is drained after waiting on it for 10ms.
+    Batch,
+}
+
+#[derive(Debug, Copy, Clone)]
+/// Contains all the state for how the consumers wait on new data
+pub struct WaitStrategy {
+    pub mode: WaitMode,
+    /// Number of spins before yielding or parking
+    pub spins: usize,
+    /// Number of payloads to wait for in batch mode
+    pub batch: usize,
+}
+
+impl WaitStrategy {
+    /// The number of payloads that need to be queued before the consumer should pop
+    fn threshold(&self) -> usize {
+        match self.mode {
+            WaitMode::Batch => self.batch.max(1),
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// Shared between the producer and consumer so the producer can wake up a parked consumer
+pub struct Waker {
+    consumer: OnceLock<Thread>,
+    parked: AtomicBool,
+    threshold: AtomicUsize,
+}
+
+impl Waker {
+    /// Called by the producer after every push, unparking the consumer if it is waiting on us
+    pub fn notify<T>(&self, producer: &rtrb::Producer<T>) {
+        // Pairs with the fence in `park` so one of us always sees the other
+        fence(Ordering::SeqCst);
+        if !self.parked.load(Ordering::Relaxed) {
+            return;
+        }
+        let queued = producer.buffer().capacity() - producer.slots();
+        if queued >= self.threshold.load(Ordering::Relaxed) {
+            self.parked.store(false, Ordering::Relaxed);
+            if let Some(thread) = self.consumer.get() {
+                thread.unpark();
+            }
+        }
+    }
+
+    fn park(&self, threshold: usize, ready: impl Fn() -> bool) {
+        self.consumer.get_or_init(std::thread::current);
+        self.threshold.store(threshold, Ordering::Relaxed);
+        self.parked.store(true, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        // The producer might have pushed before it saw we were parked
+        if !ready() {
+            std::thread::park_timeout(PARK_TIMEOUT);
+        }
+        self.parked.store(false, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Default)]
+/// Counters for how much time the consumer spent waiting on data
+pub struct WaitStats {
+    idle_ns: AtomicU64,
+    waits: AtomicU64,
+    parks: AtomicU64,
+}
+
+impl WaitStats {
+    /// Total time spent waiting on an empty ringbuffer
+    pub fn idle(&self) -> Duration {
+        Duration::from_nanos(self.idle_ns.load(Ordering::Relaxed))
+    }
+    /// Number of times the ringbuffer was empty when we went to pop
+    pub fn waits(&self) -> u64 {
+        self.waits.load(Ordering::Relaxed)
+    }
+    /// Number of times we parked the thread
+    pub fn parks(&self) -> u64 {
+        self.parks.load(Ordering::Relaxed)
+    }
+}
+
+/// The consumer half of the wait strategy, pops from the ringbuffer and blocks until there is data
+pub struct Waiter {
+    strategy: WaitStrategy,
+    waker: Arc<Waker>,
+    stats: Arc<WaitStats>,
+    // Payloads left in the current batch that we can pop without waiting
+    batch_remaining: usize,
+    last_report: Instant,
+    last_idle: Duration,
+}
+
+impl Waiter {
+    pub fn new(strategy: WaitStrategy, waker: Arc<Waker>) -> Self {
+        Self {
+            strategy,
+            waker,
+            stats: Arc::new(WaitStats::default()),
+            batch_remaining: 0,
+            last_report: Instant::now(),
+            last_idle: Duration::ZERO,
+        }
+    }
+
+    /// Shared handle to this waiter's idle statistics
+    pub fn stats(&self) -> Arc<WaitStats> {
+        self.stats.clone()
+    }
+
+    /// Pop the next item from the ringbuffer, waiting with our strategy if there isn't one
+    pub fn pop<T>(&mut self, consumer: &mut rtrb::Consumer<T>) -> T {
+        self.pop_unless(consumer, || false).unwrap()
+    }
+
+    /// Like [`Waiter::pop`], but gives up waiting once `stop` returns true
+    pub fn pop_unless<T>(
+        &mut self,
+        consumer: &mut rtrb::Consumer<T>,
+        stop: impl Fn() -> bool,
+    ) -> Option<T> {
+        let threshold = self.strategy.threshold();
+        if let Some(v) = self.try_pop(consumer, threshold) {
+            return Some(v);
+        }
+        let start = Instant::now();
+        let mut spins = 0usize;
+        let v = loop {
+            if stop() {
+                break None;
+            }
+            self.backoff(consumer, &mut spins);
+            // Take whatever there is once we've waited long enough on a batch
+            let threshold = if start.elapsed() >= BATCH_TIMEOUT {
+                1
+            } else {
+                threshold
+            };
+            if let Some(v) = self.try_pop(consumer, threshold) {
+                break Some(v);
+            }
+        };
+        self.stats
+            .idle_ns
+            .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        self.stats.waits.fetch_add(1, Ordering::Relaxed);
+        self.report();
+        v
+    }
+
+    fn try_pop<T>(&mut self, consumer: &mut rtrb::Consumer<T>, threshold: usize) -> Option<T> {
+        if self.batch_remaining == 0 {
+            let slots = consumer.slots();
+            if slots < threshold {
+                return None;
+            }
+            self.batch_remaining = slots;
+        }
+        let v = consumer.pop().ok()?;
+        self.batch_remaining -= 1;
+        Some(v)
+    }
+
+    fn backoff<T>(&mut self, consumer: &rtrb::Consumer<T>, spins: &mut usize) {
+        if self.strategy.mode == WaitMode::Spin || *spins < self.strategy.spins {
+            *spins += 1;
+            std::hint::spin_loop();
+            return;
+        }
+        match self.strategy.mode {
+            WaitMode::Yield => std::thread::yield_now(),
+            WaitMode::Park | WaitMode::Batch => {
+                let threshold = self.strategy.threshold();
+                self.waker.park(threshold, || consumer.slots() >= threshold);
+                self.stats.parks.fetch_add(1, Ordering::Relaxed);
+            }
+            WaitMode::Spin => unreachable!(),
+        }
+    }
+
+    fn report(&mut self) {
+        let elapsed = self.last_report.elapsed();
+        if elapsed < REPORT_INTERVAL {
+            return;
+        }
+        let idle = self.stats.idle();
+        debug!(
+            "Consumer idle {:.1}% of the last {:.0}s ({} parks total)",
+            (idle - self.last_idle).as_secs_f32() / elapsed.as_secs_f32() * 100.0,
+            elapsed.as_secs_f32(),
+            self.stats.parks()
+        );
+        self.last_report = Instant::now();
+        self.last_idle = idle;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rtrb::RingBuffer;
+
+    #[test]
+    fn test_batch_drains() {
+        let (mut producer, mut consumer) = RingBuffer::new(8);
+        let waker = Arc::new(Waker::default());
+        let mut waiter = Waiter::new(
+            WaitStrategy {
+                mode: WaitMode::Batch,
+                spins: 0,
+                batch: 4,
+            },
+            waker.clone(),
+        );
+        let handle =
+            std::thread::spawn(move || (0..8).map(|_| waiter.pop(&mut consumer)).sum::<u32>());
+        for i in 0..8u32 {
+            producer.push(i).unwrap();
+            waker.notify(&producer);
+        }
+        assert_eq!(28u32, handle.join().unwrap());
+    }
+
+    #[test]
+    fn test_batch_flushes() {
+        let (mut producer, mut consumer) = RingBuffer::new(8);
+        let waker = Arc::new(Waker::default());
+        let mut waiter = Waiter::new(
+            WaitStrategy {
+                mode: WaitMode::Batch,
+                spins: 0,
+                batch: 4,
+            },
+            waker.clone(),
+        );
+        // Only half a batch ever shows up, which still gets drained
+        for i in 0..2u32 {
+            producer.push(i).unwrap();
+            waker.notify(&producer);
+        }
+        let start = Instant::now();
+        assert_eq!(0, waiter.pop(&mut consumer));
+        assert_eq!(1, waiter.pop(&mut consumer));
+        assert!(start.elapsed() >= BATCH_TIMEOUT);
+    }
+
+    #[test]
+    fn test_park_wakes() {
+        let (mut producer, mut consumer) = RingBuffer::new(2);
+        let waker = Arc::new(Waker::default());
+        let mut waiter = Waiter::new(
+            WaitStrategy {
+                mode: WaitMode::Park,
+                spins: 10,
+                batch: 1,
+            },
+            waker.clone(),
+        );
+        let stats = waiter.stats();
+        let handle = std::thread::spawn(move || waiter.pop(&mut consumer));
+        std::thread::sleep(Duration::from_millis(20));
+        producer.push(42u8).unwrap();
+        waker.notify(&producer);
+        assert_eq!(42, handle.join().unwrap());
+        assert_eq!(1, stats.waits());
+        assert!(stats.parks() > 0);
+    }
+
+    #[test]
+    fn test_pop_stops() {
+        let (_producer, mut consumer) = RingBuffer::<u8>::new(2);
+        let mut waiter = Waiter::new(
+            WaitStrategy {
+                mode: WaitMode::Park,
+                spins: 10,
+                batch: 1,
+            },
+            Arc::new(Waker::default()),
+        );
+        // Nothing's coming, so we give up when asked to instead of waiting forever
+        let stop = AtomicBool::new(false);
+        let handle = std::thread::scope(|scope| {
+            let handle =
+                scope.spawn(|| waiter.pop_unless(&mut consumer, || stop.load(Ordering::Relaxed)));
+            std::thread::sleep(Duration::from_millis(20));
+            stop.store(true, Ordering::Relaxed);
+            handle.join().unwrap()
+        });
+        assert_eq!(handle, None);
+    }
+}
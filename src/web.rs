@@ -0,0 +1,235 @@
+//! A small HTTP server so the band can be checked from a browser during observations.
+//!
+//! Routes:
+//! - `GET /spectrum` - The latest integrated spectrum as JSON
+//! - `GET /waterfall` - The last N integrations as concatenated monitoring frames
+//! - `GET /ws` - WebSocket stream of new integrations, one monitoring frame per binary message
+//! - `GET /health` - The health status of the packet flow as JSON
+
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use serde::Serialize;
+use serde_json::json;
+use tracing::{debug, info, warn};
+use tungstenite::{handshake::derive_accept_key, protocol::Role, Message, WebSocket};
+
+use crate::{
+    health::Health,
+    monitoring::{encode_frame, FrameHeader},
+};
+
+// Number of frames a websocket client can fall behind before we skip frames for it
+const WS_QUEUE_DEPTH: usize = 16;
+// Clients that go quiet or stop reading get dropped instead of holding on to their thread
+const CLIENT_READ_TIMEOUT: Duration = Duration::from_secs(5);
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct SpectrumResponse<'a> {
+    #[serde(flatten)]
+    header: &'a FrameHeader,
+    data: &'a [f32],
+}
+
+/// The integrations shared between the monitoring thread and the HTTP clients
+pub struct WebState {
+    latest: Mutex<Option<(FrameHeader, Vec<f32>)>>,
+    waterfall: Mutex<VecDeque<Arc<Vec<u8>>>>,
+    waterfall_len: usize,
+    subscribers: Mutex<Vec<Sender<Arc<Vec<u8>>>>>,
+    health: Arc<Health>,
+}
+
+impl WebState {
+    pub fn new(waterfall_len: usize, health: Arc<Health>) -> Self {
+        Self {
+            latest: Mutex::new(None),
+            waterfall: Mutex::new(VecDeque::with_capacity(waterfall_len)),
+            waterfall_len,
+            subscribers: Mutex::new(vec![]),
+            health,
+        }
+    }
+
+    /// Publish a new integration to all the HTTP and WebSocket clients
+    pub fn publish(&self, header: &FrameHeader, data: &[f32]) {
+        let frame = Arc::new(encode_frame(header, data));
+        *self.latest.lock().unwrap() = Some((*header, data.to_vec()));
+        {
+            let mut waterfall = self.waterfall.lock().unwrap();
+            if waterfall.len() == self.waterfall_len {
+                waterfall.pop_front();
+            }
+            waterfall.push_back(frame.clone());
+        }
+        // Slow clients just miss frames, clients that went away get dropped
+        self.subscribers.lock().unwrap().retain(|s| {
+            !matches!(
+                s.try_send(frame.clone()),
+                Err(TrySendError::Disconnected(_))
+            )
+        });
+    }
+
+    fn subscribe(&self) -> Receiver<Arc<Vec<u8>>> {
+        let (s, r) = bounded(WS_QUEUE_DEPTH);
+        self.subscribers.lock().unwrap().push(s);
+        r
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) {
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    let _ = stream
+        .write_all(header.as_bytes())
+        .and_then(|_| stream.write_all(body));
+}
+
+fn serve_websocket(mut stream: TcpStream, key: &str, state: &WebState) {
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes())
+    );
+    if stream.write_all(response.as_bytes()).is_err() {
+        return;
+    }
+    info!("New websocket client {:?}", stream.peer_addr());
+    let rx = state.subscribe();
+    let mut ws = WebSocket::from_raw_socket(stream, Role::Server, None);
+    for frame in rx {
+        if ws.write_message(Message::Binary(frame.to_vec())).is_err() {
+            break;
+        }
+    }
+    debug!("Websocket client disconnected");
+}
+
+fn handle_client(stream: TcpStream, state: &WebState) {
+    let _ = stream.set_read_timeout(Some(CLIENT_READ_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT));
+    let mut reader = BufReader::new(match stream.try_clone() {
+        Ok(s) => s,
+        Err(_) => return,
+    });
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    // Grab the websocket key if there is one, we don't care about the other headers
+    let mut ws_key = None;
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) if line.trim().is_empty() => break,
+            Ok(_) => {
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                        ws_key = Some(value.trim().to_owned());
+                    }
+                }
+            }
+        }
+    }
+    let mut stream = stream;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next(), parts.next());
+    if method != Some("GET") {
+        respond(&mut stream, "405 Method Not Allowed", "text/plain", b"");
+        return;
+    }
+    match (path, ws_key) {
+        (Some("/ws"), Some(key)) => serve_websocket(stream, &key, state),
+        (Some("/spectrum"), _) => match &*state.latest.lock().unwrap() {
+            Some((header, data)) => {
+                let body = serde_json::to_vec(&SpectrumResponse { header, data }).unwrap();
+                respond(&mut stream, "200 OK", "application/json", &body);
+            }
+            None => respond(&mut stream, "503 Service Unavailable", "text/plain", b""),
+        },
+        (Some("/health"), _) => {
+            let health = &state.health;
+            let body = json!({
+                "status": health.status(),
+                "since_packet_s": health.since_packet().as_secs_f64(),
+                "since_commit_s": health.since_commit().as_secs_f64(),
+                "packets": health.packet_stats(),
+                "rejected": health.rejections(),
+                "timing": health.timing(),
+                "boards": health.board_stats(),
+            });
+            respond(
+                &mut stream,
+                "200 OK",
+                "application/json",
+                body.to_string().as_bytes(),
+            );
+        }
+        (Some("/waterfall"), _) => {
+            let body: Vec<u8> = state
+                .waterfall
+                .lock()
+                .unwrap()
+                .iter()
+                .flat_map(|f| f.iter().copied())
+                .collect();
+            respond(&mut stream, "200 OK", "application/octet-stream", &body);
+        }
+        _ => respond(&mut stream, "404 Not Found", "text/plain", b""),
+    }
+}
+
+pub fn http_server(addr: SocketAddr, state: Arc<WebState>) {
+    let listener = TcpListener::bind(addr).unwrap();
+    info!("HTTP monitoring listening on {}", addr);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let state = state.clone();
+                std::thread::spawn(move || handle_client(stream, &state));
+            }
+            Err(e) => warn!("Failed to accept HTTP client: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::{HEADER_SIZE, PROTOCOL_VERSION};
+
+    #[test]
+    fn test_waterfall_rolls() {
+        let state = WebState::new(2, Arc::new(Health::default()));
+        let mut header = FrameHeader {
+            version: PROTOCOL_VERSION,
+            flags: 0,
+            payload_n: 0,
+            mjd: 0.0,
+            integrations: 1,
+            nchan: 1,
+            fch1: 0.0,
+            foff: 0.0,
+        };
+        for i in 0..3 {
+            header.payload_n = i;
+            state.publish(&header, &[i as f32]);
+        }
+        let waterfall = state.waterfall.lock().unwrap();
+        assert_eq!(waterfall.len(), 2);
+        assert_eq!(waterfall[0].len(), HEADER_SIZE + 4);
+        assert_eq!(state.latest.lock().unwrap().as_ref().unwrap().1, vec![2.0]);
+    }
+}